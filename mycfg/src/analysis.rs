@@ -0,0 +1,98 @@
+pub mod annotate;
+pub mod dataflow;
+mod dominators;
+mod loops;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{BasicBlock, Function, Instruction, LabelId};
+use crate::parser::control_flow_graph;
+
+pub use crate::analysis::dominators::Dominators;
+pub use crate::analysis::loops::{Loop, LoopForest};
+
+pub fn predecessors(
+    func: &Function,
+    cfg: &HashMap<LabelId, Vec<LabelId>>,
+) -> HashMap<LabelId, Vec<LabelId>> {
+    let mut preds: HashMap<LabelId, Vec<LabelId>> = func
+        .blocks
+        .iter()
+        .map(|block| (block.name, vec![]))
+        .collect();
+
+    // Walk blocks in program order so predecessor lists are deterministic
+    for block in func.blocks.iter() {
+        for succ in cfg[&block.name].iter() {
+            preds.get_mut(succ).unwrap().push(block.name);
+        }
+    }
+
+    preds
+}
+
+pub fn reverse_postorder(func: &Function, cfg: &HashMap<LabelId, Vec<LabelId>>) -> Vec<LabelId> {
+    let mut order: Vec<LabelId> = vec![];
+    let mut visited: HashSet<LabelId> = HashSet::new();
+
+    if let Some(entry) = func.blocks.first() {
+        // Iterative DFS; each stack entry is a block and the index of the next successor to visit
+        let mut stack: Vec<(LabelId, usize)> = vec![(entry.name, 0)];
+        visited.insert(entry.name);
+        while let Some((block, i)) = stack.pop() {
+            if let Some(&succ) = cfg[&block].get(i) {
+                stack.push((block, i + 1));
+                if visited.insert(succ) {
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+    }
+
+    order.reverse();
+    order
+}
+
+impl Function {
+    /// Splits every edge from a block with several successors to a block with several
+    /// predecessors by routing it through a new block holding a single `jmp`. The new block is
+    /// placed right after the branch, which never falls through.
+    pub fn split_critical_edges(&self) -> Function {
+        let cfg = control_flow_graph(self);
+        let preds = predecessors(self, &cfg);
+        let mut func = self.clone();
+
+        for block in self.blocks.iter() {
+            let succs = &cfg[&block.name];
+            if succs.len() < 2 {
+                continue;
+            }
+            for succ in succs.iter().filter(|succ| preds[*succ].len() > 1) {
+                let name = func.fresh_block_name(&format!("{}.{}", block.name, succ));
+                let index = func
+                    .blocks
+                    .iter()
+                    .position(|b| b.name == block.name)
+                    .unwrap();
+                let branch = func.blocks[index].instructions.last_mut().unwrap();
+                for label in branch.labels.as_mut().unwrap().iter_mut() {
+                    if label == succ {
+                        *label = name;
+                    }
+                }
+                func.blocks.insert(
+                    index + 1,
+                    BasicBlock {
+                        name,
+                        instructions: vec![Instruction::jmp(*succ)],
+                        pos: None,
+                        synthetic: true,
+                    },
+                );
+            }
+        }
+        func
+    }
+}
@@ -0,0 +1,162 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::analysis::dataflow::{
+    solve, Analysis, DefSite, Definition, Direction, LiveVariables, ReachingDefinitions,
+};
+use crate::core::{Function, Instruction, LabelId, VarId};
+
+/// Comments to print alongside a function: facts at the start and end of each block, and next
+/// to each instruction. Several sets of facts can be combined, and are shown in the order they
+/// were added.
+#[derive(Default)]
+pub struct Annotations {
+    block_in: HashMap<LabelId, Vec<String>>,
+    block_out: HashMap<LabelId, Vec<String>>,
+    instructions: HashMap<(LabelId, usize), Vec<String>>,
+}
+
+impl Annotations {
+    pub fn new() -> Annotations {
+        Annotations::default()
+    }
+
+    /// Adds the facts `analysis` finds at the start and end of every block of `func`.
+    pub fn with_blocks<A: Analysis>(
+        mut self,
+        func: &Function,
+        analysis: &A,
+        format: impl Fn(&A::Fact) -> String,
+    ) -> Annotations {
+        let result = solve(func, analysis);
+        for block in func.blocks.iter() {
+            let name = block.name;
+            let fact_in = format!("in: {}", format(&result.block_in[&name]));
+            let fact_out = format!("out: {}", format(&result.block_out[&name]));
+            self.block_in.entry(name).or_default().push(fact_in);
+            self.block_out.entry(name).or_default().push(fact_out);
+        }
+        self
+    }
+
+    /// Adds the fact `analysis` finds at every instruction of `func`: the one the instruction's
+    /// transfer function starts from, so the fact before it for forward analyses and after it for
+    /// backward ones. `format` may leave out instructions by returning `None`.
+    pub fn with_instructions<A: Analysis>(
+        mut self,
+        func: &Function,
+        analysis: &A,
+        format: impl Fn(&Instruction, &A::Fact) -> Option<String>,
+    ) -> Annotations {
+        let result = solve(func, analysis);
+        let forward = analysis.direction() == Direction::Forward;
+        for block in func.blocks.iter() {
+            let facts = result.instruction_facts(analysis, block);
+            for (index, (before, after)) in facts.iter().enumerate() {
+                let fact = if forward { before } else { after };
+                if let Some(comment) = format(&block.instructions[index], fact) {
+                    self.add(block.name, index, comment);
+                }
+            }
+        }
+        self
+    }
+
+    /// Adds live-in and live-out variables to every block.
+    pub fn with_live_variables(self, func: &Function) -> Annotations {
+        self.with_blocks(func, &LiveVariables, format_variables)
+    }
+
+    /// Adds the definitions that reach each instruction for the variables it reads.
+    pub fn with_reaching_definitions(self, func: &Function) -> Annotations {
+        self.with_instructions(func, &ReachingDefinitions, |instr, fact| {
+            let uses = instr.uses();
+            let mut reaching: Vec<&Definition> =
+                fact.iter().filter(|def| uses.contains(&def.var)).collect();
+            reaching.sort_by(|a, b| cmp_definitions(a, b));
+            let reaching: Vec<String> = reaching.into_iter().map(format_definition).collect();
+            if reaching.is_empty() {
+                None
+            } else {
+                Some(format!("reaching: {}", reaching.join(" ")))
+            }
+        })
+    }
+
+    /// Adds the value number of the variable each instruction defines, for a function in SSA
+    /// form. Variables holding the same value share a number.
+    pub fn with_value_numbers(mut self, func: &Function) -> Annotations {
+        let numbers = func.value_numbers();
+        for block in func.blocks.iter() {
+            for (index, instr) in block.instructions.iter().enumerate() {
+                if let Some(dst) = &instr.dst {
+                    self.add(block.name, index, format!("value: v{}", numbers[dst]));
+                }
+            }
+        }
+        self
+    }
+
+    fn add(&mut self, block: LabelId, index: usize, comment: String) {
+        self.instructions
+            .entry((block, index))
+            .or_default()
+            .push(comment);
+    }
+}
+
+/// `{a, b}`
+pub fn format_variables(vars: &BTreeSet<VarId>) -> String {
+    let mut vars: Vec<&VarId> = vars.iter().collect();
+    vars.sort_by(|a, b| a.cmp_by_name(b));
+    let vars: Vec<String> = vars.iter().map(|var| var.to_string()).collect();
+    format!("{{{}}}", vars.join(", "))
+}
+
+/// Orders definitions by variable name, then arguments first and instructions by block name and
+/// index.
+fn cmp_definitions(a: &Definition, b: &Definition) -> Ordering {
+    let site = |def: &Definition| match &def.site {
+        DefSite::Argument => None,
+        DefSite::Instruction { block, index } => Some((block.name(), *index)),
+    };
+    a.var
+        .cmp_by_name(&b.var)
+        .then_with(|| site(a).cmp(&site(b)))
+}
+
+/// `x@.block:index` for the instruction at `index` of `block`, or `x@arg` for an argument.
+pub fn format_definition(def: &Definition) -> String {
+    match &def.site {
+        DefSite::Argument => format!("{}@arg", def.var),
+        DefSite::Instruction { block, index } => format!("{}@.{}:{}", def.var, block, index),
+    }
+}
+
+impl Function {
+    /// Prints the function like `Display` does, with `annotations` as `#` comments: block facts
+    /// on their own lines after the label and after the last instruction, and instruction facts
+    /// at the end of the instruction's line.
+    pub fn annotated(&self, annotations: &Annotations) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} {{", self.signature()).unwrap();
+        for block in self.blocks.iter() {
+            writeln!(out, ".{}:", block.name).unwrap();
+            if let Some(facts) = annotations.block_in.get(&block.name) {
+                writeln!(out, "    # {}", facts.join("; ")).unwrap();
+            }
+            for (index, instr) in block.instructions.iter().enumerate() {
+                match annotations.instructions.get(&(block.name, index)) {
+                    Some(facts) => writeln!(out, "    {}  # {}", instr, facts.join("; ")).unwrap(),
+                    None => writeln!(out, "    {}", instr).unwrap(),
+                }
+            }
+            if let Some(facts) = annotations.block_out.get(&block.name) {
+                writeln!(out, "    # {}", facts.join("; ")).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use crate::analysis::predecessors;
+use crate::core::{BasicBlock, Function, Instruction, LabelId, MiscOp, OpCode, VarId};
+use crate::parser::control_flow_graph;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    /// Fact flowing into the entry block (forward) or out of exit blocks (backward).
+    fn boundary(&self, func: &Function) -> Self::Fact;
+
+    /// Starting fact for every other block: the identity of `meet`.
+    fn initial(&self, func: &Function) -> Self::Fact;
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+
+    /// Transfer function of the instruction at `index` in `block`. Maps the fact before the
+    /// instruction to the fact after it for forward analyses, and the other way around for
+    /// backward ones. Analyses defined on whole blocks override `transfer_block` instead.
+    fn transfer(&self, _block: &BasicBlock, _index: usize, fact: &Self::Fact) -> Self::Fact {
+        fact.clone()
+    }
+
+    fn transfer_block(&self, block: &BasicBlock, fact: &Self::Fact) -> Self::Fact {
+        let mut fact = fact.clone();
+        let n = block.instructions.len();
+        match self.direction() {
+            Direction::Forward => {
+                for i in 0..n {
+                    fact = self.transfer(block, i, &fact);
+                }
+            }
+            Direction::Backward => {
+                for i in (0..n).rev() {
+                    fact = self.transfer(block, i, &fact);
+                }
+            }
+        }
+        fact
+    }
+}
+
+/// Facts at the start (`block_in`) and end (`block_out`) of every block, regardless of the
+/// direction of the analysis.
+pub struct DataflowResult<F> {
+    pub block_in: HashMap<LabelId, F>,
+    pub block_out: HashMap<LabelId, F>,
+}
+
+impl<F: Clone> DataflowResult<F> {
+    /// Facts before and after each instruction of `block`, in program order.
+    pub fn instruction_facts<A: Analysis<Fact = F>>(
+        &self,
+        analysis: &A,
+        block: &BasicBlock,
+    ) -> Vec<(F, F)> {
+        let n = block.instructions.len();
+        match analysis.direction() {
+            Direction::Forward => {
+                let mut fact = self.block_in[&block.name].clone();
+                (0..n)
+                    .map(|i| {
+                        let after = analysis.transfer(block, i, &fact);
+                        (std::mem::replace(&mut fact, after.clone()), after)
+                    })
+                    .collect()
+            }
+            Direction::Backward => {
+                let mut fact = self.block_out[&block.name].clone();
+                let mut facts: Vec<(F, F)> = (0..n)
+                    .rev()
+                    .map(|i| {
+                        let before = analysis.transfer(block, i, &fact);
+                        (before.clone(), std::mem::replace(&mut fact, before))
+                    })
+                    .collect();
+                facts.reverse();
+                facts
+            }
+        }
+    }
+}
+
+/// Solves `analysis` over `func` with a worklist until every block's facts are stable.
+pub fn solve<A: Analysis>(func: &Function, analysis: &A) -> DataflowResult<A::Fact> {
+    let cfg = control_flow_graph(func);
+    let preds = predecessors(func, &cfg);
+    let forward = analysis.direction() == Direction::Forward;
+    // Edges are followed against the direction of the analysis to find a block's inputs
+    let (inputs, outputs) = if forward {
+        (&preds, &cfg)
+    } else {
+        (&cfg, &preds)
+    };
+    let entry = func.blocks.first().map(|block| block.name);
+    let blocks: HashMap<LabelId, &BasicBlock> = func
+        .blocks
+        .iter()
+        .map(|block| (block.name, block))
+        .collect();
+
+    let mut fact_in: HashMap<LabelId, A::Fact> = HashMap::new();
+    let mut fact_out: HashMap<LabelId, A::Fact> = HashMap::new();
+    for block in func.blocks.iter() {
+        fact_in.insert(block.name, analysis.initial(func));
+        fact_out.insert(block.name, analysis.initial(func));
+    }
+
+    let order: Vec<&BasicBlock> = if forward {
+        func.blocks.iter().collect()
+    } else {
+        func.blocks.iter().rev().collect()
+    };
+    let mut queued: HashSet<LabelId> = order.iter().map(|block| block.name).collect();
+    let mut worklist: VecDeque<&BasicBlock> = order.into_iter().collect();
+    while let Some(block) = worklist.pop_front() {
+        queued.remove(&block.name);
+        let is_boundary = if forward {
+            Some(block.name) == entry
+        } else {
+            cfg[&block.name].is_empty()
+        };
+        let mut merged = if is_boundary {
+            analysis.boundary(func)
+        } else {
+            analysis.initial(func)
+        };
+        for input in inputs[&block.name].iter() {
+            merged = analysis.meet(&merged, &fact_out[input]);
+        }
+
+        let result = analysis.transfer_block(block, &merged);
+        fact_in.insert(block.name, merged);
+        if fact_out[&block.name] != result {
+            fact_out.insert(block.name, result);
+            for output in outputs[&block.name].iter() {
+                if queued.insert(*output) {
+                    worklist.push_back(blocks[output]);
+                }
+            }
+        }
+    }
+
+    // Internally "in" is the side facts flow into; report them by program position instead
+    if forward {
+        DataflowResult {
+            block_in: fact_in,
+            block_out: fact_out,
+        }
+    } else {
+        DataflowResult {
+            block_in: fact_out,
+            block_out: fact_in,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DefSite {
+    Argument,
+    Instruction { block: LabelId, index: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Definition {
+    pub var: VarId,
+    pub site: DefSite,
+}
+
+/// Forward may-analysis of which definitions can reach each point.
+pub struct ReachingDefinitions;
+
+impl Analysis for ReachingDefinitions {
+    type Fact = BTreeSet<Definition>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, func: &Function) -> Self::Fact {
+        func.args
+            .iter()
+            .map(|(name, _)| Definition {
+                var: *name,
+                site: DefSite::Argument,
+            })
+            .collect()
+    }
+
+    fn initial(&self, _func: &Function) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        a.union(b).cloned().collect()
+    }
+
+    fn transfer(&self, block: &BasicBlock, index: usize, fact: &Self::Fact) -> Self::Fact {
+        match &block.instructions[index].dst {
+            Some(dst) => {
+                let mut fact: Self::Fact = fact.iter().filter(|d| d.var != *dst).cloned().collect();
+                fact.insert(Definition {
+                    var: *dst,
+                    site: DefSite::Instruction {
+                        block: block.name,
+                        index,
+                    },
+                });
+                fact
+            }
+            None => fact.clone(),
+        }
+    }
+}
+
+/// Backward may-analysis of which variables may still be read before being redefined.
+pub struct LiveVariables;
+
+impl Analysis for LiveVariables {
+    type Fact = BTreeSet<VarId>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self, _func: &Function) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self, _func: &Function) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        a.union(b).cloned().collect()
+    }
+
+    fn transfer(&self, block: &BasicBlock, index: usize, fact: &Self::Fact) -> Self::Fact {
+        let instr = &block.instructions[index];
+        let mut fact = fact.clone();
+        if let Some(dst) = &instr.dst {
+            fact.remove(dst);
+        }
+        fact.extend(instr.uses().iter().copied());
+        fact
+    }
+}
+
+/// Forward must-analysis of which variables are assigned on every path to each point. A phi
+/// leaves its destination unassigned when it has no defined value for the edge it's reached
+/// by, so phis are taken to unassign theirs.
+pub struct DefinedVariables;
+
+impl Analysis for DefinedVariables {
+    type Fact = BTreeSet<VarId>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, func: &Function) -> Self::Fact {
+        func.args.iter().map(|(arg, _)| *arg).collect()
+    }
+
+    fn initial(&self, func: &Function) -> Self::Fact {
+        func.variables().into_iter().collect()
+    }
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer(&self, block: &BasicBlock, index: usize, fact: &Self::Fact) -> Self::Fact {
+        let instr = &block.instructions[index];
+        let mut fact = fact.clone();
+        if let Some(dst) = instr.dst {
+            if instr.op == OpCode::Phi {
+                fact.remove(&dst);
+            } else {
+                fact.insert(dst);
+            }
+        }
+        fact
+    }
+}
+
+/// Forward must-analysis of which copies `dst = id src` hold at each point: the copy ran on
+/// every path there and neither side has been assigned since.
+pub struct AvailableCopies;
+
+impl AvailableCopies {
+    fn copy(instr: &Instruction) -> Option<(VarId, VarId)> {
+        match (
+            instr.op == OpCode::Misc(MiscOp::Id),
+            instr.dst,
+            instr.uses(),
+        ) {
+            (true, Some(dst), &[src]) if dst != src => Some((dst, src)),
+            _ => None,
+        }
+    }
+}
+
+impl Analysis for AvailableCopies {
+    type Fact = BTreeSet<(VarId, VarId)>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _func: &Function) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self, func: &Function) -> Self::Fact {
+        func.blocks
+            .iter()
+            .flat_map(|block| block.instructions.iter())
+            .filter_map(AvailableCopies::copy)
+            .collect()
+    }
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer(&self, block: &BasicBlock, index: usize, fact: &Self::Fact) -> Self::Fact {
+        let instr = &block.instructions[index];
+        let mut fact = fact.clone();
+        if let Some(dst) = instr.dst {
+            fact.retain(|&(copy, src)| copy != dst && src != dst);
+        }
+        fact.extend(AvailableCopies::copy(instr));
+        fact
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::{predecessors, reverse_postorder};
+use crate::core::{Function, LabelId};
+use crate::parser::control_flow_graph;
+
+pub struct Dominators {
+    idom: HashMap<LabelId, LabelId>,
+    children: HashMap<LabelId, Vec<LabelId>>,
+    rpo: Vec<LabelId>,
+}
+
+impl Dominators {
+    /// Computes immediate dominators with the Cooper-Harvey-Kennedy iterative algorithm.
+    /// Blocks unreachable from the entry block have no dominators.
+    pub fn new(func: &Function) -> Dominators {
+        let cfg = control_flow_graph(func);
+        let preds = predecessors(func, &cfg);
+        let rpo = reverse_postorder(func, &cfg);
+        let rpo_index: HashMap<&LabelId, usize> =
+            rpo.iter().enumerate().map(|(i, name)| (name, i)).collect();
+
+        let mut idom: HashMap<LabelId, LabelId> = HashMap::new();
+        if let Some(entry) = rpo.first() {
+            idom.insert(*entry, *entry);
+        }
+
+        let intersect = |idom: &HashMap<LabelId, LabelId>, a: &LabelId, b: &LabelId| -> LabelId {
+            let (mut a, mut b) = (a, b);
+            while a != b {
+                while rpo_index[a] > rpo_index[b] {
+                    a = &idom[a];
+                }
+                while rpo_index[b] > rpo_index[a] {
+                    b = &idom[b];
+                }
+            }
+            *a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in rpo.iter().skip(1) {
+                let mut new_idom: Option<LabelId> = None;
+                for pred in preds[block].iter() {
+                    if !idom.contains_key(pred) {
+                        continue;
+                    }
+                    new_idom = match new_idom {
+                        None => Some(*pred),
+                        Some(cur) => Some(intersect(&idom, pred, &cur)),
+                    };
+                }
+                let new_idom = new_idom.unwrap();
+                if idom.get(block) != Some(&new_idom) {
+                    idom.insert(*block, new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        // Children are listed in program order to keep tree walks deterministic
+        let mut children: HashMap<LabelId, Vec<LabelId>> = HashMap::new();
+        for block in func.blocks.iter() {
+            if let Some(parent) = idom.get(&block.name) {
+                if *parent != block.name {
+                    children.entry(*parent).or_default().push(block.name);
+                }
+            }
+        }
+
+        Dominators {
+            idom,
+            children,
+            rpo,
+        }
+    }
+
+    pub fn is_reachable(&self, block: LabelId) -> bool {
+        self.idom.contains_key(&block)
+    }
+
+    /// Immediate dominator of `block`, or `None` for the entry and unreachable blocks.
+    pub fn idom(&self, block: LabelId) -> Option<LabelId> {
+        match self.idom.get(&block) {
+            Some(&parent) if parent != block => Some(parent),
+            _ => None,
+        }
+    }
+
+    pub fn children(&self, block: LabelId) -> &[LabelId] {
+        match self.children.get(&block) {
+            Some(children) => children,
+            None => &[],
+        }
+    }
+
+    /// Reachable blocks in reverse postorder, entry first.
+    pub fn reverse_postorder(&self) -> &[LabelId] {
+        &self.rpo
+    }
+
+    pub fn dominates(&self, a: LabelId, b: LabelId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut cur = b;
+        loop {
+            if cur == a {
+                return true;
+            }
+            match self.idom(cur) {
+                Some(parent) => cur = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn strictly_dominates(&self, a: LabelId, b: LabelId) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// Dominance frontier of every reachable block.
+    pub fn frontier(&self, func: &Function) -> HashMap<LabelId, HashSet<LabelId>> {
+        let cfg = control_flow_graph(func);
+        let preds = predecessors(func, &cfg);
+        let mut frontier: HashMap<LabelId, HashSet<LabelId>> = self
+            .rpo
+            .iter()
+            .map(|block| (*block, HashSet::new()))
+            .collect();
+
+        for block in self.rpo.iter() {
+            let reachable_preds: Vec<&LabelId> = preds[block]
+                .iter()
+                .filter(|pred| self.is_reachable(**pred))
+                .collect();
+            // The entry block has an implicit extra predecessor: the function call itself
+            let is_entry = self.idom.get(block) == Some(block);
+            if reachable_preds.len() < 2 && !is_entry {
+                continue;
+            }
+            let idom = &self.idom[block];
+            for pred in reachable_preds {
+                let mut runner = pred;
+                while runner != idom {
+                    frontier.get_mut(runner).unwrap().insert(*block);
+                    runner = &self.idom[runner];
+                }
+            }
+        }
+
+        frontier
+    }
+}
@@ -0,0 +1,246 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::analysis::{predecessors, Dominators};
+use crate::core::{BasicBlock, ControlOp, Function, Instruction, LabelId, OpCode, SpeculationOp};
+use crate::parser::control_flow_graph;
+
+pub struct Loop {
+    pub header: LabelId,
+    /// Sources of the back edges into `header`.
+    pub latches: Vec<LabelId>,
+    /// Every block of the loop including the header, in program order.
+    pub body: Vec<LabelId>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Nesting depth, 1 for outermost loops.
+    pub depth: usize,
+}
+
+impl Loop {
+    pub fn contains(&self, block: LabelId) -> bool {
+        self.body.contains(&block)
+    }
+
+    /// Edges leaving the loop as (inside, outside) block pairs.
+    pub fn exits(&self, cfg: &HashMap<LabelId, Vec<LabelId>>) -> Vec<(LabelId, LabelId)> {
+        self.body
+            .iter()
+            .flat_map(|&block| {
+                cfg[&block]
+                    .iter()
+                    .filter(|&&succ| !self.contains(succ))
+                    .map(move |&succ| (block, succ))
+            })
+            .collect()
+    }
+}
+
+pub struct LoopForest {
+    /// Loops ordered so that every loop comes after the loop containing it.
+    pub loops: Vec<Loop>,
+}
+
+impl LoopForest {
+    pub fn new(func: &Function) -> LoopForest {
+        let cfg = control_flow_graph(func);
+        let preds = predecessors(func, &cfg);
+        let doms = Dominators::new(func);
+        let position: HashMap<LabelId, usize> = func
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.name, i))
+            .collect();
+
+        // A back edge is an edge whose target dominates its source. Loops sharing a header are
+        // merged into one natural loop.
+        let mut latches: HashMap<LabelId, Vec<LabelId>> = HashMap::new();
+        let mut headers: Vec<LabelId> = vec![];
+        for block in func.blocks.iter() {
+            for &succ in cfg[&block.name].iter() {
+                if doms.dominates(succ, block.name) {
+                    if !latches.contains_key(&succ) {
+                        headers.push(succ);
+                    }
+                    latches.entry(succ).or_default().push(block.name);
+                }
+            }
+        }
+
+        let mut loops: Vec<Loop> = headers
+            .iter()
+            .map(|&header| {
+                let mut body: HashSet<LabelId> = HashSet::from([header]);
+                let mut worklist: Vec<LabelId> = latches[&header].clone();
+                while let Some(block) = worklist.pop() {
+                    if body.insert(block) {
+                        worklist.extend(preds[&block].iter().filter(|&&p| doms.is_reachable(p)));
+                    }
+                }
+                let mut body: Vec<LabelId> = body.into_iter().collect();
+                body.sort_by_key(|name| position[name]);
+                Loop {
+                    header,
+                    latches: latches[&header].clone(),
+                    body,
+                    parent: None,
+                    children: vec![],
+                    depth: 0,
+                }
+            })
+            .collect();
+
+        // Larger loops first, so a loop's parent is always placed before it
+        loops.sort_by_key(|l| (Reverse(l.body.len()), position[&l.header]));
+        for i in 0..loops.len() {
+            // The parent is the smallest earlier loop containing this header
+            let parent = (0..i).rev().find(|&j| loops[j].contains(loops[i].header));
+            if let Some(j) = parent {
+                loops[i].parent = Some(j);
+                loops[i].depth = loops[j].depth + 1;
+                loops[j].children.push(i);
+            } else {
+                loops[i].depth = 1;
+            }
+        }
+
+        LoopForest { loops }
+    }
+
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.loops.len())
+            .filter(|&i| self.loops[i].parent.is_none())
+            .collect()
+    }
+
+    /// Index of the innermost loop containing `block`.
+    pub fn innermost(&self, block: LabelId) -> Option<usize> {
+        (0..self.loops.len())
+            .rev()
+            .find(|&i| self.loops[i].contains(block))
+    }
+
+    pub fn depth(&self, block: LabelId) -> usize {
+        match self.innermost(block) {
+            Some(i) => self.loops[i].depth,
+            None => 0,
+        }
+    }
+
+    /// Loop indices with every inner loop before the loops enclosing it.
+    pub fn inner_to_outer(&self) -> Vec<usize> {
+        (0..self.loops.len()).rev().collect()
+    }
+
+    /// The block outside the loop that falls or jumps only into its header, if there is one.
+    pub fn preheader(&self, func: &Function, index: usize) -> Option<LabelId> {
+        let cfg = control_flow_graph(func);
+        let preds = predecessors(func, &cfg);
+        let l = &self.loops[index];
+        let outside: Vec<LabelId> = preds[&l.header]
+            .iter()
+            .copied()
+            .filter(|&pred| !l.contains(pred))
+            .collect();
+        match outside.as_slice() {
+            [pred] if cfg[pred] == [l.header] => Some(*pred),
+            _ => None,
+        }
+    }
+}
+
+impl Function {
+    /// Gives every loop a dedicated preheader: a block outside the loop whose only successor is
+    /// the header. Loops whose header has several outside predecessors, or an outside predecessor
+    /// that also branches elsewhere, get a new empty block placed right before the header.
+    pub fn insert_preheaders(&self) -> Function {
+        let mut func = self.clone();
+        loop {
+            let forest = LoopForest::new(&func);
+            let missing = (0..forest.loops.len()).find(|&i| forest.preheader(&func, i).is_none());
+            let l = match missing {
+                Some(i) => &forest.loops[i],
+                None => break,
+            };
+
+            let header = l.header;
+            let preheader = func.fresh_block_name(&format!("{}.preheader", header));
+            let index = func.blocks.iter().position(|b| b.name == header).unwrap();
+
+            for block in func.blocks.iter_mut() {
+                if l.contains(block.name) {
+                    continue;
+                }
+                // Guards are edges too: a failing one jumps to its label from the middle
+                for instr in block.instructions.iter_mut() {
+                    let is_branch = matches!(
+                        instr.op,
+                        OpCode::Control(ControlOp::Jmp)
+                            | OpCode::Control(ControlOp::Br)
+                            | OpCode::Speculation(SpeculationOp::Guard)
+                    );
+                    if is_branch {
+                        for label in instr.labels.as_mut().unwrap().iter_mut() {
+                            if *label == header {
+                                *label = preheader;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // A latch placed right before the header would now fall into the preheader instead
+            if index > 0 && l.contains(func.blocks[index - 1].name) {
+                let prev = &mut func.blocks[index - 1];
+                if !prev.instructions.last().is_some_and(|i| i.is_terminator()) {
+                    prev.instructions.push(Instruction::jmp(header));
+                }
+            }
+
+            func.blocks.insert(
+                index,
+                BasicBlock {
+                    name: preheader,
+                    instructions: vec![],
+                    pos: None,
+                    synthetic: true,
+                },
+            );
+        }
+        func
+    }
+}
+
+impl fmt::Display for LoopForest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.loops.is_empty() {
+            return writeln!(f, "no loops");
+        }
+        writeln!(f, "{} loop(s)", self.loops.len())?;
+
+        let mut stack: Vec<usize> = self.roots();
+        stack.reverse();
+        while let Some(i) = stack.pop() {
+            let l = &self.loops[i];
+            write!(
+                f,
+                "{}.{} (depth {}): latches",
+                "  ".repeat(l.depth),
+                l.header,
+                l.depth
+            )?;
+            for latch in l.latches.iter() {
+                write!(f, " .{}", latch)?;
+            }
+            write!(f, "; body")?;
+            for block in l.body.iter() {
+                write!(f, " .{}", block)?;
+            }
+            writeln!(f)?;
+            stack.extend(l.children.iter().rev());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+mod c;
+mod llvm;
+mod wasm;
+mod x86;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::core::{
+    at, fresh_name, BasicBlock, ControlOp, Function, Instruction, LabelId, MiscOp, OpCode,
+    SpeculationOp, Type, VarId,
+};
+use crate::ssa::variable_types;
+
+pub use crate::codegen::c::emit_c;
+pub use crate::codegen::llvm::emit_llvm;
+pub use crate::codegen::wasm::emit_wat;
+pub use crate::codegen::x86::emit_x86;
+
+/// Types of every argument and destination of `func`, or an error naming the first variable it
+/// reads that is neither.
+fn defined_types(func: &Function) -> Result<HashMap<VarId, Type>, Box<dyn Error>> {
+    let types = variable_types(func);
+    for instr in func
+        .blocks
+        .iter()
+        .flat_map(|block| block.instructions.iter())
+    {
+        if let Some(arg) = instr.uses().iter().find(|arg| !types.contains_key(arg)) {
+            return Err(format!(
+                "@{}{} uses undefined variable {}",
+                func.name,
+                at(instr.pos),
+                arg
+            )
+            .into());
+        }
+    }
+    Ok(types)
+}
+
+/// Backends handle neither phis nor speculation, so functions still in SSA form are lowered out
+/// of it first and speculation is then turned into plain copies.
+fn lowered(func: &Function) -> Function {
+    let has_phis = func.blocks.iter().any(|block| {
+        block
+            .instructions
+            .iter()
+            .any(|instr| instr.op == OpCode::Phi)
+    });
+    let func = if has_phis {
+        func.from_ssa()
+    } else {
+        func.clone()
+    };
+    without_speculation(&func)
+}
+
+/// `speculate` copies every variable to a shadow variable, each `guard` becomes a branch to a
+/// block that copies them back before jumping to the guard's label, and `commit` is dropped.
+/// Speculation regions must not nest.
+fn without_speculation(func: &Function) -> Function {
+    let speculates = func.blocks.iter().any(|block| {
+        block
+            .instructions
+            .iter()
+            .any(|instr| matches!(instr.op, OpCode::Speculation(_)))
+    });
+    if !speculates {
+        return func.clone();
+    }
+
+    let mut taken_vars: HashSet<VarId> = variable_types(func).into_keys().collect();
+    for block in func.blocks.iter() {
+        for instr in block.instructions.iter() {
+            taken_vars.extend(instr.uses().iter().copied());
+        }
+    }
+    let mut vars: Vec<(VarId, Type)> = variable_types(func).into_iter().collect();
+    vars.sort_by(|a, b| a.0.cmp_by_name(&b.0));
+    let shadows: Vec<VarId> = vars
+        .iter()
+        .map(|(var, _)| fresh_name(&format!("{}.saved", var), &mut taken_vars))
+        .collect();
+    let copy = |dst: VarId, ty: &Type, src: VarId| {
+        Instruction::compute(OpCode::Misc(MiscOp::Id), dst, ty.clone(), vec![src])
+    };
+
+    let mut taken_blocks: HashSet<LabelId> = func.blocks.iter().map(|block| block.name).collect();
+    let mut rollbacks: Vec<(LabelId, BasicBlock)> = vec![];
+    let mut blocks: Vec<BasicBlock> = vec![];
+    for block in func.blocks.iter() {
+        let mut current = BasicBlock {
+            name: block.name,
+            instructions: vec![],
+            pos: block.pos,
+            synthetic: block.synthetic,
+        };
+        for instr in block.instructions.iter() {
+            match &instr.op {
+                OpCode::Speculation(SpeculationOp::Speculate) => {
+                    for ((var, ty), shadow) in vars.iter().zip(shadows.iter()) {
+                        current.instructions.push(copy(*shadow, ty, *var));
+                    }
+                }
+                OpCode::Speculation(SpeculationOp::Commit) => {}
+                OpCode::Speculation(SpeculationOp::Guard) => {
+                    let target = instr.labels.as_ref().unwrap()[0];
+                    let rollback = match rollbacks.iter().find(|(t, _)| *t == target) {
+                        Some((_, rollback)) => rollback.name,
+                        None => {
+                            let mut instructions: Vec<Instruction> = vars
+                                .iter()
+                                .zip(shadows.iter())
+                                .map(|((var, ty), shadow)| copy(*var, ty, *shadow))
+                                .collect();
+                            instructions.push(Instruction::jmp(target));
+                            let name =
+                                fresh_name(&format!("{}.rollback", target), &mut taken_blocks);
+                            rollbacks.push((
+                                target,
+                                BasicBlock {
+                                    name,
+                                    instructions,
+                                    synthetic: true,
+                                    pos: None,
+                                },
+                            ));
+                            name
+                        }
+                    };
+                    let next = fresh_name(&format!("{}.guarded", block.name), &mut taken_blocks);
+                    current.instructions.push(Instruction {
+                        op: OpCode::Control(ControlOp::Br),
+                        labels: Some([next, rollback].into()),
+                        ..instr.clone()
+                    });
+                    blocks.push(std::mem::replace(
+                        &mut current,
+                        BasicBlock {
+                            name: next,
+                            instructions: vec![],
+                            synthetic: true,
+                            pos: None,
+                        },
+                    ));
+                }
+                _ => current.instructions.push(instr.clone()),
+            }
+        }
+        blocks.push(current);
+    }
+    // Rollback blocks end in a jump, so placing them after a block that doesn't fall through
+    // leaves every other block's successor unchanged
+    let position = blocks
+        .iter()
+        .position(|block| {
+            block
+                .instructions
+                .last()
+                .is_some_and(|instr| instr.is_terminator())
+        })
+        .map_or(blocks.len(), |i| i + 1);
+    blocks.splice(
+        position..position,
+        rollbacks.into_iter().map(|(_, rollback)| rollback),
+    );
+    Function {
+        blocks,
+        ..func.clone()
+    }
+}
+
+/// Turns a Bril identifier into one that is valid in every target language. Letters and digits
+/// are kept, an underscore is doubled and any other character becomes `_XX_`, with XX its hex
+/// code, so different names never mangle to the same identifier.
+fn mangle(name: &str) -> String {
+    let mut mangled = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            mangled.push(c);
+        } else if c == '_' {
+            mangled.push_str("__");
+        } else {
+            mangled.push_str(&format!("_{:02x}_", c as u32));
+        }
+    }
+    mangled
+}
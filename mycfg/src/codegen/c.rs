@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use crate::codegen::{defined_types, lowered, mangle};
+use crate::core::{
+    ArithmeticOp, ComparisonOp, ControlOp, FuncId, Function, Instruction, LabelId, LogicOp, MiscOp,
+    OpCode, Program, Type, Value, VarId,
+};
+
+/// Bril integers wrap around on overflow, which signed arithmetic in C doesn't promise, so it is
+/// done on unsigned values instead. Division by zero is reported like the reference interpreter
+/// does rather than left undefined.
+const RUNTIME: &str = r#"#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static inline int64_t rt_add(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }
+static inline int64_t rt_sub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }
+static inline int64_t rt_mul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }
+
+static inline int64_t rt_div(int64_t a, int64_t b) {
+    if (b == 0) {
+        fputs("error: division by zero\n", stderr);
+        exit(2);
+    }
+    if (b == -1) {
+        return (int64_t)(0 - (uint64_t)a);
+    }
+    return a / b;
+}
+
+static inline void rt_print_int(int64_t n) { printf("%lld", (long long)n); }
+static inline void rt_print_bool(bool b) { fputs(b ? "true" : "false", stdout); }
+"#;
+
+/// Emits a C99 translation unit with one C function per Bril function. Blocks become `goto`
+/// labels and variables become `int64_t` or `bool` locals, all named after mangled Bril names
+/// with a prefix so they can't clash with C keywords or the runtime. Functions reading a
+/// variable they never define are rejected.
+pub fn emit_c(prog: &Program) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    writeln!(out, "{}", RUNTIME)?;
+    for func in prog.functions.iter() {
+        writeln!(out, "{};", signature(func))?;
+    }
+    writeln!(out)?;
+    for func in prog.functions.iter() {
+        let func = lowered(func);
+        let types = defined_types(&func)?;
+        write_function(&mut out, &func, &types)?;
+    }
+    if let Some(main) = prog.functions.iter().find(|func| func.name == "main") {
+        write_entry_point(&mut out, main)?;
+    }
+    Ok(out)
+}
+
+fn c_type(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int => "int64_t",
+        Type::Bool => "bool",
+    }
+}
+
+fn symbol(func: &FuncId) -> String {
+    format!("bril_{}", mangle(&func.name()))
+}
+
+fn label(block: &LabelId) -> String {
+    format!("l_{}", mangle(&block.name()))
+}
+
+fn variable(var: &VarId) -> String {
+    format!("v_{}", mangle(&var.name()))
+}
+
+fn signature(func: &Function) -> String {
+    let params: Vec<String> = func
+        .args
+        .iter()
+        .map(|(arg, ty)| format!("{} {}", c_type(ty), variable(arg)))
+        .collect();
+    format!(
+        "{} {}({})",
+        func.ret_type.as_ref().map_or("void", c_type),
+        symbol(&func.name),
+        if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        }
+    )
+}
+
+fn write_function(out: &mut String, func: &Function, types: &HashMap<VarId, Type>) -> fmt::Result {
+    writeln!(out, "{} {{", signature(func))?;
+    let mut locals: Vec<(&VarId, &Type)> = types
+        .iter()
+        .filter(|(var, _)| !func.args.iter().any(|(arg, _)| arg == *var))
+        .collect();
+    locals.sort_by(|a, b| a.0.cmp_by_name(b.0));
+    for (var, ty) in locals {
+        writeln!(out, "    {} {} = 0;", c_type(ty), variable(var))?;
+    }
+    for (i, block) in func.blocks.iter().enumerate() {
+        writeln!(out, "{}:;", label(&block.name))?;
+        let next = func.blocks.get(i + 1).map(|next| next.name);
+        for instr in block.instructions.iter() {
+            write_instruction(out, types, instr, next)?;
+        }
+    }
+    // Falling off the end of a function with a return type is an error in Bril
+    if func.ret_type.is_some() {
+        writeln!(out, "    abort();")?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+fn write_instruction(
+    out: &mut String,
+    types: &HashMap<VarId, Type>,
+    instr: &Instruction,
+    next: Option<LabelId>,
+) -> fmt::Result {
+    let args: Vec<String> = instr.uses().iter().map(variable).collect();
+    let value = match &instr.op {
+        OpCode::Const => match instr.value.as_ref().unwrap() {
+            // The most negative integer can't be written as a literal
+            Value::Int(n) if *n == isize::MIN => Some("INT64_MIN".to_string()),
+            Value::Int(n) => Some(format!("INT64_C({})", n)),
+            Value::Bool(b) => Some(b.to_string()),
+        },
+        OpCode::Arithmetic(aop) => {
+            let helper = match aop {
+                ArithmeticOp::Add => "rt_add",
+                ArithmeticOp::Sub => "rt_sub",
+                ArithmeticOp::Mul => "rt_mul",
+                ArithmeticOp::Div => "rt_div",
+            };
+            Some(format!("{}({}, {})", helper, args[0], args[1]))
+        }
+        OpCode::Comparison(cop) => {
+            let op = match cop {
+                ComparisonOp::Eq => "==",
+                ComparisonOp::Lt => "<",
+                ComparisonOp::Gt => ">",
+                ComparisonOp::Le => "<=",
+                ComparisonOp::Ge => ">=",
+            };
+            Some(format!("{} {} {}", args[0], op, args[1]))
+        }
+        OpCode::Logic(LogicOp::Not) => Some(format!("!{}", args[0])),
+        OpCode::Logic(LogicOp::And) => Some(format!("{} && {}", args[0], args[1])),
+        OpCode::Logic(LogicOp::Or) => Some(format!("{} || {}", args[0], args[1])),
+        OpCode::Misc(MiscOp::Id) => Some(args[0].clone()),
+        OpCode::Control(ControlOp::Call) => Some(format!(
+            "{}({})",
+            symbol(&instr.funcs.as_ref().unwrap()[0]),
+            args.join(", ")
+        )),
+        OpCode::Misc(MiscOp::Print) => {
+            for (i, (arg, var)) in instr.uses().iter().zip(args.iter()).enumerate() {
+                if i > 0 {
+                    writeln!(out, "    putchar(' ');")?;
+                }
+                let helper = match types[arg] {
+                    Type::Int => "rt_print_int",
+                    Type::Bool => "rt_print_bool",
+                };
+                writeln!(out, "    {}({});", helper, var)?;
+            }
+            writeln!(out, "    putchar('\\n');")?;
+            None
+        }
+        OpCode::Misc(MiscOp::Nop) => None,
+        OpCode::Control(ControlOp::Jmp) => {
+            let target = &instr.labels.as_ref().unwrap()[0];
+            if next != Some(*target) {
+                writeln!(out, "    goto {};", label(target))?;
+            }
+            None
+        }
+        OpCode::Control(ControlOp::Br) => {
+            // Falling through to the next block keeps the C compiler's layout close to ours
+            let labels = instr.labels.as_ref().unwrap();
+            if next == Some(labels[1]) {
+                writeln!(out, "    if ({}) goto {};", args[0], label(&labels[0]))?;
+            } else if next == Some(labels[0]) {
+                writeln!(out, "    if (!{}) goto {};", args[0], label(&labels[1]))?;
+            } else {
+                writeln!(
+                    out,
+                    "    if ({}) goto {}; else goto {};",
+                    args[0],
+                    label(&labels[0]),
+                    label(&labels[1])
+                )?;
+            }
+            None
+        }
+        OpCode::Control(ControlOp::Ret) => {
+            match args.first() {
+                Some(arg) => writeln!(out, "    return {};", arg)?,
+                None => writeln!(out, "    return;")?,
+            }
+            None
+        }
+        OpCode::Phi | OpCode::Speculation(_) => {
+            unreachable!("phis and speculation are removed before lowering")
+        }
+    };
+    match (&instr.dst, value) {
+        (Some(dst), Some(value)) => writeln!(out, "    {} = {};", variable(dst), value),
+        // A call whose result is discarded
+        (None, Some(value)) => writeln!(out, "    {};", value),
+        _ => Ok(()),
+    }
+}
+
+/// A C `main` that converts `argv` to Bril values and calls the Bril `main`.
+fn write_entry_point(out: &mut String, main: &Function) -> fmt::Result {
+    writeln!(out, "int main(int argc, char **argv) {{")?;
+    writeln!(out, "    if (argc != {}) {{", main.args.len() + 1)?;
+    writeln!(
+        out,
+        "        fputs(\"error: wrong number of arguments to main\\n\", stderr);"
+    )?;
+    writeln!(out, "        return 1;")?;
+    writeln!(out, "    }}")?;
+    let args: Vec<String> = main
+        .args
+        .iter()
+        .enumerate()
+        .map(|(i, (_, ty))| match ty {
+            Type::Int => format!("strtoll(argv[{}], NULL, 10)", i + 1),
+            Type::Bool => format!("strcmp(argv[{}], \"true\") == 0", i + 1),
+        })
+        .collect();
+    writeln!(out, "    {}({});", symbol(&main.name), args.join(", "))?;
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")
+}
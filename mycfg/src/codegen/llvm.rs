@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use crate::codegen::{defined_types, lowered, mangle};
+use crate::core::{
+    ArithmeticOp, ComparisonOp, ControlOp, FuncId, Function, Instruction, LabelId, LogicOp, MiscOp,
+    OpCode, Program, Type, Value, VarId,
+};
+
+/// Declarations and `print` and `div` helpers shared by every module. Pointers are spelled with
+/// their pointee types, which both older and newer LLVM releases accept. `sdiv` is undefined for
+/// a zero divisor and for the smallest integer divided by -1, so division by zero is reported
+/// like the reference interpreter does and division by -1 wraps around.
+const RUNTIME: &str = r#"@rt.int_format = private constant [5 x i8] c"%lld\00"
+@rt.true = private constant [5 x i8] c"true\00"
+@rt.false = private constant [6 x i8] c"false\00"
+@rt.arity_error = private constant [42 x i8] c"error: wrong number of arguments to main\0A\00"
+@rt.div_error = private constant [25 x i8] c"error: division by zero\0A\00"
+@stderr = external global i8*
+
+declare i32 @printf(i8*, ...)
+declare i32 @putchar(i32)
+declare i32 @fputs(i8*, i8*)
+declare i64 @atoll(i8*)
+declare i32 @strcmp(i8*, i8*)
+declare void @exit(i32) noreturn
+
+define private void @rt.print_int(i64 %n) {
+  %format = getelementptr inbounds [5 x i8], [5 x i8]* @rt.int_format, i64 0, i64 0
+  call i32 (i8*, ...) @printf(i8* %format, i64 %n)
+  ret void
+}
+
+define private void @rt.print_bool(i1 %b) {
+  %true = getelementptr inbounds [5 x i8], [5 x i8]* @rt.true, i64 0, i64 0
+  %false = getelementptr inbounds [6 x i8], [6 x i8]* @rt.false, i64 0, i64 0
+  %s = select i1 %b, i8* %true, i8* %false
+  call i32 (i8*, ...) @printf(i8* %s)
+  ret void
+}
+
+define private i64 @rt.div(i64 %a, i64 %b) {
+entry:
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %error, label %nonzero
+error:
+  %message = getelementptr inbounds [25 x i8], [25 x i8]* @rt.div_error, i64 0, i64 0
+  %stderr = load i8*, i8** @stderr
+  call i32 @fputs(i8* %message, i8* %stderr)
+  call void @exit(i32 2)
+  unreachable
+nonzero:
+  %minus_one = icmp eq i64 %b, -1
+  br i1 %minus_one, label %negate, label %divide
+negate:
+  %negated = sub i64 0, %a
+  ret i64 %negated
+divide:
+  %quotient = sdiv i64 %a, %b
+  ret i64 %quotient
+}
+"#;
+
+/// Translates a program to textual LLVM IR. Every variable gets an `alloca` in the function's
+/// entry block and each instruction loads its operands and stores its result, leaving it to
+/// `mem2reg` to build SSA form. Bril functions become `@bril.<name>` and a C `main` is added
+/// when the program has a `main` function. Functions reading a variable they never define are
+/// rejected.
+pub fn emit_llvm(prog: &Program) -> Result<String, Box<dyn Error>> {
+    let returns: HashMap<FuncId, &Option<Type>> = prog
+        .functions
+        .iter()
+        .map(|func| (func.name, &func.ret_type))
+        .collect();
+    let mut out = String::new();
+    writeln!(out, "{}", RUNTIME)?;
+    for func in prog.functions.iter() {
+        let func = lowered(func);
+        let types = defined_types(&func)?;
+        write_function(&mut out, &func, types, &returns)?;
+    }
+    if let Some(main) = prog.functions.iter().find(|func| func.name == "main") {
+        write_entry_point(&mut out, main)?;
+    }
+    Ok(out)
+}
+
+fn llvm_type(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int => "i64",
+        Type::Bool => "i1",
+    }
+}
+
+fn return_type(func: &Function) -> &'static str {
+    func.ret_type.as_ref().map_or("void", llvm_type)
+}
+
+fn symbol(func: &FuncId) -> String {
+    format!("@bril.{}", mangle(&func.name()))
+}
+
+fn label(block: &LabelId) -> String {
+    format!("bb.{}", mangle(&block.name()))
+}
+
+fn variable(var: &VarId) -> String {
+    format!("%v.{}", mangle(&var.name()))
+}
+
+/// Emits instructions for one function, numbering the temporaries it introduces.
+struct FunctionWriter<'a> {
+    out: &'a mut String,
+    func: &'a Function,
+    returns: &'a HashMap<FuncId, &'a Option<Type>>,
+    types: HashMap<VarId, Type>,
+    temps: usize,
+}
+
+impl FunctionWriter<'_> {
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    /// Loads `var` into a fresh temporary and returns it with its type.
+    fn load(&mut self, var: VarId) -> Result<(String, &'static str), fmt::Error> {
+        let ty = llvm_type(&self.types[&var]);
+        let temp = self.temp();
+        writeln!(
+            self.out,
+            "  {} = load {}, {}* {}",
+            temp,
+            ty,
+            ty,
+            variable(&var)
+        )?;
+        Ok((temp, ty))
+    }
+
+    fn store(&mut self, var: VarId, value: &str) -> fmt::Result {
+        let ty = llvm_type(&self.types[&var]);
+        writeln!(
+            self.out,
+            "  store {} {}, {}* {}",
+            ty,
+            value,
+            ty,
+            variable(&var)
+        )
+    }
+
+    fn write(&mut self) -> fmt::Result {
+        let func = self.func;
+        let params: Vec<String> = func
+            .args
+            .iter()
+            .map(|(arg, ty)| format!("{} %a.{}", llvm_type(ty), mangle(&arg.name())))
+            .collect();
+        writeln!(
+            self.out,
+            "define {} {}({}) {{",
+            return_type(func),
+            symbol(&func.name),
+            params.join(", ")
+        )?;
+        writeln!(self.out, "entry:")?;
+        let mut vars: Vec<&VarId> = self.types.keys().collect();
+        vars.sort_by(|a, b| a.cmp_by_name(b));
+        for var in vars {
+            writeln!(
+                self.out,
+                "  {} = alloca {}",
+                variable(var),
+                llvm_type(&self.types[var])
+            )?;
+        }
+        for (arg, _) in func.args.iter() {
+            self.store(*arg, &format!("%a.{}", mangle(&arg.name())))?;
+        }
+        // The entry block of an LLVM function can't be a branch target
+        match func.blocks.first() {
+            Some(first) => writeln!(self.out, "  br label %{}", label(&first.name))?,
+            None => self.write_fallthrough_return()?,
+        }
+
+        for (i, block) in func.blocks.iter().enumerate() {
+            writeln!(self.out, "{}:", label(&block.name))?;
+            for instr in block.instructions.iter() {
+                self.write_instruction(instr)?;
+            }
+            if !block
+                .instructions
+                .last()
+                .is_some_and(|instr| instr.is_terminator())
+            {
+                match func.blocks.get(i + 1) {
+                    Some(next) => writeln!(self.out, "  br label %{}", label(&next.name))?,
+                    None => self.write_fallthrough_return()?,
+                }
+            }
+        }
+        writeln!(self.out, "}}")?;
+        writeln!(self.out)
+    }
+
+    /// Falling off the end of a function returns; doing so from one with a return type is an
+    /// error in Bril, so that path is left undefined.
+    fn write_fallthrough_return(&mut self) -> fmt::Result {
+        if self.func.ret_type.is_some() {
+            writeln!(self.out, "  unreachable")
+        } else {
+            writeln!(self.out, "  ret void")
+        }
+    }
+
+    fn write_instruction(&mut self, instr: &Instruction) -> fmt::Result {
+        let mut args: Vec<(String, &'static str)> = vec![];
+        for arg in instr.uses() {
+            args.push(self.load(*arg)?);
+        }
+        let result = match &instr.op {
+            OpCode::Const => match instr.value.as_ref().unwrap() {
+                Value::Int(n) => Some(n.to_string()),
+                Value::Bool(b) => Some(b.to_string()),
+            },
+            OpCode::Arithmetic(ArithmeticOp::Div) => {
+                let temp = self.temp();
+                writeln!(
+                    self.out,
+                    "  {} = call i64 @rt.div(i64 {}, i64 {})",
+                    temp, args[0].0, args[1].0
+                )?;
+                Some(temp)
+            }
+            OpCode::Arithmetic(aop) => {
+                let op = match aop {
+                    ArithmeticOp::Add => "add",
+                    ArithmeticOp::Sub => "sub",
+                    ArithmeticOp::Mul => "mul",
+                    ArithmeticOp::Div => unreachable!(),
+                };
+                let temp = self.temp();
+                writeln!(
+                    self.out,
+                    "  {} = {} i64 {}, {}",
+                    temp, op, args[0].0, args[1].0
+                )?;
+                Some(temp)
+            }
+            OpCode::Comparison(cop) => {
+                let cond = match cop {
+                    ComparisonOp::Eq => "eq",
+                    ComparisonOp::Lt => "slt",
+                    ComparisonOp::Gt => "sgt",
+                    ComparisonOp::Le => "sle",
+                    ComparisonOp::Ge => "sge",
+                };
+                let temp = self.temp();
+                writeln!(
+                    self.out,
+                    "  {} = icmp {} i64 {}, {}",
+                    temp, cond, args[0].0, args[1].0
+                )?;
+                Some(temp)
+            }
+            OpCode::Logic(lop) => {
+                let temp = self.temp();
+                match lop {
+                    LogicOp::Not => writeln!(self.out, "  {} = xor i1 {}, true", temp, args[0].0)?,
+                    LogicOp::And => {
+                        writeln!(self.out, "  {} = and i1 {}, {}", temp, args[0].0, args[1].0)?
+                    }
+                    LogicOp::Or => {
+                        writeln!(self.out, "  {} = or i1 {}, {}", temp, args[0].0, args[1].0)?
+                    }
+                }
+                Some(temp)
+            }
+            OpCode::Misc(MiscOp::Id) => Some(args[0].0.clone()),
+            OpCode::Misc(MiscOp::Print) => {
+                for (i, (value, ty)) in args.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(self.out, "  call i32 @putchar(i32 32)")?;
+                    }
+                    let helper = if *ty == "i1" {
+                        "print_bool"
+                    } else {
+                        "print_int"
+                    };
+                    writeln!(self.out, "  call void @rt.{}({} {})", helper, ty, value)?;
+                }
+                writeln!(self.out, "  call i32 @putchar(i32 10)")?;
+                None
+            }
+            OpCode::Misc(MiscOp::Nop) => None,
+            OpCode::Control(ControlOp::Jmp) => {
+                let target = &instr.labels.as_ref().unwrap()[0];
+                writeln!(self.out, "  br label %{}", label(target))?;
+                None
+            }
+            OpCode::Control(ControlOp::Br) => {
+                let labels = instr.labels.as_ref().unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 {}, label %{}, label %{}",
+                    args[0].0,
+                    label(&labels[0]),
+                    label(&labels[1])
+                )?;
+                None
+            }
+            OpCode::Control(ControlOp::Call) => {
+                let params: Vec<String> = args
+                    .iter()
+                    .map(|(value, ty)| format!("{} {}", ty, value))
+                    .collect();
+                // A call discarding its result still has to match the callee's signature
+                let callee = instr.funcs.as_ref().unwrap()[0];
+                let ret_type = self.returns.get(&callee).and_then(|ty| ty.as_ref());
+                let callee = symbol(&callee);
+                match ret_type {
+                    Some(ty) => {
+                        let temp = self.temp();
+                        writeln!(
+                            self.out,
+                            "  {} = call {} {}({})",
+                            temp,
+                            llvm_type(ty),
+                            callee,
+                            params.join(", ")
+                        )?;
+                        Some(temp)
+                    }
+                    None => {
+                        writeln!(self.out, "  call void {}({})", callee, params.join(", "))?;
+                        None
+                    }
+                }
+            }
+            OpCode::Control(ControlOp::Ret) => {
+                match args.first() {
+                    Some((value, ty)) => writeln!(self.out, "  ret {} {}", ty, value)?,
+                    None => writeln!(self.out, "  ret void")?,
+                }
+                None
+            }
+            OpCode::Phi | OpCode::Speculation(_) => {
+                unreachable!("phis and speculation are removed before lowering")
+            }
+        };
+        if let (Some(dst), Some(value)) = (instr.dst, result) {
+            self.store(dst, &value)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_function(
+    out: &mut String,
+    func: &Function,
+    types: HashMap<VarId, Type>,
+    returns: &HashMap<FuncId, &Option<Type>>,
+) -> fmt::Result {
+    FunctionWriter {
+        out,
+        func,
+        returns,
+        types,
+        temps: 0,
+    }
+    .write()
+}
+
+/// A C `main` that converts `argv` to Bril values and calls `@bril.main`.
+fn write_entry_point(out: &mut String, main: &Function) -> fmt::Result {
+    writeln!(out, "define i32 @main(i32 %argc, i8** %argv) {{")?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  %arity = icmp eq i32 %argc, {}", main.args.len() + 1)?;
+    writeln!(out, "  br i1 %arity, label %args, label %error")?;
+    writeln!(out, "error:")?;
+    writeln!(
+        out,
+        "  %message = getelementptr inbounds [42 x i8], [42 x i8]* @rt.arity_error, i64 0, i64 0"
+    )?;
+    writeln!(out, "  %stderr = load i8*, i8** @stderr")?;
+    writeln!(out, "  call i32 @fputs(i8* %message, i8* %stderr)")?;
+    writeln!(out, "  ret i32 1")?;
+    writeln!(out, "args:")?;
+    let mut params: Vec<String> = vec![];
+    for (i, (_, ty)) in main.args.iter().enumerate() {
+        writeln!(
+            out,
+            "  %p{} = getelementptr inbounds i8*, i8** %argv, i64 {}",
+            i,
+            i + 1
+        )?;
+        writeln!(out, "  %s{} = load i8*, i8** %p{}", i, i)?;
+        match ty {
+            Type::Int => writeln!(out, "  %arg{} = call i64 @atoll(i8* %s{})", i, i)?,
+            Type::Bool => {
+                writeln!(
+                    out,
+                    "  %true{} = getelementptr inbounds [5 x i8], [5 x i8]* @rt.true, i64 0, i64 0",
+                    i
+                )?;
+                writeln!(
+                    out,
+                    "  %cmp{} = call i32 @strcmp(i8* %s{}, i8* %true{})",
+                    i, i, i
+                )?;
+                writeln!(out, "  %arg{} = icmp eq i32 %cmp{}, 0", i, i)?;
+            }
+        }
+        params.push(format!("{} %arg{}", llvm_type(ty), i));
+    }
+    writeln!(
+        out,
+        "  call {} {}({})",
+        return_type(main),
+        symbol(&main.name),
+        params.join(", ")
+    )?;
+    writeln!(out, "  ret i32 0")?;
+    writeln!(out, "}}")
+}
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use crate::analysis::{predecessors, Dominators};
+use crate::codegen::{defined_types, lowered, mangle};
+use crate::core::{
+    ArithmeticOp, ComparisonOp, ControlOp, FuncId, Function, Instruction, LabelId, LogicOp, MiscOp,
+    OpCode, Program, Type, Value, VarId,
+};
+use crate::parser::control_flow_graph;
+
+/// `print` is provided by the host as one import per piece of output. Division goes through a
+/// helper because `i64.div_s` traps where Bril wraps around.
+const RUNTIME: &str = r#"  (import "print" "int" (func $print.int (param i64)))
+  (import "print" "bool" (func $print.bool (param i32)))
+  (import "print" "space" (func $print.space))
+  (import "print" "newline" (func $print.newline))
+
+  (func $rt.div (param $a i64) (param $b i64) (result i64)
+    local.get $b
+    i64.const -1
+    i64.eq
+    if
+      i64.const 0
+      local.get $a
+      i64.sub
+      return
+    end
+    local.get $a
+    local.get $b
+    i64.div_s
+  )
+"#;
+
+/// Translates a program to a WebAssembly text module exporting every function under its Bril
+/// name. Structured control flow is recovered with Ramsey's algorithm from "Beyond Relooper",
+/// which handles any reducible CFG; functions with irreducible control flow or reading a variable
+/// they never define are rejected.
+pub fn emit_wat(prog: &Program) -> Result<String, Box<dyn Error>> {
+    let returns: HashMap<FuncId, &Option<Type>> = prog
+        .functions
+        .iter()
+        .map(|func| (func.name, &func.ret_type))
+        .collect();
+    let mut out = String::new();
+    writeln!(out, "(module")?;
+    writeln!(out, "{}", RUNTIME)?;
+    for func in prog.functions.iter() {
+        let func = lowered(func);
+        let mut writer = FunctionWriter::new(&func, &returns, &mut out)?;
+        writer.write()?;
+    }
+    writeln!(out, ")")?;
+    Ok(out)
+}
+
+fn wasm_type(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int => "i64",
+        Type::Bool => "i32",
+    }
+}
+
+fn symbol(func: &FuncId) -> String {
+    format!("$bril.{}", mangle(&func.name()))
+}
+
+fn variable(var: &VarId) -> String {
+    format!("$v.{}", mangle(&var.name()))
+}
+
+/// Emits one function. Every CFG node is placed by walking the dominator tree: a node's code is
+/// nested inside a `block` for each dominator tree child with several forward predecessors, so
+/// those children can be reached by breaking out, and loop headers are wrapped in a `loop` that
+/// back edges continue.
+struct FunctionWriter<'a> {
+    func: &'a Function,
+    returns: &'a HashMap<FuncId, &'a Option<Type>>,
+    out: &'a mut String,
+    doms: Dominators,
+    rpo_index: HashMap<LabelId, usize>,
+    merge_nodes: HashSet<LabelId>,
+    loop_headers: HashSet<LabelId>,
+    types: HashMap<VarId, Type>,
+    depth: usize,
+}
+
+impl<'a> FunctionWriter<'a> {
+    fn new(
+        func: &'a Function,
+        returns: &'a HashMap<FuncId, &'a Option<Type>>,
+        out: &'a mut String,
+    ) -> Result<FunctionWriter<'a>, Box<dyn Error>> {
+        let doms = Dominators::new(func);
+        let cfg = control_flow_graph(func);
+        let preds = predecessors(func, &cfg);
+        let rpo_index: HashMap<LabelId, usize> = doms
+            .reverse_postorder()
+            .iter()
+            .enumerate()
+            .map(|(i, &block)| (block, i))
+            .collect();
+
+        let mut merge_nodes: HashSet<LabelId> = HashSet::new();
+        let mut loop_headers: HashSet<LabelId> = HashSet::new();
+        for &block in doms.reverse_postorder() {
+            let forward = preds[&block]
+                .iter()
+                .filter(|pred| rpo_index.get(*pred).is_some_and(|&i| i < rpo_index[&block]))
+                .count();
+            if forward >= 2 {
+                merge_nodes.insert(block);
+            }
+            for &succ in cfg[&block].iter() {
+                if rpo_index[&succ] <= rpo_index[&block] {
+                    if !doms.dominates(succ, block) {
+                        return Err(format!(
+                            "@{} has irreducible control flow at .{}",
+                            func.name, succ
+                        )
+                        .into());
+                    }
+                    loop_headers.insert(succ);
+                }
+            }
+        }
+
+        Ok(FunctionWriter {
+            func,
+            returns,
+            out,
+            doms,
+            rpo_index,
+            merge_nodes,
+            loop_headers,
+            types: defined_types(func)?,
+            depth: 2,
+        })
+    }
+
+    fn line(&mut self, text: &str) -> fmt::Result {
+        writeln!(self.out, "{}{}", "  ".repeat(self.depth), text)
+    }
+
+    fn write(&mut self) -> fmt::Result {
+        let func = self.func;
+        let mut header = format!("  (func {} (export \"{}\")", symbol(&func.name), func.name);
+        for (arg, ty) in func.args.iter() {
+            write!(header, " (param {} {})", variable(arg), wasm_type(ty))?;
+        }
+        if let Some(ty) = &func.ret_type {
+            write!(header, " (result {})", wasm_type(ty))?;
+        }
+        writeln!(self.out, "{}", header)?;
+        let mut locals: Vec<(&VarId, &Type)> = self
+            .types
+            .iter()
+            .filter(|(var, _)| !func.args.iter().any(|(arg, _)| arg == *var))
+            .collect();
+        locals.sort_by(|a, b| a.0.cmp_by_name(b.0));
+        for (var, ty) in locals {
+            writeln!(self.out, "    (local {} {})", variable(var), wasm_type(ty))?;
+        }
+        if let Some(&entry) = self.doms.reverse_postorder().first() {
+            self.do_tree(entry)?;
+        }
+        // Every path has already returned, but a function with a result still needs a value on
+        // the stack when the body ends
+        if func.ret_type.is_some() {
+            self.line("unreachable")?;
+        }
+        writeln!(self.out, "  )")
+    }
+
+    fn do_tree(&mut self, block: LabelId) -> fmt::Result {
+        let mut merges: Vec<LabelId> = self
+            .doms
+            .children(block)
+            .iter()
+            .filter(|child| self.merge_nodes.contains(*child))
+            .copied()
+            .collect();
+        merges.sort_by_key(|child| self.rpo_index[child]);
+        if self.loop_headers.contains(&block) {
+            self.line(&format!("loop $loop.{}", mangle(&block.name())))?;
+            self.depth += 1;
+            self.node_within(block, &merges)?;
+            self.depth -= 1;
+            self.line("end")
+        } else {
+            self.node_within(block, &merges)
+        }
+    }
+
+    /// Code for `block` nested in one `block` per merge node in `merges`, the earliest merge node
+    /// innermost so that it directly follows `block`'s own code.
+    fn node_within(&mut self, block: LabelId, merges: &[LabelId]) -> fmt::Result {
+        match merges.split_last() {
+            Some((last, rest)) => {
+                self.line(&format!("block $block.{}", mangle(&last.name())))?;
+                self.depth += 1;
+                self.node_within(block, rest)?;
+                self.depth -= 1;
+                self.line("end")?;
+                self.do_tree(*last)
+            }
+            None => {
+                let func = self.func;
+                let index = func.blocks.iter().position(|b| b.name == block).unwrap();
+                let instructions = &func.blocks[index].instructions;
+                for instr in instructions.iter() {
+                    self.write_instruction(block, instr)?;
+                }
+                if !instructions
+                    .last()
+                    .is_some_and(|instr| instr.is_terminator())
+                {
+                    match func.blocks.get(index + 1) {
+                        Some(next) => self.do_branch(block, next.name)?,
+                        None if func.ret_type.is_some() => self.line("unreachable")?,
+                        None => self.line("return")?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn do_branch(&mut self, from: LabelId, to: LabelId) -> fmt::Result {
+        if self.rpo_index[&to] <= self.rpo_index[&from] {
+            self.line(&format!("br $loop.{}", mangle(&to.name())))
+        } else if self.merge_nodes.contains(&to) {
+            self.line(&format!("br $block.{}", mangle(&to.name())))
+        } else {
+            self.do_tree(to)
+        }
+    }
+
+    fn write_instruction(&mut self, block: LabelId, instr: &Instruction) -> fmt::Result {
+        if instr.op != OpCode::Misc(MiscOp::Print) {
+            for arg in instr.uses().to_vec() {
+                self.line(&format!("local.get {}", variable(&arg)))?;
+            }
+        }
+        let produces_value = match &instr.op {
+            OpCode::Const => {
+                match instr.value.as_ref().unwrap() {
+                    Value::Int(n) => self.line(&format!("i64.const {}", n))?,
+                    Value::Bool(b) => self.line(&format!("i32.const {}", *b as i32))?,
+                }
+                true
+            }
+            OpCode::Arithmetic(ArithmeticOp::Div) => {
+                self.line("call $rt.div")?;
+                true
+            }
+            OpCode::Arithmetic(aop) => {
+                let op = match aop {
+                    ArithmeticOp::Add => "i64.add",
+                    ArithmeticOp::Sub => "i64.sub",
+                    _ => "i64.mul",
+                };
+                self.line(op)?;
+                true
+            }
+            OpCode::Comparison(cop) => {
+                let op = match cop {
+                    ComparisonOp::Eq => "i64.eq",
+                    ComparisonOp::Lt => "i64.lt_s",
+                    ComparisonOp::Gt => "i64.gt_s",
+                    ComparisonOp::Le => "i64.le_s",
+                    ComparisonOp::Ge => "i64.ge_s",
+                };
+                self.line(op)?;
+                true
+            }
+            OpCode::Logic(lop) => {
+                let op = match lop {
+                    LogicOp::Not => "i32.eqz",
+                    LogicOp::And => "i32.and",
+                    LogicOp::Or => "i32.or",
+                };
+                self.line(op)?;
+                true
+            }
+            OpCode::Misc(MiscOp::Id) => true,
+            OpCode::Misc(MiscOp::Print) => {
+                for (i, arg) in instr.uses().iter().enumerate() {
+                    if i > 0 {
+                        self.line("call $print.space")?;
+                    }
+                    self.line(&format!("local.get {}", variable(arg)))?;
+                    let helper = match self.types[arg] {
+                        Type::Int => "call $print.int",
+                        Type::Bool => "call $print.bool",
+                    };
+                    self.line(helper)?;
+                }
+                self.line("call $print.newline")?;
+                false
+            }
+            OpCode::Misc(MiscOp::Nop) => false,
+            OpCode::Control(ControlOp::Call) => {
+                let callee = instr.funcs.as_ref().unwrap()[0];
+                self.line(&format!("call {}", symbol(&callee)))?;
+                let returns_value = self.returns.get(&callee).is_some_and(|ty| ty.is_some());
+                if returns_value && instr.dst.is_none() {
+                    self.line("drop")?;
+                }
+                returns_value
+            }
+            OpCode::Control(ControlOp::Jmp) => {
+                let target = instr.labels.as_ref().unwrap()[0];
+                self.do_branch(block, target)?;
+                false
+            }
+            OpCode::Control(ControlOp::Br) => {
+                let labels = instr.labels.as_ref().unwrap().clone();
+                // Both arms would hold the same code
+                if labels[0] == labels[1] {
+                    self.line("drop")?;
+                    self.do_branch(block, labels[0])?;
+                    return Ok(());
+                }
+                self.line("if")?;
+                self.depth += 1;
+                self.do_branch(block, labels[0])?;
+                self.depth -= 1;
+                self.line("else")?;
+                self.depth += 1;
+                self.do_branch(block, labels[1])?;
+                self.depth -= 1;
+                self.line("end")?;
+                false
+            }
+            OpCode::Control(ControlOp::Ret) => {
+                self.line("return")?;
+                false
+            }
+            OpCode::Phi | OpCode::Speculation(_) => {
+                unreachable!("phis and speculation are removed before lowering")
+            }
+        };
+        if let (Some(dst), true) = (&instr.dst, produces_value) {
+            self.line(&format!("local.set {}", variable(dst)))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use crate::codegen::{defined_types, lowered, mangle};
+use crate::core::{
+    ArithmeticOp, ComparisonOp, ControlOp, FuncId, Function, Instruction, LabelId, LogicOp, MiscOp,
+    OpCode, Program, Type, Value, VarId,
+};
+
+/// Integer argument registers of the System V AMD64 calling convention, in order.
+const ARG_REGISTERS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+/// Helpers called by `print` and `div`, written against the C library so the output is buffered
+/// along with anything else the program writes, and flushed by `exit` when a division fails.
+const RUNTIME: &str = r#"    .section .rodata
+rt.int_format:
+    .string "%lld"
+rt.true:
+    .string "true"
+rt.false:
+    .string "false"
+rt.arity_error:
+    .string "error: wrong number of arguments to main\n"
+rt.div_error:
+    .string "error: division by zero\n"
+
+    .text
+rt.print_int:
+    pushq %rbp
+    movq %rsp, %rbp
+    movq %rdi, %rsi
+    leaq rt.int_format(%rip), %rdi
+    xorl %eax, %eax
+    call printf@PLT
+    leave
+    ret
+
+rt.print_bool:
+    pushq %rbp
+    movq %rsp, %rbp
+    testq $1, %rdi
+    leaq rt.true(%rip), %rax
+    leaq rt.false(%rip), %rdi
+    cmovneq %rax, %rdi
+    xorl %eax, %eax
+    call printf@PLT
+    leave
+    ret
+
+rt.print_char:
+    pushq %rbp
+    movq %rsp, %rbp
+    call putchar@PLT
+    leave
+    ret
+
+rt.div:
+    pushq %rbp
+    movq %rsp, %rbp
+    testq %rsi, %rsi
+    je rt.div_by_zero
+    movq %rdi, %rax
+    cmpq $-1, %rsi
+    je rt.div_negate
+    cqto
+    idivq %rsi
+    leave
+    ret
+rt.div_negate:
+    negq %rax
+    leave
+    ret
+rt.div_by_zero:
+    leaq rt.div_error(%rip), %rdi
+    movq stderr@GOTPCREL(%rip), %rax
+    movq (%rax), %rsi
+    call fputs@PLT
+    movl $2, %edi
+    call exit@PLT
+"#;
+
+/// Lowers a program to AT&T syntax x86-64 assembly for the System V ABI. Every variable lives in
+/// its own stack slot and each instruction computes its result in `%rax`.
+/// Bril functions become `bril.<name>` symbols, and if the program has a `main` function a C
+/// `main` is emitted that parses its arguments from the command line, so the output can be
+/// linked with `cc` directly. Functions reading a variable they never define are rejected.
+pub fn emit_x86(prog: &Program) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    writeln!(out, "{}", RUNTIME)?;
+    for func in prog.functions.iter() {
+        let func = lowered(func);
+        let frame = Frame::new(&func)?;
+        write_function(&mut out, &func, &frame)?;
+    }
+    if let Some(main) = prog.functions.iter().find(|func| func.name == "main") {
+        write_entry_point(&mut out, main)?;
+    }
+    writeln!(out, "    .section .note.GNU-stack,\"\",@progbits")?;
+    Ok(out)
+}
+
+fn symbol(func: &FuncId) -> String {
+    format!("bril.{}", mangle(&func.name()))
+}
+
+fn label(func: &Function, block: &LabelId) -> String {
+    format!(".L{}.{}", mangle(&func.name.name()), mangle(&block.name()))
+}
+
+/// Stack space for `slots` 8-byte slots, keeping `%rsp` 16-byte aligned.
+fn frame_size(slots: usize) -> usize {
+    (slots * 8).div_ceil(16) * 16
+}
+
+/// Emits a call to `target` with the values at the `args` memory operands.
+fn write_call(out: &mut String, target: &str, args: &[String]) -> fmt::Result {
+    let stack_args = args.len().saturating_sub(ARG_REGISTERS.len());
+    let padding = stack_args % 2 * 8;
+    if padding != 0 {
+        writeln!(out, "    subq ${}, %rsp", padding)?;
+    }
+    for arg in args.iter().skip(ARG_REGISTERS.len()).rev() {
+        writeln!(out, "    pushq {}", arg)?;
+    }
+    for (arg, reg) in args.iter().zip(ARG_REGISTERS.iter()) {
+        writeln!(out, "    movq {}, {}", arg, reg)?;
+    }
+    writeln!(out, "    call {}", target)?;
+    if stack_args != 0 {
+        writeln!(out, "    addq ${}, %rsp", stack_args * 8 + padding)?;
+    }
+    Ok(())
+}
+
+struct Frame {
+    slots: HashMap<VarId, String>,
+    types: HashMap<VarId, Type>,
+}
+
+impl Frame {
+    fn new(func: &Function) -> Result<Frame, Box<dyn Error>> {
+        let mut slots: HashMap<VarId, String> = HashMap::new();
+        let vars = func.args.iter().map(|&(arg, _)| arg).chain(
+            func.blocks
+                .iter()
+                .flat_map(|block| block.instructions.iter())
+                .filter_map(|instr| instr.dst),
+        );
+        for var in vars {
+            if !slots.contains_key(&var) {
+                let offset = (slots.len() + 1) * 8;
+                slots.insert(var, format!("-{}(%rbp)", offset));
+            }
+        }
+        Ok(Frame {
+            slots,
+            types: defined_types(func)?,
+        })
+    }
+
+    fn slot(&self, var: VarId) -> &str {
+        &self.slots[&var]
+    }
+}
+
+fn write_function(out: &mut String, func: &Function, frame: &Frame) -> fmt::Result {
+    let name = symbol(&func.name);
+    writeln!(out, "    .text")?;
+    writeln!(out, "{}:", name)?;
+    writeln!(out, "    pushq %rbp")?;
+    writeln!(out, "    movq %rsp, %rbp")?;
+    writeln!(out, "    subq ${}, %rsp", frame_size(frame.slots.len()))?;
+    for (i, (arg, _)) in func.args.iter().enumerate() {
+        match ARG_REGISTERS.get(i) {
+            Some(reg) => writeln!(out, "    movq {}, {}", reg, frame.slot(*arg))?,
+            None => {
+                let offset = 16 + (i - ARG_REGISTERS.len()) * 8;
+                writeln!(out, "    movq {}(%rbp), %rax", offset)?;
+                writeln!(out, "    movq %rax, {}", frame.slot(*arg))?;
+            }
+        }
+    }
+    for (i, block) in func.blocks.iter().enumerate() {
+        writeln!(out, "{}:", label(func, &block.name))?;
+        let next = func.blocks.get(i + 1).map(|next| next.name);
+        for instr in block.instructions.iter() {
+            write_instruction(out, func, frame, instr, next)?;
+        }
+    }
+    // Falling off the end of the function returns
+    writeln!(out, "    leave")?;
+    writeln!(out, "    ret")?;
+    writeln!(out)
+}
+
+fn write_instruction(
+    out: &mut String,
+    func: &Function,
+    frame: &Frame,
+    instr: &Instruction,
+    next: Option<LabelId>,
+) -> fmt::Result {
+    let args: Vec<&str> = instr.uses().iter().map(|&arg| frame.slot(arg)).collect();
+    let dst = instr.dst.map(|dst| frame.slot(dst));
+    match &instr.op {
+        OpCode::Const => {
+            let value = match instr.value.as_ref().unwrap() {
+                Value::Int(n) => *n as i64,
+                Value::Bool(b) => *b as i64,
+            };
+            writeln!(out, "    movabsq ${}, %rax", value)?;
+        }
+        OpCode::Arithmetic(ArithmeticOp::Div) => {
+            // `idivq` traps on zero and on the one quotient that overflows
+            writeln!(out, "    movq {}, %rdi", args[0])?;
+            writeln!(out, "    movq {}, %rsi", args[1])?;
+            writeln!(out, "    call rt.div")?;
+        }
+        OpCode::Arithmetic(aop) => {
+            let mnemonic = match aop {
+                ArithmeticOp::Add => "addq",
+                ArithmeticOp::Sub => "subq",
+                ArithmeticOp::Mul => "imulq",
+                ArithmeticOp::Div => unreachable!(),
+            };
+            writeln!(out, "    movq {}, %rax", args[0])?;
+            writeln!(out, "    {} {}, %rax", mnemonic, args[1])?;
+        }
+        OpCode::Comparison(cop) => {
+            let condition = match cop {
+                ComparisonOp::Eq => "e",
+                ComparisonOp::Lt => "l",
+                ComparisonOp::Gt => "g",
+                ComparisonOp::Le => "le",
+                ComparisonOp::Ge => "ge",
+            };
+            writeln!(out, "    movq {}, %rax", args[0])?;
+            writeln!(out, "    cmpq {}, %rax", args[1])?;
+            writeln!(out, "    set{} %al", condition)?;
+            writeln!(out, "    movzbq %al, %rax")?;
+        }
+        OpCode::Logic(LogicOp::Not) => {
+            writeln!(out, "    movq {}, %rax", args[0])?;
+            writeln!(out, "    xorq $1, %rax")?;
+        }
+        OpCode::Logic(lop) => {
+            let mnemonic = if *lop == LogicOp::And { "andq" } else { "orq" };
+            writeln!(out, "    movq {}, %rax", args[0])?;
+            writeln!(out, "    {} {}, %rax", mnemonic, args[1])?;
+        }
+        OpCode::Misc(MiscOp::Id) => {
+            writeln!(out, "    movq {}, %rax", args[0])?;
+        }
+        OpCode::Misc(MiscOp::Print) => {
+            for (i, arg) in instr.uses().iter().enumerate() {
+                if i > 0 {
+                    writeln!(out, "    movl $32, %edi")?;
+                    writeln!(out, "    call rt.print_char")?;
+                }
+                let helper = match frame.types[arg] {
+                    Type::Int => "rt.print_int",
+                    Type::Bool => "rt.print_bool",
+                };
+                writeln!(out, "    movq {}, %rdi", frame.slot(*arg))?;
+                writeln!(out, "    call {}", helper)?;
+            }
+            writeln!(out, "    movl $10, %edi")?;
+            writeln!(out, "    call rt.print_char")?;
+        }
+        OpCode::Misc(MiscOp::Nop) => {}
+        OpCode::Control(ControlOp::Jmp) => {
+            let target = &instr.labels.as_ref().unwrap()[0];
+            if next != Some(*target) {
+                writeln!(out, "    jmp {}", label(func, target))?;
+            }
+        }
+        OpCode::Control(ControlOp::Br) => {
+            // Whichever successor is laid out next is reached by falling through
+            let labels = instr.labels.as_ref().unwrap();
+            writeln!(out, "    testq $1, {}", args[0])?;
+            if next == Some(labels[0]) {
+                writeln!(out, "    jz {}", label(func, &labels[1]))?;
+            } else {
+                writeln!(out, "    jnz {}", label(func, &labels[0]))?;
+                if next != Some(labels[1]) {
+                    writeln!(out, "    jmp {}", label(func, &labels[1]))?;
+                }
+            }
+        }
+        OpCode::Control(ControlOp::Call) => {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            write_call(out, &symbol(&instr.funcs.as_ref().unwrap()[0]), &args)?;
+        }
+        OpCode::Control(ControlOp::Ret) => {
+            if let Some(arg) = args.first() {
+                writeln!(out, "    movq {}, %rax", arg)?;
+            }
+            writeln!(out, "    leave")?;
+            writeln!(out, "    ret")?;
+        }
+        OpCode::Phi | OpCode::Speculation(_) => {
+            unreachable!("phis and speculation are removed before lowering")
+        }
+    }
+    // Every value-producing instruction leaves its result in %rax
+    if let Some(dst) = dst {
+        writeln!(out, "    movq %rax, {}", dst)?;
+    }
+    Ok(())
+}
+
+/// A C `main` that converts `argv` to Bril values, calls `bril.main` and exits with status 0.
+/// Slot 1 holds `argv` and the following slots the converted arguments.
+fn write_entry_point(out: &mut String, main: &Function) -> fmt::Result {
+    let argv = "-8(%rbp)";
+    let slots: Vec<String> = (0..main.args.len())
+        .map(|i| format!("-{}(%rbp)", (i + 2) * 8))
+        .collect();
+    writeln!(out, "    .text")?;
+    writeln!(out, "    .globl main")?;
+    writeln!(out, "main:")?;
+    writeln!(out, "    pushq %rbp")?;
+    writeln!(out, "    movq %rsp, %rbp")?;
+    writeln!(out, "    subq ${}, %rsp", frame_size(slots.len() + 1))?;
+    writeln!(out, "    movq %rsi, {}", argv)?;
+    writeln!(out, "    cmpl ${}, %edi", main.args.len() + 1)?;
+    writeln!(out, "    je .L.main_args")?;
+    writeln!(out, "    movq stderr@GOTPCREL(%rip), %rax")?;
+    writeln!(out, "    movq (%rax), %rsi")?;
+    writeln!(out, "    leaq rt.arity_error(%rip), %rdi")?;
+    writeln!(out, "    call fputs@PLT")?;
+    writeln!(out, "    movl $1, %eax")?;
+    writeln!(out, "    leave")?;
+    writeln!(out, "    ret")?;
+    writeln!(out, ".L.main_args:")?;
+    for (i, ((_, ty), slot)) in main.args.iter().zip(slots.iter()).enumerate() {
+        writeln!(out, "    movq {}, %rax", argv)?;
+        writeln!(out, "    movq {}(%rax), %rdi", (i + 1) * 8)?;
+        match ty {
+            Type::Int => {
+                writeln!(out, "    call atoll@PLT")?;
+            }
+            Type::Bool => {
+                writeln!(out, "    leaq rt.true(%rip), %rsi")?;
+                writeln!(out, "    call strcmp@PLT")?;
+                writeln!(out, "    testl %eax, %eax")?;
+                writeln!(out, "    sete %al")?;
+                writeln!(out, "    movzbq %al, %rax")?;
+            }
+        }
+        writeln!(out, "    movq %rax, {}", slot)?;
+    }
+    write_call(out, &symbol(&main.name), &slots)?;
+    writeln!(out, "    xorl %eax, %eax")?;
+    writeln!(out, "    leave")?;
+    writeln!(out, "    ret")?;
+    writeln!(out)
+}
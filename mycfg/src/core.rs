@@ -1,12 +1,27 @@
 mod display;
+mod operands;
+mod serialize;
+mod symbol;
 
-#[derive(Clone, Debug, PartialEq)]
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+
+use json::JsonValue;
+
+pub use crate::core::operands::Operands;
+pub use crate::core::symbol::{FuncId, LabelId, VarId};
+
+/// Fields of a Bril JSON instruction or function that `mycfg` doesn't know about, such as
+/// attributes added by other tools, kept so they can be written out again.
+pub type Extensions = BTreeMap<String, JsonValue>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Type {
     Int,
     Bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Value {
     Int(isize),
     Bool(bool),
@@ -51,41 +66,200 @@ pub enum MiscOp {
     Nop,
 }
 
+/// Bril's speculative execution extension. `speculate` saves every variable, `commit` discards
+/// the saved copy and `guard cond .l` restores it and jumps to `.l` when `cond` is false.
+#[derive(Clone, PartialEq, Debug, Hash, Eq)]
+pub enum SpeculationOp {
+    Speculate,
+    Commit,
+    Guard,
+}
+
 #[derive(Clone, PartialEq, Debug, Hash, Eq)]
 pub enum OpCode {
     Const,
+    Phi,
     Arithmetic(ArithmeticOp),
     Comparison(ComparisonOp),
     Logic(LogicOp),
     Control(ControlOp),
     Misc(MiscOp),
+    Speculation(SpeculationOp),
+}
+
+/// Where an instruction or label starts in the Bril source, as `bril2json -p` records it. Rows
+/// and columns count from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub row: usize,
+    pub col: usize,
+}
+
+/// ` at row:col` to put after what an error message is about, or nothing without a position.
+pub(crate) fn at(pos: Option<Position>) -> String {
+    match pos {
+        Some(pos) => format!(" at {}", pos),
+        None => String::new(),
+    }
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Instruction {
     pub op: OpCode,
-    pub dst: Option<String>,
+    pub dst: Option<VarId>,
     pub dst_type: Option<Type>,
-    pub args: Option<Vec<String>>,
-    pub funcs: Option<Vec<String>>,
-    pub labels: Option<Vec<String>>,
+    pub args: Option<Operands<VarId>>,
+    pub funcs: Option<Operands<FuncId>>,
+    pub labels: Option<Operands<LabelId>>,
     pub value: Option<Value>,
+    pub pos: Option<Position>,
+    /// Kept out of line and only allocated for instructions that have any, which few do.
+    pub extensions: Option<Box<Extensions>>,
 }
 
 #[derive(Clone, PartialEq)]
 pub struct BasicBlock {
-    pub name: String,
+    pub name: LabelId,
     pub instructions: Vec<Instruction>,
+    /// Position of the block's label, if it has one in the source.
+    pub pos: Option<Position>,
+    /// Whether the name was made up, by the parser for a block without a label or by a pass,
+    /// rather than written by the user. Made up labels are only written out when something
+    /// refers to them.
+    pub synthetic: bool,
 }
 
 #[derive(PartialEq, Clone)]
 pub struct Function {
-    pub name: String,
-    pub args: Vec<(String, Type)>,
+    pub name: FuncId,
+    pub args: Vec<(VarId, Type)>,
     pub ret_type: Option<Type>,
     pub blocks: Vec<BasicBlock>,
+    pub extensions: Extensions,
 }
 
+#[derive(Clone)]
 pub struct Program {
     pub functions: Vec<Function>,
 }
+
+impl Instruction {
+    /// A value operation such as `dst: dst_type = op args...`.
+    pub fn compute(op: OpCode, dst: VarId, dst_type: Type, args: Vec<VarId>) -> Instruction {
+        Instruction {
+            op,
+            dst: Some(dst),
+            dst_type: Some(dst_type),
+            args: Some(args.into()),
+            funcs: None,
+            labels: None,
+            value: None,
+            pos: None,
+            extensions: None,
+        }
+    }
+
+    /// `dst: dst_type = const value`.
+    pub fn constant(dst: VarId, dst_type: Type, value: Value) -> Instruction {
+        Instruction {
+            op: OpCode::Const,
+            dst: Some(dst),
+            dst_type: Some(dst_type),
+            args: None,
+            funcs: None,
+            labels: None,
+            value: Some(value),
+            pos: None,
+            extensions: None,
+        }
+    }
+
+    pub fn jmp(label: LabelId) -> Instruction {
+        Instruction {
+            op: OpCode::Control(ControlOp::Jmp),
+            dst: None,
+            dst_type: None,
+            args: None,
+            funcs: None,
+            labels: Some([label].into()),
+            value: None,
+            pos: None,
+            extensions: None,
+        }
+    }
+
+    /// Variables read by the instruction.
+    pub fn uses(&self) -> &[VarId] {
+        self.args.as_deref().unwrap_or(&[])
+    }
+
+    /// Whether the result depends only on the arguments and evaluating it has no side effects
+    /// other than possibly trapping (see `may_trap`).
+    pub fn is_pure(&self) -> bool {
+        self.dst.is_some()
+            && matches!(
+                self.op,
+                OpCode::Const
+                    | OpCode::Arithmetic(_)
+                    | OpCode::Comparison(_)
+                    | OpCode::Logic(_)
+                    | OpCode::Misc(MiscOp::Id)
+            )
+    }
+
+    pub fn may_trap(&self) -> bool {
+        self.op == OpCode::Arithmetic(ArithmeticOp::Div)
+    }
+
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self.op,
+            OpCode::Control(ControlOp::Jmp)
+                | OpCode::Control(ControlOp::Br)
+                | OpCode::Control(ControlOp::Ret)
+        )
+    }
+}
+
+impl Function {
+    /// Returns `base` if no block uses it as a name, otherwise `base` with the smallest numeric
+    /// suffix that makes it unique.
+    pub fn fresh_block_name(&self, base: &str) -> LabelId {
+        fresh_name(
+            base,
+            &mut self.blocks.iter().map(|block| block.name).collect(),
+        )
+    }
+
+    /// Every argument, destination and operand of the function.
+    pub fn variables(&self) -> HashSet<VarId> {
+        let mut vars: HashSet<VarId> = self.args.iter().map(|&(arg, _)| arg).collect();
+        for block in self.blocks.iter() {
+            for instr in block.instructions.iter() {
+                vars.extend(instr.dst.iter().chain(instr.uses()));
+            }
+        }
+        vars
+    }
+
+    /// Returns a variable name based on `base` that is not an argument, destination or operand
+    /// anywhere in the function.
+    pub fn fresh_var_name(&self, base: &str) -> VarId {
+        fresh_name(base, &mut self.variables())
+    }
+}
+
+/// `base`, or `base` with the smallest numeric suffix that isn't in `taken`, which it's added to.
+pub(crate) fn fresh_name<T>(base: &str, taken: &mut HashSet<T>) -> T
+where
+    T: for<'a> From<&'a str> + Copy + Eq + Hash,
+{
+    let mut name = T::from(base);
+    let mut i = 1;
+    while taken.contains(&name) {
+        name = T::from(format!("{}.{}", base, i).as_str());
+        i += 1;
+    }
+    taken.insert(name);
+    name
+}
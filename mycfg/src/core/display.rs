@@ -5,24 +5,40 @@ use std::fmt::Write;
 use crate::core::OpCode::*;
 use crate::core::Value::*;
 use crate::core::{
-    BasicBlock, ControlOp, Function, Instruction, LogicOp, MiscOp, Program, Type, Value,
+    BasicBlock, ControlOp, Function, Instruction, LabelId, LogicOp, MiscOp, Position, Program,
+    SpeculationOp, Type, Value, VarId,
 };
 use crate::parser::control_flow_graph;
+use crate::profile::Profile;
+
+/// `vars` separated by spaces.
+fn join(vars: &[VarId]) -> String {
+    let vars: Vec<String> = vars.iter().map(VarId::to_string).collect();
+    vars.join(" ")
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.row, self.col)
+    }
+}
 
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for (i, function) in self.functions.iter().enumerate() {
             write!(f, "{}", function)?;
             if i != self.functions.len() - 1 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
         }
         Ok(())
     }
 }
 
-impl fmt::Display for Function {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Function {
+    /// `@name(a: Int,b: Bool): void`, the first line of the function's `Display` without its
+    /// opening brace.
+    pub fn signature(&self) -> String {
         let mut ret_str = "void";
         if let Some(ret_type) = &self.ret_type {
             match ret_type {
@@ -30,27 +46,34 @@ impl fmt::Display for Function {
                 Type::Bool => ret_str = "Bool",
             }
         }
-        write!(f, "@{}(", self.name)?;
+        let mut signature = format!("@{}(", self.name);
         for (i, (arg_name, arg_type)) in self.args.iter().enumerate() {
-            write!(f, "{}: {:?}", arg_name, arg_type)?;
+            write!(signature, "{}: {:?}", arg_name, arg_type).unwrap();
             if i != self.args.len() - 1 {
-                write!(f, ",")?;
+                signature.push(',');
             }
         }
-        write!(f, "): {} {{\n", ret_str)?;
+        write!(signature, "): {}", ret_str).unwrap();
+        signature
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {{", self.signature())?;
         for block in self.blocks.iter() {
             write!(f, "{}", block)?;
         }
-        write!(f, "}}\n")?;
+        writeln!(f, "}}")?;
         Ok(())
     }
 }
 
 impl fmt::Display for BasicBlock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, ".{}:\n", self.name)?;
+        writeln!(f, ".{}:", self.name)?;
         for instr in self.instructions.iter() {
-            write!(f, "    {}\n", instr)?;
+            writeln!(f, "    {}", instr)?;
         }
         Ok(())
     }
@@ -69,6 +92,20 @@ impl fmt::Display for Instruction {
                     self.value.as_ref().unwrap()
                 )?;
             }
+            Phi => {
+                write!(
+                    f,
+                    "{}: {:?} = {:?}",
+                    self.dst.as_ref().unwrap(),
+                    self.dst_type.as_ref().unwrap(),
+                    Phi
+                )?;
+                let labels = self.labels.as_ref().unwrap();
+                for (arg, label) in self.args.as_ref().unwrap().iter().zip(labels.iter()) {
+                    write!(f, " {} .{}", arg, label)?;
+                }
+                write!(f, ";")?;
+            }
             Arithmetic(aop) => {
                 write!(
                     f,
@@ -142,12 +179,12 @@ impl fmt::Display for Instruction {
                         "{:?} @{} {};",
                         ControlOp::Call,
                         self.funcs.as_ref().unwrap()[0],
-                        self.args.as_ref().unwrap().join(" ")
+                        join(self.uses())
                     )?;
                 }
                 ControlOp::Ret => {
-                    if let Some(args) = &self.args {
-                        write!(f, "ret {};", args[0])?;
+                    if let Some(arg) = self.uses().first() {
+                        write!(f, "ret {};", arg)?;
                     } else {
                         write!(f, "ret;")?;
                     }
@@ -155,20 +192,36 @@ impl fmt::Display for Instruction {
             },
             Misc(mop) => match &mop {
                 MiscOp::Id => {
-                    write!(f, "{:?} {};", MiscOp::Id, self.args.as_ref().unwrap()[0])?;
-                }
-                MiscOp::Print => {
                     write!(
                         f,
-                        "{:?} {};",
-                        MiscOp::Print,
-                        self.args.as_ref().unwrap().join(" ")
+                        "{}: {:?} = {:?} {};",
+                        self.dst.as_ref().unwrap(),
+                        self.dst_type.as_ref().unwrap(),
+                        MiscOp::Id,
+                        self.args.as_ref().unwrap()[0]
                     )?;
                 }
+                MiscOp::Print => {
+                    write!(f, "{:?} {};", MiscOp::Print, join(self.uses()))?;
+                }
                 MiscOp::Nop => {
                     write!(f, "{:?};", MiscOp::Nop)?;
                 }
             },
+            Speculation(sop) => match sop {
+                SpeculationOp::Guard => {
+                    write!(
+                        f,
+                        "{:?} {} .{};",
+                        SpeculationOp::Guard,
+                        self.args.as_ref().unwrap()[0],
+                        self.labels.as_ref().unwrap()[0]
+                    )?;
+                }
+                _ => {
+                    write!(f, "{:?};", sop)?;
+                }
+            },
         }
         Ok(())
     }
@@ -194,26 +247,60 @@ impl fmt::Display for Value {
 
 impl Program {
     pub fn graphviz(&self) -> Result<String, Box<dyn Error>> {
+        self.write_graphviz(None)
+    }
+
+    /// Like `graphviz`, shading each block by how often it ran relative to the hottest block and
+    /// labelling each edge with the number of times it was taken.
+    pub fn graphviz_with_profile(&self, profile: &Profile) -> Result<String, Box<dyn Error>> {
+        self.write_graphviz(Some(profile))
+    }
+
+    fn write_graphviz(&self, profile: Option<&Profile>) -> Result<String, Box<dyn Error>> {
         let mut s = String::new();
-        for func in self.functions.iter() {
-            write!(s, "digraph {} {{\n", func.name)?;
+        if let Some(func) = self.functions.first() {
+            writeln!(s, "digraph {} {{", func.name)?;
             let cfg = control_flow_graph(func);
 
             // Sort to make output deterministic
-            let mut sorted_keys: Vec<&String> = cfg.keys().collect();
-            sorted_keys.sort();
+            let mut sorted_keys: Vec<&LabelId> = cfg.keys().collect();
+            sorted_keys.sort_by(|a, b| a.cmp_by_name(b));
 
+            let hottest = sorted_keys
+                .iter()
+                .map(|key| profile.map_or(0, |p| p.block(&func.name.name(), &key.name())))
+                .max()
+                .unwrap_or(0);
             for &key in &sorted_keys {
-                write!(s, "  {};\n", key)?;
+                match profile {
+                    Some(profile) => {
+                        let count = profile.block(&func.name.name(), &key.name());
+                        let heat = if hottest == 0 {
+                            0.0
+                        } else {
+                            count as f64 / hottest as f64
+                        };
+                        writeln!(
+                            s,
+                            "  {key} [label=\"{key}\\n{count}\" style=filled fillcolor=\"0.0 {heat:.3} 1.0\"];"
+                        )?;
+                    }
+                    None => writeln!(s, "  {};", key)?,
+                }
             }
             for &key in &sorted_keys {
                 for succ in cfg[key].iter() {
-                    write!(s, "  {key} -> {succ};\n")?;
+                    match profile {
+                        Some(profile) => {
+                            let count = profile.edge(&func.name.name(), &key.name(), &succ.name());
+                            writeln!(s, "  {key} -> {succ} [label=\"{count}\"];")?
+                        }
+                        None => writeln!(s, "  {key} -> {succ};")?,
+                    }
                 }
             }
             write!(s, "}}")?;
-            break;
         }
-        return Ok(s);
+        Ok(s)
     }
 }
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// The arguments, functions or labels of an instruction. Up to three are kept inline, which is
+/// every operation but long calls, prints and phis, so most instructions are cloned without
+/// touching the heap.
+#[derive(Clone, Default)]
+pub enum Operands<T: Copy> {
+    #[default]
+    Empty,
+    One([T; 1]),
+    Two([T; 2]),
+    Three([T; 3]),
+    Many(Box<[T]>),
+}
+
+impl<T: Copy> Operands<T> {
+    pub fn new() -> Operands<T> {
+        Operands::Empty
+    }
+
+    pub fn push(&mut self, item: T) {
+        *self = match *self {
+            Operands::Empty => Operands::One([item]),
+            Operands::One([a]) => Operands::Two([a, item]),
+            Operands::Two([a, b]) => Operands::Three([a, b, item]),
+            _ => self.iter().copied().chain([item]).collect(),
+        };
+    }
+}
+
+impl<T: Copy> Deref for Operands<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Operands::Empty => &[],
+            Operands::One(items) => items,
+            Operands::Two(items) => items,
+            Operands::Three(items) => items,
+            Operands::Many(items) => items,
+        }
+    }
+}
+
+impl<T: Copy> DerefMut for Operands<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Operands::Empty => &mut [],
+            Operands::One(items) => items,
+            Operands::Two(items) => items,
+            Operands::Three(items) => items,
+            Operands::Many(items) => items,
+        }
+    }
+}
+
+impl<T: Copy> FromIterator<T> for Operands<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Operands<T> {
+        let mut iter = iter.into_iter();
+        let (a, b, c) = match (iter.next(), iter.next(), iter.next()) {
+            (None, _, _) => return Operands::Empty,
+            (Some(a), None, _) => return Operands::One([a]),
+            (Some(a), Some(b), None) => return Operands::Two([a, b]),
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+        };
+        match iter.next() {
+            None => Operands::Three([a, b, c]),
+            Some(d) => Operands::Many([a, b, c, d].into_iter().chain(iter).collect()),
+        }
+    }
+}
+
+impl<T: Copy> From<Vec<T>> for Operands<T> {
+    fn from(items: Vec<T>) -> Operands<T> {
+        match items.len() {
+            0..=3 => items.into_iter().collect(),
+            _ => Operands::Many(items.into_boxed_slice()),
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> From<[T; N]> for Operands<T> {
+    fn from(items: [T; N]) -> Operands<T> {
+        items.into_iter().collect()
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for &'a Operands<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> std::slice::Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq for Operands<T> {
+    fn eq(&self, other: &Operands<T>) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Copy + Eq> Eq for Operands<T> {}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Operands<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
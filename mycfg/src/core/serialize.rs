@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use json::number::Number;
+use json::{array, object, JsonValue};
+
+use crate::core::{
+    ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, Extensions, Function, Instruction, LabelId,
+    LogicOp, MiscOp, OpCode, Position, Program, SpeculationOp, Type, Value,
+};
+
+/// `n` as a JSON number. The `json` crate overflows negating `i64::MIN`, so that one is built
+/// from its magnitude.
+fn integer(n: isize) -> JsonValue {
+    match n {
+        isize::MIN => JsonValue::Number(Number::from_parts(
+            false,
+            isize::MIN.unsigned_abs() as u64,
+            0,
+        )),
+        n => (n as i64).into(),
+    }
+}
+
+/// The name of `op` in Bril's JSON and text formats.
+fn op_name(op: &OpCode) -> &'static str {
+    match op {
+        OpCode::Const => "const",
+        OpCode::Phi => "phi",
+        OpCode::Arithmetic(ArithmeticOp::Add) => "add",
+        OpCode::Arithmetic(ArithmeticOp::Sub) => "sub",
+        OpCode::Arithmetic(ArithmeticOp::Mul) => "mul",
+        OpCode::Arithmetic(ArithmeticOp::Div) => "div",
+        OpCode::Comparison(ComparisonOp::Eq) => "eq",
+        OpCode::Comparison(ComparisonOp::Lt) => "lt",
+        OpCode::Comparison(ComparisonOp::Gt) => "gt",
+        OpCode::Comparison(ComparisonOp::Le) => "le",
+        OpCode::Comparison(ComparisonOp::Ge) => "ge",
+        OpCode::Logic(LogicOp::Not) => "not",
+        OpCode::Logic(LogicOp::And) => "and",
+        OpCode::Logic(LogicOp::Or) => "or",
+        OpCode::Control(ControlOp::Jmp) => "jmp",
+        OpCode::Control(ControlOp::Br) => "br",
+        OpCode::Control(ControlOp::Call) => "call",
+        OpCode::Control(ControlOp::Ret) => "ret",
+        OpCode::Misc(MiscOp::Id) => "id",
+        OpCode::Misc(MiscOp::Print) => "print",
+        OpCode::Misc(MiscOp::Nop) => "nop",
+        OpCode::Speculation(SpeculationOp::Speculate) => "speculate",
+        OpCode::Speculation(SpeculationOp::Commit) => "commit",
+        OpCode::Speculation(SpeculationOp::Guard) => "guard",
+    }
+}
+
+fn type_name(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int => "int",
+        Type::Bool => "bool",
+    }
+}
+
+fn strings<T: fmt::Display>(names: &[T]) -> JsonValue {
+    JsonValue::Array(names.iter().map(|name| name.to_string().into()).collect())
+}
+
+fn position(pos: &Position) -> JsonValue {
+    object! { "row" => pos.row, "col" => pos.col }
+}
+
+/// Adds the fields of `extensions` to the object `json`, after the ones `mycfg` knows.
+fn write_extensions(json: &mut JsonValue, extensions: &Extensions) {
+    for (key, value) in extensions.iter() {
+        json[key.as_str()] = value.clone();
+    }
+}
+
+impl Program {
+    /// Encodes the program in Bril's JSON format, the inverse of `parser::parse_program`.
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "functions" => JsonValue::Array(self.functions.iter().map(Function::to_json).collect()),
+        }
+    }
+}
+
+impl Function {
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "name" => self.name.to_string(),
+        };
+        if !self.args.is_empty() {
+            json["args"] = JsonValue::Array(
+                self.args
+                    .iter()
+                    .map(|(name, ty)| object! { "name" => name.to_string(), "type" => type_name(ty) })
+                    .collect(),
+            );
+        }
+        if let Some(ty) = &self.ret_type {
+            json["type"] = type_name(ty).into();
+        }
+        let referenced: HashSet<LabelId> = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.instructions.iter())
+            .flat_map(|instr| instr.labels.iter().flatten().copied())
+            .collect();
+        let mut instrs = array![];
+        for block in self.blocks.iter() {
+            block.write_json(&mut instrs, referenced.contains(&block.name));
+        }
+        json["instrs"] = instrs;
+        write_extensions(&mut json, &self.extensions);
+        json
+    }
+}
+
+impl BasicBlock {
+    /// Appends the block's label and instructions to a function's `instrs` array. A made-up
+    /// label is left out unless an instruction refers to it: the block is then only entered by
+    /// falling through, so its instructions may as well join the previous block's. A block of
+    /// phis keeps its label since they have to start a block.
+    fn write_json(&self, instrs: &mut JsonValue, referenced: bool) {
+        let starts_with_phi = self
+            .instructions
+            .first()
+            .is_some_and(|instr| instr.op == OpCode::Phi);
+        if !self.synthetic || referenced || starts_with_phi {
+            let mut label = object! { "label" => self.name.to_string() };
+            if let Some(pos) = &self.pos {
+                label["pos"] = position(pos);
+            }
+            instrs.push(label).unwrap();
+        }
+        for instr in self.instructions.iter() {
+            instrs.push(instr.to_json()).unwrap();
+        }
+    }
+}
+
+impl Instruction {
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "op" => op_name(&self.op),
+        };
+        if let Some(dst) = &self.dst {
+            json["dest"] = dst.to_string().into();
+        }
+        if let Some(ty) = &self.dst_type {
+            json["type"] = type_name(ty).into();
+        }
+        if let Some(args) = &self.args {
+            json["args"] = strings(args);
+        }
+        if let Some(funcs) = &self.funcs {
+            json["funcs"] = strings(funcs);
+        }
+        if let Some(labels) = &self.labels {
+            json["labels"] = strings(labels);
+        }
+        match &self.value {
+            Some(Value::Int(n)) => json["value"] = integer(*n),
+            Some(Value::Bool(b)) => json["value"] = (*b).into(),
+            None => {}
+        }
+        if let Some(pos) = &self.pos {
+            json["pos"] = position(pos);
+        }
+        if let Some(extensions) = &self.extensions {
+            write_extensions(&mut json, extensions);
+        }
+        json
+    }
+}
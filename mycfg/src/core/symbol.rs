@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// The name table of a thread: every variable, label and function name it has seen, numbered in
+/// the order they were first interned.
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, u32>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+fn intern(name: &str) -> u32 {
+    INTERNER.with_borrow_mut(|interner| {
+        if let Some(&id) = interner.ids.get(name) {
+            return id;
+        }
+        let name: Rc<str> = name.into();
+        let id = interner.names.len() as u32;
+        interner.names.push(name.clone());
+        interner.ids.insert(name, id);
+        id
+    })
+}
+
+fn lookup(id: u32) -> Rc<str> {
+    INTERNER.with_borrow(|interner| interner.names[id as usize].clone())
+}
+
+/// Defines a handle to an interned name. Handles are `Copy` and compare, hash and order as
+/// integers, so anything shown to the user has to be sorted by name first. They number names in
+/// the table of the thread that made them, so they are neither `Send` nor `Sync`.
+macro_rules! handle {
+    ($(#[$doc:meta])* $handle:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $handle(u32, PhantomData<*const ()>);
+
+        impl $handle {
+            pub fn new(name: &str) -> $handle {
+                $handle(intern(name), PhantomData)
+            }
+
+            pub fn name(&self) -> Rc<str> {
+                lookup(self.0)
+            }
+
+            /// Orders handles by their names rather than by when they were interned.
+            pub fn cmp_by_name(&self, other: &$handle) -> std::cmp::Ordering {
+                if self.0 == other.0 {
+                    std::cmp::Ordering::Equal
+                } else {
+                    self.name().cmp(&other.name())
+                }
+            }
+        }
+
+        impl fmt::Display for $handle {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.name())
+            }
+        }
+
+        impl fmt::Debug for $handle {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&*self.name(), f)
+            }
+        }
+
+        impl From<&str> for $handle {
+            fn from(name: &str) -> $handle {
+                $handle::new(name)
+            }
+        }
+
+        impl From<&String> for $handle {
+            fn from(name: &String) -> $handle {
+                $handle::new(name)
+            }
+        }
+
+        impl From<String> for $handle {
+            fn from(name: String) -> $handle {
+                $handle::new(&name)
+            }
+        }
+
+        impl PartialEq<str> for $handle {
+            fn eq(&self, other: &str) -> bool {
+                *self.name() == *other
+            }
+        }
+
+        impl PartialEq<&str> for $handle {
+            fn eq(&self, other: &&str) -> bool {
+                *self.name() == **other
+            }
+        }
+
+        impl PartialEq<String> for $handle {
+            fn eq(&self, other: &String) -> bool {
+                *self.name() == **other
+            }
+        }
+    };
+}
+
+handle! {
+    /// The name of a variable.
+    VarId
+}
+
+handle! {
+    /// The name of a basic block, which jumps and branches refer to.
+    LabelId
+}
+
+handle! {
+    /// The name of a function, which calls refer to.
+    FuncId
+}
@@ -0,0 +1,414 @@
+use crate::core::{
+    ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, Extensions, FuncId, Function, Instruction,
+    LabelId, LogicOp, MiscOp, OpCode, Program, Type, Value, VarId,
+};
+
+/// Functions besides `main` in a generated program.
+const MAX_HELPERS: u64 = 3;
+/// Variables each function starts out with, besides its arguments.
+const MAX_VARIABLES: u64 = 6;
+const MAX_ARGS: u64 = 3;
+/// Statements in a block of structured code, and how deeply ifs and loops nest.
+const MAX_STATEMENTS: u64 = 6;
+const MAX_DEPTH: usize = 3;
+/// Loops nest at most this deep in one function and run at most `MAX_TRIPS` times, which bounds
+/// how long a program runs together with calls only going to later functions and never from
+/// inside loops.
+const MAX_LOOP_DEPTH: usize = 2;
+const MAX_TRIPS: u64 = 4;
+
+/// The SplitMix64 generator, small and good enough to generate test programs reproducibly.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+
+    fn int(&mut self) -> isize {
+        match self.below(10) {
+            // Mostly small numbers, so comparisons go both ways, and now and then extreme ones
+            0 => *self.choose(&[isize::MAX, isize::MIN, -1]),
+            _ => self.below(21) as isize - 10,
+        }
+    }
+
+    fn ty(&mut self) -> Type {
+        if self.chance(60) {
+            Type::Int
+        } else {
+            Type::Bool
+        }
+    }
+}
+
+/// A generated program together with arguments for its `main`.
+pub struct Generated {
+    pub program: Program,
+    pub args: Vec<String>,
+}
+
+/// Generates a random well-typed program that terminates. Every variable is assigned before it
+/// is read, loops count up to a small bound with a counter nothing else assigns, calls only go
+/// to functions defined later, so there's no recursion, and divisors are nonzero constants. The
+/// same seed always gives the same program.
+pub fn random_program(seed: u64) -> Generated {
+    let mut rng = Rng::new(seed);
+    let helpers = rng.below(MAX_HELPERS + 1) as usize;
+    let mut signatures: Vec<Function> = vec![];
+    for index in 0..=helpers {
+        let name = match index {
+            0 => FuncId::new("main"),
+            _ => FuncId::new(&format!("f{}", index)),
+        };
+        let args = (0..rng.below(MAX_ARGS + 1))
+            .map(|i| (VarId::new(&format!("a{}", i)), rng.ty()))
+            .collect();
+        let ret_type = match index {
+            0 => None,
+            _ if rng.chance(30) => None,
+            _ => Some(rng.ty()),
+        };
+        signatures.push(Function {
+            name,
+            args,
+            ret_type,
+            blocks: vec![],
+            extensions: Extensions::new(),
+        });
+    }
+    let mut functions = vec![];
+    for index in 0..signatures.len() {
+        let mut generator = FunctionGenerator {
+            rng: &mut rng,
+            callees: &signatures[index + 1..],
+            variables: signatures[index].args.clone(),
+            blocks: vec![],
+            current: BasicBlock {
+                name: "entry".into(),
+                instructions: vec![],
+                pos: None,
+                synthetic: false,
+            },
+            fresh: 0,
+            loop_depth: 0,
+        };
+        functions.push(generator.generate(&signatures[index]));
+    }
+    let args = functions[0]
+        .args
+        .iter()
+        .map(|(_, ty)| match ty {
+            Type::Int => rng.int().to_string(),
+            Type::Bool => rng.chance(50).to_string(),
+        })
+        .collect();
+    Generated {
+        program: Program { functions },
+        args,
+    }
+}
+
+struct FunctionGenerator<'a> {
+    rng: &'a mut Rng,
+    /// Functions this one may call.
+    callees: &'a [Function],
+    /// Variables statements may read and assign; loop counters and bounds aren't among them.
+    variables: Vec<(VarId, Type)>,
+    blocks: Vec<BasicBlock>,
+    current: BasicBlock,
+    fresh: usize,
+    loop_depth: usize,
+}
+
+impl FunctionGenerator<'_> {
+    fn generate(&mut self, signature: &Function) -> Function {
+        for _ in 0..self.rng.below(MAX_VARIABLES) + 1 {
+            let ty = self.rng.ty();
+            let var = self.fresh_name("v");
+            self.constant(var, &ty);
+            self.variables.push((var, ty));
+        }
+        // Both types are always available to read
+        for ty in [Type::Int, Type::Bool] {
+            if !self.variables.iter().any(|(_, t)| *t == ty) {
+                let var = self.fresh_name("v");
+                self.constant(var, &ty);
+                self.variables.push((var, ty));
+            }
+        }
+        self.statements(0);
+        if let Some(ty) = &signature.ret_type {
+            let value = self.variable(ty);
+            self.emit(Instruction {
+                args: Some([value].into()),
+                ..effect(OpCode::Control(ControlOp::Ret))
+            });
+        }
+        let last = std::mem::replace(
+            &mut self.current,
+            BasicBlock {
+                name: LabelId::new(""),
+                instructions: vec![],
+                pos: None,
+                synthetic: false,
+            },
+        );
+        self.blocks.push(last);
+        Function {
+            blocks: std::mem::take(&mut self.blocks),
+            ..signature.clone()
+        }
+    }
+
+    fn fresh_name<T: for<'a> From<&'a str>>(&mut self, base: &str) -> T {
+        self.fresh += 1;
+        T::from(format!("{}{}", base, self.fresh).as_str())
+    }
+
+    fn emit(&mut self, instr: Instruction) {
+        self.current.instructions.push(instr);
+    }
+
+    /// Ends the current block and starts one called `name`.
+    fn start_block(&mut self, name: LabelId) {
+        let done = std::mem::replace(
+            &mut self.current,
+            BasicBlock {
+                name,
+                instructions: vec![],
+                pos: None,
+                synthetic: false,
+            },
+        );
+        self.blocks.push(done);
+    }
+
+    /// A random variable of type `ty`.
+    fn variable(&mut self, ty: &Type) -> VarId {
+        let candidates: Vec<VarId> = self
+            .variables
+            .iter()
+            .filter(|(_, t)| t == ty)
+            .map(|(var, _)| *var)
+            .collect();
+        *self.rng.choose(&candidates)
+    }
+
+    fn constant(&mut self, dst: VarId, ty: &Type) {
+        let value = match ty {
+            Type::Int => Value::Int(self.rng.int()),
+            Type::Bool => Value::Bool(self.rng.chance(50)),
+        };
+        self.emit(Instruction {
+            value: Some(value),
+            args: None,
+            ..Instruction::compute(OpCode::Const, dst, ty.clone(), vec![])
+        });
+    }
+
+    fn statements(&mut self, depth: usize) {
+        for _ in 0..self.rng.below(MAX_STATEMENTS) + 1 {
+            match self.rng.below(10) {
+                0 if depth < MAX_DEPTH => self.branch(depth),
+                1 if depth < MAX_DEPTH && self.loop_depth < MAX_LOOP_DEPTH => {
+                    self.bounded_loop(depth)
+                }
+                2 => self.print(),
+                // Calls in loops could multiply into very long runs
+                3 if !self.callees.is_empty() && self.loop_depth == 0 => self.call(),
+                _ => self.assign(),
+            }
+        }
+    }
+
+    fn assign(&mut self) {
+        let (dst, ty) = self.rng.choose(&self.variables).clone();
+        let ops: &[OpCode] = match ty {
+            Type::Int => &[
+                OpCode::Const,
+                OpCode::Arithmetic(ArithmeticOp::Add),
+                OpCode::Arithmetic(ArithmeticOp::Sub),
+                OpCode::Arithmetic(ArithmeticOp::Mul),
+                OpCode::Arithmetic(ArithmeticOp::Div),
+                OpCode::Misc(MiscOp::Id),
+            ],
+            Type::Bool => &[
+                OpCode::Const,
+                OpCode::Comparison(ComparisonOp::Eq),
+                OpCode::Comparison(ComparisonOp::Lt),
+                OpCode::Comparison(ComparisonOp::Gt),
+                OpCode::Comparison(ComparisonOp::Le),
+                OpCode::Comparison(ComparisonOp::Ge),
+                OpCode::Logic(LogicOp::Not),
+                OpCode::Logic(LogicOp::And),
+                OpCode::Logic(LogicOp::Or),
+                OpCode::Misc(MiscOp::Id),
+            ],
+        };
+        let op = self.rng.choose(ops).clone();
+        let args = match &op {
+            OpCode::Const => return self.constant(dst, &ty),
+            OpCode::Arithmetic(ArithmeticOp::Div) => {
+                // Dividing by a fresh nonzero constant can't trap
+                let divisor: VarId = self.fresh_name("d");
+                let value = match self.rng.int() {
+                    0 => 2,
+                    n => n,
+                };
+                self.emit(Instruction {
+                    value: Some(Value::Int(value)),
+                    args: None,
+                    ..Instruction::compute(OpCode::Const, divisor, Type::Int, vec![])
+                });
+                vec![self.variable(&Type::Int), divisor]
+            }
+            OpCode::Arithmetic(_) | OpCode::Comparison(_) => {
+                vec![self.variable(&Type::Int), self.variable(&Type::Int)]
+            }
+            OpCode::Logic(LogicOp::Not) => vec![self.variable(&Type::Bool)],
+            OpCode::Logic(_) => vec![self.variable(&Type::Bool), self.variable(&Type::Bool)],
+            _ => vec![self.variable(&ty)],
+        };
+        self.emit(Instruction::compute(op, dst, ty, args));
+    }
+
+    fn print(&mut self) {
+        let args = (0..self.rng.below(3) + 1)
+            .map(|_| {
+                let ty = self.rng.ty();
+                self.variable(&ty)
+            })
+            .collect();
+        self.emit(Instruction {
+            args: Some(args),
+            ..effect(OpCode::Misc(MiscOp::Print))
+        });
+    }
+
+    fn call(&mut self) {
+        let callee = self.rng.choose(self.callees).clone();
+        let args = callee
+            .args
+            .iter()
+            .map(|(_, ty)| self.variable(ty))
+            .collect();
+        let (dst, dst_type) = match &callee.ret_type {
+            Some(ty) if self.rng.chance(80) => (Some(self.variable(ty)), Some(ty.clone())),
+            _ => (None, None),
+        };
+        self.emit(Instruction {
+            dst,
+            dst_type,
+            args: Some(args),
+            funcs: Some([callee.name].into()),
+            ..effect(OpCode::Control(ControlOp::Call))
+        });
+    }
+
+    /// An if with or without an else. The else, or the then without one, falls through to the
+    /// join.
+    fn branch(&mut self, depth: usize) {
+        let cond = self.variable(&Type::Bool);
+        let then: LabelId = self.fresh_name("then");
+        let join: LabelId = self.fresh_name("join");
+        let otherwise = match self.rng.chance(60) {
+            true => Some(self.fresh_name("else")),
+            false => None,
+        };
+        let target = otherwise.unwrap_or(join);
+        self.emit(Instruction {
+            args: Some([cond].into()),
+            labels: Some([then, target].into()),
+            ..effect(OpCode::Control(ControlOp::Br))
+        });
+        self.start_block(then);
+        self.statements(depth + 1);
+        if let Some(otherwise) = otherwise {
+            self.emit(Instruction::jmp(join));
+            self.start_block(otherwise);
+            self.statements(depth + 1);
+        }
+        self.start_block(join);
+    }
+
+    /// `for (i = 0; i < bound; i++)` with a counter and bound of its own.
+    fn bounded_loop(&mut self, depth: usize) {
+        let counter: VarId = self.fresh_name("i");
+        let bound: VarId = self.fresh_name("n");
+        let one: VarId = self.fresh_name("one");
+        let cond: VarId = self.fresh_name("c");
+        let header: LabelId = self.fresh_name("loop");
+        let body: LabelId = self.fresh_name("body");
+        let exit: LabelId = self.fresh_name("exit");
+        let int = |value: isize, dst: VarId| Instruction {
+            value: Some(Value::Int(value)),
+            args: None,
+            ..Instruction::compute(OpCode::Const, dst, Type::Int, vec![])
+        };
+        self.emit(int(0, counter));
+        let trips = self.rng.below(MAX_TRIPS + 1) as isize;
+        self.emit(int(trips, bound));
+        self.emit(int(1, one));
+        self.start_block(header);
+        self.emit(Instruction::compute(
+            OpCode::Comparison(ComparisonOp::Lt),
+            cond,
+            Type::Bool,
+            vec![counter, bound],
+        ));
+        self.emit(Instruction {
+            args: Some([cond].into()),
+            labels: Some([body, exit].into()),
+            ..effect(OpCode::Control(ControlOp::Br))
+        });
+        self.start_block(body);
+        self.loop_depth += 1;
+        self.statements(depth + 1);
+        self.loop_depth -= 1;
+        self.emit(Instruction::compute(
+            OpCode::Arithmetic(ArithmeticOp::Add),
+            counter,
+            Type::Int,
+            vec![counter, one],
+        ));
+        self.emit(Instruction::jmp(header));
+        self.start_block(exit);
+    }
+}
+
+/// An instruction without a result, arguments or labels yet.
+fn effect(op: OpCode) -> Instruction {
+    Instruction {
+        op,
+        dst: None,
+        dst_type: None,
+        args: None,
+        funcs: None,
+        labels: None,
+        value: None,
+        pos: None,
+        extensions: None,
+    }
+}
@@ -1,3 +1,12 @@
+pub mod analysis;
+pub mod codegen;
 pub mod core;
+pub mod generate;
 pub mod optimize;
 pub mod parser;
+pub mod profile;
+pub mod reduce;
+pub mod regalloc;
+pub mod ssa;
+pub mod verify;
+pub mod vm;
@@ -1,71 +1,617 @@
+use std::env;
 use std::error::Error;
 use std::fs;
 use std::io;
-use std::io::Read;
-use std::process;
-
-use json::JsonValue;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
 
+use mycfg::analysis::annotate::Annotations;
+use mycfg::analysis::LoopForest;
+use mycfg::codegen;
+use mycfg::core::Program;
 use mycfg::parser;
+use mycfg::profile::Profile;
+use mycfg::reduce::minimize;
+use mycfg::vm::Module;
+
+/// Every command with a one-line description for `--help`.
+const COMMANDS: [(&str, &str); 20] = [
+    ("print", "Print the program"),
+    (
+        "cfg",
+        "Print the control flow graph of the first function in Graphviz format",
+    ),
+    ("loops", "List the natural loops of each function"),
+    (
+        "annotate",
+        "Print the program with the facts of an --analysis as comments",
+    ),
+    ("opt", "Hoist loop invariants and delete dead code"),
+    ("sr", "Strength-reduce induction variables"),
+    ("ssa", "Convert to SSA form"),
+    (
+        "gvn",
+        "Run global value numbering over SSA and convert back",
+    ),
+    (
+        "pre",
+        "Eliminate partial redundancies with lazy code motion",
+    ),
+    ("linear-scan", "Allocate registers with linear scan"),
+    ("coloring", "Allocate registers by graph coloring"),
+    ("asm", "Compile to x86-64 assembly"),
+    ("llvm", "Translate to LLVM IR"),
+    ("c", "Translate to C99"),
+    ("wat", "Translate to the WebAssembly text format"),
+    ("vm", "Run the program on the bytecode VM"),
+    (
+        "profile",
+        "Output the block, edge and call counts of a run as JSON, and what it prints to stderr",
+    ),
+    ("layout", "Reorder blocks using a profile"),
+    (
+        "trace",
+        "Speculatively optimize the path a function takes on an input",
+    ),
+    (
+        "reduce",
+        "Shrink the program while an --interesting command still succeeds on it",
+    ),
+];
+
+/// Commands whose output is a program, which `--format` applies to.
+const PROGRAM_COMMANDS: [&str; 11] = [
+    "print",
+    "opt",
+    "sr",
+    "ssa",
+    "gvn",
+    "pre",
+    "linear-scan",
+    "coloring",
+    "layout",
+    "trace",
+    "reduce",
+];
+
+/// Commands that run the program and take its arguments after the options.
+const RUNNING_COMMANDS: [&str; 3] = ["vm", "profile", "trace"];
+
+/// What `annotate --analysis` accepts.
+const ANALYSES: [&str; 3] = ["live", "reaching", "values"];
+
+const DEFAULT_REGISTERS: usize = 4;
+const TRACE_LENGTH: usize = 64;
+
+/// The input couldn't be read or parsed, a backend rejected it or the output couldn't be written.
+const EXIT_FAILURE: i32 = 1;
+/// The Bril program failed while running, like `brili` reports it.
+const EXIT_RUNTIME_ERROR: i32 = 2;
+/// The command line was invalid.
+const EXIT_USAGE: i32 = 64;
 
-const DEBUG_FILE: &str = "/Users/brendan/Desktop/cs6120/mycfg/tests/fib2seven.json";
-const NORMAL_MODES: [&str; 3] = ["main", "cfg", "opt"];
+const OPTIONS: &str = "Options:
+  -i, --input <file>       Read the program as Bril JSON from <file> instead of stdin
+  -o, --output <file>      Write to <file> instead of stdout
+      --format <format>    Output programs as `text` (the default) or Bril `json`
+      --function <name>    Only process and output @<name>; may be repeated. For `trace`, the
+                           function to trace instead of @main
+  -r, --registers <n>      Registers available to `linear-scan` and `coloring` (default 4)
+      --profile <file>     Profile written by `profile`, for `cfg` (optional) and `layout`
+      --analysis <name>    Facts `annotate` shows; may be repeated. `live` (the default) for
+                           live variables at block boundaries, `reaching` for the definitions
+                           reaching each instruction, `values` for value numbers over SSA form
+  -p                       Report the number of executed instructions to stderr for `vm`
+      --interesting <cmd>  Shell command for `reduce` that exits with 0 if a program still
+                           shows the problem. The program is on its stdin and in the file $1
+  -h, --help               Print this help
 
-fn parse_stdin() -> Result<JsonValue, Box<dyn Error>> {
-    let mut contents = String::new();
-    let mut stdin = io::stdin();
-    stdin.read_to_string(&mut contents)?;
-    let program = json::parse(&contents)?;
-    return Ok(program);
+Arguments after the options of `vm`, `profile` and `trace` are passed to the Bril program's
+main function; put them after `--` if any starts with a dash.
+
+Exit codes:
+  0   Success, including when the output is closed early, as by `head`
+  1   The input couldn't be read or compiled, or the output couldn't be written
+  2   The Bril program failed while running
+  64  Invalid command line";
+
+enum Format {
+    Text,
+    Json,
 }
 
-fn parse_file(filename: &str) -> Result<JsonValue, Box<dyn Error>> {
-    let contents = String::from(fs::read_to_string(filename)?);
-    let program = json::parse(&contents)?;
-    return Ok(program);
+struct Options {
+    command: Option<String>,
+    input: Option<String>,
+    output: Option<String>,
+    format: Format,
+    functions: Vec<String>,
+    registers: Option<usize>,
+    profile: Option<String>,
+    analyses: Vec<String>,
+    count: bool,
+    interesting: Option<String>,
+    help: bool,
+    args: Vec<String>,
 }
 
-fn main() {
-    let mut args = std::env::args();
-    args.next();
-    let mode = args.next().unwrap_or(String::from("dbg")).to_lowercase();
-
-    let json: JsonValue;
-    if NORMAL_MODES.contains(&mode.as_str()) {
-        json = parse_stdin().unwrap_or_else(|err| {
-            eprintln!("Problem parsing stdin: {}", err);
-            process::exit(1);
-        });
-    } else {
-        json = parse_file(DEBUG_FILE).unwrap_or_else(|err| {
-            eprintln!("Problem parsing file {}: {}", DEBUG_FILE, err);
-            process::exit(1);
-        });
-    }
-
-    match mode.as_str() {
-        "main" => {
-            print!("{}", parser::parse_program(&json));
+enum Failure {
+    Usage(String),
+    Failed(Box<dyn Error>),
+    Runtime(Box<dyn Error>),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Failed(err.into())
+    }
+}
+
+fn usage() -> String {
+    let mut usage = String::from(
+        "Analyses, optimizations and backends for Bril programs\n\n\
+         Usage: mycfg <command> [options] [args...]\n\nCommands:\n",
+    );
+    for (name, description) in COMMANDS.iter() {
+        usage.push_str(&format!("  {:<13}{}\n", name, description));
+    }
+    usage.push('\n');
+    usage.push_str(OPTIONS);
+    usage
+}
+
+/// Negative numbers are arguments to the Bril program rather than options.
+fn is_option(arg: &str) -> bool {
+    arg.starts_with('-') && arg.len() > 1 && arg.parse::<i64>().is_err()
+}
+
+fn parse_options(args: impl Iterator<Item = String>) -> Result<Options, Failure> {
+    let mut options = Options {
+        command: None,
+        input: None,
+        output: None,
+        format: Format::Text,
+        functions: vec![],
+        registers: None,
+        profile: None,
+        analyses: vec![],
+        count: false,
+        interesting: None,
+        help: false,
+        args: vec![],
+    };
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        if !is_option(&arg) {
+            match options.command {
+                None => options.command = Some(arg),
+                Some(_) => options.args.push(arg),
+            }
+            continue;
         }
+        if arg == "--" {
+            options.args.extend(args.by_ref());
+            break;
+        }
+        // Long options also accept `--name=value`
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name.to_string(), Some(value.into())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| -> Result<String, Failure> {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| Failure::Usage(format!("{} needs a value", name)))
+        };
+        match name.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-i" | "--input" => options.input = Some(value(&name)?),
+            "-o" | "--output" => options.output = Some(value(&name)?),
+            "--format" => {
+                options.format = match value(&name)?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    other => return Err(Failure::Usage(format!("unknown format {}", other))),
+                }
+            }
+            "--function" => options.functions.push(value(&name)?),
+            "-r" | "--registers" => {
+                let n = value(&name)?;
+                options.registers = Some(
+                    n.parse()
+                        .map_err(|_| Failure::Usage(format!("invalid register count {}", n)))?,
+                );
+            }
+            "--profile" => options.profile = Some(value(&name)?),
+            "--analysis" => {
+                let analysis = value(&name)?;
+                if !ANALYSES.contains(&analysis.as_str()) {
+                    return Err(Failure::Usage(format!("unknown analysis {}", analysis)));
+                }
+                options.analyses.push(analysis);
+            }
+            "-p" => options.count = true,
+            "--interesting" => options.interesting = Some(value(&name)?),
+            _ => return Err(Failure::Usage(format!("unknown option {}", arg))),
+        }
+    }
+    Ok(options)
+}
+
+/// Rejects options that the command would silently ignore.
+fn check_options(command: &str, options: &Options) -> Result<(), Failure> {
+    let unsupported = |option: &str| {
+        Err(Failure::Usage(format!(
+            "{} is not supported by `{}`",
+            option, command
+        )))
+    };
+    if !COMMANDS.iter().any(|(name, _)| *name == command) {
+        return Err(Failure::Usage(format!("unknown command {}", command)));
+    }
+    if matches!(options.format, Format::Json) && !PROGRAM_COMMANDS.contains(&command) {
+        return unsupported("--format json");
+    }
+    // Running or reducing needs the whole program
+    if !options.functions.is_empty() && ["vm", "profile", "reduce"].contains(&command) {
+        return unsupported("--function");
+    }
+    if options.functions.len() > 1 && command == "trace" {
+        return Err(Failure::Usage("`trace` traces a single --function".into()));
+    }
+    if options.registers.is_some() && command != "linear-scan" && command != "coloring" {
+        return unsupported("--registers");
+    }
+    if options.profile.is_some() && command != "cfg" && command != "layout" {
+        return unsupported("--profile");
+    }
+    if options.profile.is_none() && command == "layout" {
+        return Err(Failure::Usage("`layout` needs a --profile".into()));
+    }
+    if !options.analyses.is_empty() && command != "annotate" {
+        return unsupported("--analysis");
+    }
+    if options.count && command != "vm" {
+        return unsupported("-p");
+    }
+    if options.interesting.is_some() && command != "reduce" {
+        return unsupported("--interesting");
+    }
+    if options.interesting.is_none() && command == "reduce" {
+        return Err(Failure::Usage(
+            "`reduce` needs an --interesting command".into(),
+        ));
+    }
+    if !options.args.is_empty() && !RUNNING_COMMANDS.contains(&command) {
+        return Err(Failure::Usage(format!(
+            "unexpected argument {}",
+            options.args[0]
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the program as it comes from the file or stdin.
+fn read_input(input: &Option<String>) -> Result<Program, Failure> {
+    let prog = match input {
+        Some(filename) => {
+            let file = fs::File::open(filename).map_err(|err| {
+                Failure::Failed(format!("couldn't read {}: {}", filename, err).into())
+            })?;
+            parser::read_program(file)
+        }
+        None => parser::read_program(io::stdin().lock()),
+    };
+    prog.map_err(|err| {
+        let source = input.as_deref().unwrap_or("stdin");
+        Failure::Failed(format!("couldn't parse {}: {}", source, err).into())
+    })
+}
+
+fn read_profile(filename: &str) -> Result<Profile, Failure> {
+    fs::read_to_string(filename)
+        .map_err(|err| -> Box<dyn Error> { err.into() })
+        .and_then(|contents| Ok(json::parse(&contents)?))
+        .and_then(|json| Profile::from_json(&json))
+        .map_err(|err| {
+            Failure::Failed(format!("couldn't read profile {}: {}", filename, err).into())
+        })
+}
+
+fn write_program(out: &mut dyn Write, prog: &Program, format: &Format) -> io::Result<()> {
+    match format {
+        Format::Text => write!(out, "{}", prog),
+        Format::Json => writeln!(out, "{}", prog.to_json().pretty(2)),
+    }
+}
+
+fn run(command: &str, options: &Options) -> Result<(), Failure> {
+    let mut prog = read_input(&options.input)?;
+    for name in options.functions.iter() {
+        if !prog.functions.iter().any(|func| func.name == *name) {
+            return Err(Failure::Failed(format!("no function @{}", name).into()));
+        }
+    }
+    // The whole program is needed to run it, so `trace` only transforms the function it traces
+    if !options.functions.is_empty() && command != "trace" {
+        prog.functions
+            .retain(|func| options.functions.iter().any(|name| func.name == *name));
+    }
+
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(filename) => Box::new(BufWriter::new(fs::File::create(filename).map_err(
+            |err| Failure::Failed(format!("couldn't create {}: {}", filename, err).into()),
+        )?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let result = run_command(command, options, prog, &mut out);
+    // Whatever the program printed before failing is still written
+    out.flush()?;
+    result
+}
+
+fn run_command(
+    command: &str,
+    options: &Options,
+    mut prog: Program,
+    out: &mut dyn Write,
+) -> Result<(), Failure> {
+    let runtime = Failure::Runtime;
+    match command {
+        "print" => {}
         "cfg" => {
-            println!("{}", parser::parse_program(&json).graphviz().unwrap());
+            let graph = match &options.profile {
+                Some(filename) => prog.graphviz_with_profile(&read_profile(filename)?),
+                None => prog.graphviz(),
+            };
+            writeln!(out, "{}", graph.map_err(Failure::Failed)?)?;
+            return Ok(());
+        }
+        "loops" => {
+            for func in prog.functions.iter() {
+                write!(out, "@{}: {}", func.name, LoopForest::new(func))?;
+            }
+            return Ok(());
+        }
+        "annotate" => {
+            let analyses: Vec<&str> = if options.analyses.is_empty() {
+                vec!["live"]
+            } else {
+                options.analyses.iter().map(String::as_str).collect()
+            };
+            for (i, func) in prog.functions.iter().enumerate() {
+                // Value numbers are only meaningful in SSA form, so every fact is about it then
+                let func = if analyses.contains(&"values") {
+                    func.to_ssa()
+                } else {
+                    func.clone()
+                };
+                let mut annotations = Annotations::new();
+                for analysis in analyses.iter() {
+                    annotations = match *analysis {
+                        "live" => annotations.with_live_variables(&func),
+                        "reaching" => annotations.with_reaching_definitions(&func),
+                        _ => annotations.with_value_numbers(&func),
+                    };
+                }
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                write!(out, "{}", func.annotated(&annotations))?;
+            }
+            return Ok(());
         }
         "opt" => {
-            let mut prog = parser::parse_program(&json);
-            println!("[BEFORE OPTIMIZATIONS] {}", &prog);
-            for i in 0..prog.functions.len() {
-                for j in 0..prog.functions[i].blocks.len() {
-                    // prog.functions[i].blocks[j] =
-                    // prog.functions[i].blocks[j].local_value_numbering();
-                    prog.functions[i].blocks[j] = prog.functions[i].blocks[j].dead_store_elim();
+            for func in prog.functions.iter_mut() {
+                *func = func.loop_invariant_code_motion();
+                for block in func.blocks.iter_mut() {
+                    *block = block.dead_store_elim();
+                }
+                *func = func.dead_variable_elim();
+            }
+        }
+        "sr" => {
+            for func in prog.functions.iter_mut() {
+                let (reduced, changes) = func.strength_reduction();
+                for change in changes.iter() {
+                    eprintln!("@{} {}", func.name, change);
                 }
-                prog.functions[i] = prog.functions[i].dead_variable_elim();
+                *func = reduced;
+            }
+        }
+        "ssa" => {
+            for func in prog.functions.iter_mut() {
+                *func = func.to_ssa();
+            }
+        }
+        "gvn" => {
+            for func in prog.functions.iter_mut() {
+                *func = func.to_ssa().global_value_numbering().from_ssa();
+            }
+        }
+        "pre" => {
+            for func in prog.functions.iter_mut() {
+                let (optimized, stats) = func.partial_redundancy_elimination();
+                eprintln!("@{}: {}", func.name, stats);
+                *func = optimized;
+            }
+        }
+        "linear-scan" | "coloring" => {
+            let registers = options.registers.unwrap_or(DEFAULT_REGISTERS);
+            for func in prog.functions.iter_mut() {
+                let allocation = if command == "coloring" {
+                    func.allocate_graph_coloring(registers)
+                } else {
+                    func.allocate_linear_scan(registers)
+                };
+                eprintln!("{}", allocation);
+                *func = allocation.apply(func);
+            }
+        }
+        "asm" => {
+            let asm = codegen::emit_x86(&prog).map_err(|err| {
+                Failure::Failed(format!("couldn't compile to x86-64 assembly: {}", err).into())
+            })?;
+            write!(out, "{}", asm)?;
+            return Ok(());
+        }
+        "llvm" => {
+            let ir = codegen::emit_llvm(&prog).map_err(|err| {
+                Failure::Failed(format!("couldn't translate to LLVM IR: {}", err).into())
+            })?;
+            write!(out, "{}", ir)?;
+            return Ok(());
+        }
+        "c" => {
+            let c = codegen::emit_c(&prog).map_err(|err| {
+                Failure::Failed(format!("couldn't translate to C: {}", err).into())
+            })?;
+            write!(out, "{}", c)?;
+            return Ok(());
+        }
+        "wat" => {
+            let module = codegen::emit_wat(&prog).map_err(|err| {
+                Failure::Failed(format!("couldn't compile to WebAssembly: {}", err).into())
+            })?;
+            write!(out, "{}", module)?;
+            return Ok(());
+        }
+        "vm" => {
+            let module = Module::new(&prog).map_err(Failure::Failed)?;
+            let args = module.parse_args(&options.args).map_err(Failure::Failed)?;
+            let count = module.run(&args, out).map_err(runtime)?;
+            if options.count {
+                eprintln!("total_dyn_inst: {}", count);
             }
-            println!("[AFTER] {}", prog);
+            return Ok(());
+        }
+        "profile" => {
+            let module = Module::new(&prog).map_err(Failure::Failed)?;
+            let args = module.parse_args(&options.args).map_err(Failure::Failed)?;
+            let (_, profile) = module
+                .run_profiled(&args, &mut io::stderr())
+                .map_err(runtime)?;
+            writeln!(out, "{}", profile.to_json().pretty(2))?;
+            return Ok(());
         }
-        _ => {
-            println!("[DEBUG MODE] Reading program from {}\n", DEBUG_FILE);
-            print!("{}", parser::parse_program(&json));
+        "layout" => {
+            let profile = read_profile(options.profile.as_ref().unwrap())?;
+            for func in prog.functions.iter_mut() {
+                *func = func.profile_guided_layout(&profile);
+            }
+        }
+        "trace" => {
+            let name = options
+                .functions
+                .first()
+                .map_or("main", |name| name.as_str());
+            let module = Module::new(&prog).map_err(Failure::Failed)?;
+            let args = module.parse_args(&options.args).map_err(Failure::Failed)?;
+            let path = module
+                .record_trace(&args, &mut io::sink(), name, TRACE_LENGTH)
+                .map_err(runtime)?;
+            for func in prog.functions.iter_mut().filter(|f| f.name == name) {
+                *func = func.speculate_trace(&path);
+            }
         }
+        "reduce" => {
+            prog.verify().map_err(|err| {
+                Failure::Failed(format!("the program is not well-formed: {}", err).into())
+            })?;
+            prog = reduce(prog, options.interesting.as_ref().unwrap())?;
+        }
+        _ => unreachable!("commands are checked before running"),
+    }
+    write_program(out, &prog, &options.format)?;
+    Ok(())
+}
+
+fn instruction_count(prog: &Program) -> usize {
+    prog.functions
+        .iter()
+        .flat_map(|func| func.blocks.iter())
+        .map(|block| block.instructions.len())
+        .sum()
+}
+
+/// Runs `command` with the shell on `prog`, which it gets on stdin and as the file `$1`.
+fn is_interesting(command: &str, file: &Path, prog: &Program) -> io::Result<bool> {
+    fs::write(file, prog.to_json().pretty(2))?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(file)
+        .stdin(fs::File::open(file)?)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+fn reduce(prog: Program, command: &str) -> Result<Program, Failure> {
+    let file = env::temp_dir().join(format!("mycfg-reduce-{}.json", process::id()));
+    if !is_interesting(command, &file, &prog)? {
+        let _ = fs::remove_file(&file);
+        return Err(Failure::Failed(
+            "the --interesting command fails on the original program".into(),
+        ));
     }
+    let mut tests = 0;
+    let reduced = minimize(&prog, |candidate| {
+        tests += 1;
+        // A command that can't run makes no candidate interesting
+        is_interesting(command, &file, candidate).unwrap_or(false)
+    });
+    fs::remove_file(&file)?;
+    eprintln!(
+        "reduced {} function(s) with {} instruction(s) to {} with {} in {} test(s)",
+        prog.functions.len(),
+        instruction_count(&prog),
+        reduced.functions.len(),
+        instruction_count(&reduced),
+        tests
+    );
+    Ok(reduced)
+}
+
+fn is_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn main() {
+    let result = parse_options(env::args().skip(1)).and_then(|options| {
+        match (&options.command, options.help) {
+            (_, true) => {
+                writeln!(io::stdout().lock(), "{}", usage())?;
+                Ok(())
+            }
+            (Some(command), false) => {
+                check_options(command, &options)?;
+                run(command, &options)
+            }
+            (None, false) => Err(Failure::Usage("no command given".into())),
+        }
+    });
+    let code = match result {
+        Ok(()) => return,
+        // Whoever reads the output has all they want, like `head` does
+        Err(Failure::Failed(err) | Failure::Runtime(err)) if is_broken_pipe(err.as_ref()) => return,
+        Err(Failure::Usage(message)) => {
+            eprintln!("error: {}\n\nRun `mycfg --help` for usage.", message);
+            EXIT_USAGE
+        }
+        Err(Failure::Failed(err)) => {
+            eprintln!("error: {}", err);
+            EXIT_FAILURE
+        }
+        Err(Failure::Runtime(err)) => {
+            eprintln!("error: {}", err);
+            EXIT_RUNTIME_ERROR
+        }
+    };
+    process::exit(code);
 }
@@ -1,61 +1,175 @@
-mod lvn;
+mod gvn;
+mod induction;
+mod layout;
+mod licm;
+pub mod lvn;
+mod pre;
+mod trace;
 
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
 
-use crate::core::{BasicBlock, Function, Instruction, MiscOp, OpCode};
+use crate::analysis::dataflow::{solve, AvailableCopies};
+use crate::core::{BasicBlock, Function, Instruction, OpCode, VarId};
+
+pub use crate::optimize::induction::InductionChange;
+pub use crate::optimize::pre::PreStats;
 // use crate::optimize::lvn::LVN;
 
-impl Function {
-    pub fn dead_variable_elim(&self) -> Function {
-        let mut last = (*self).clone();
-        loop {
-            let used_vars: Vec<String> = last
+/// A transformation of a single function that mustn't change what the program does, registered
+/// in `PASSES` so tests can check every pass the same way.
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&Function) -> Function,
+    /// Whether the result may execute more instructions even though it's cheaper or a step
+    /// towards something cheaper. Such passes are only checked for behaving the same.
+    pub adds_instructions: bool,
+}
+
+/// Registers available to the register allocators in `PASSES`, few enough that they spill.
+const PASS_REGISTERS: usize = 3;
+
+/// Every pass that only needs the function. Profile-guided layout and trace speculation also
+/// need an input to train on and aren't listed.
+pub const PASSES: [Pass; 11] = [
+    Pass {
+        name: "dead-store-elim",
+        run: |func| Function {
+            blocks: func
                 .blocks
                 .iter()
-                .flat_map(|block| {
-                    block.instructions.iter().flat_map(|x| -> Vec<String> {
-                        match &x.args {
-                            Some(list) => list.to_vec(),
-                            None => vec![],
-                        }
-                    })
-                })
-                .collect();
-
-            let func = Function {
-                name: last.name.clone(),
-                args: last.args.clone(),
-                ret_type: last.ret_type.clone(),
-                blocks: last
-                    .blocks
-                    .iter()
-                    .map(|block| BasicBlock {
-                        name: block.name.clone(),
-                        instructions: block
-                            .instructions
-                            .iter()
-                            .filter(|&x| -> bool {
-                                if let Some(dst) = &x.dst {
-                                    if used_vars.contains(&dst) {
-                                        return true;
-                                    }
-                                    return false;
-                                }
-                                return true;
-                            })
-                            .map(|x| x.clone())
-                            .collect(),
-                    })
-                    .collect(),
-            };
-
-            if func == last {
-                break;
+                .map(BasicBlock::dead_store_elim)
+                .collect(),
+            ..func.clone()
+        },
+        adds_instructions: false,
+    },
+    Pass {
+        name: "dead-variable-elim",
+        run: Function::dead_variable_elim,
+        adds_instructions: false,
+    },
+    Pass {
+        name: "copy-propagation",
+        run: Function::copy_propagation,
+        adds_instructions: false,
+    },
+    Pass {
+        name: "licm",
+        run: Function::loop_invariant_code_motion,
+        adds_instructions: false,
+    },
+    Pass {
+        name: "strength-reduction",
+        run: |func| func.strength_reduction().0,
+        // Additions replace multiplications one for one, but the replacement and its step are
+        // computed before every entry to the loop
+        adds_instructions: true,
+    },
+    Pass {
+        name: "ssa",
+        run: Function::to_ssa,
+        // Phis are extra instructions until SSA form is left again
+        adds_instructions: true,
+    },
+    Pass {
+        name: "ssa-round-trip",
+        run: |func| func.to_ssa().from_ssa(),
+        adds_instructions: false,
+    },
+    Pass {
+        name: "gvn",
+        run: |func| func.to_ssa().global_value_numbering().from_ssa(),
+        // Leaving SSA form copies into the phis that coalescing can't merge
+        adds_instructions: true,
+    },
+    Pass {
+        name: "pre",
+        run: |func| func.partial_redundancy_elimination().0,
+        // Redundant computations become copies of the temporaries holding their values
+        adds_instructions: true,
+    },
+    Pass {
+        name: "linear-scan",
+        run: |func| func.allocate_linear_scan(PASS_REGISTERS).apply(func),
+        adds_instructions: false,
+    },
+    Pass {
+        name: "coloring",
+        run: |func| func.allocate_graph_coloring(PASS_REGISTERS).apply(func),
+        adds_instructions: false,
+    },
+];
+
+impl Function {
+    /// Reads the source of a copy instead of its destination wherever the copy is known to
+    /// hold, so that the copy itself can become dead. Phis read on their incoming edges rather
+    /// than where they stand, so their arguments are left alone.
+    pub fn copy_propagation(&self) -> Function {
+        let copies = solve(self, &AvailableCopies);
+        let mut func = self.clone();
+        for block in func.blocks.iter_mut() {
+            let facts = copies.instruction_facts(&AvailableCopies, block);
+            for (instr, (before, _)) in block.instructions.iter_mut().zip(facts) {
+                if instr.op == OpCode::Phi {
+                    continue;
+                }
+                for arg in instr.args.iter_mut().flat_map(|args| args.iter_mut()) {
+                    if let Some(&(_, src)) = before.iter().find(|&&(dst, _)| dst == *arg) {
+                        *arg = src;
+                    }
+                }
+            }
+        }
+        func
+    }
+
+    /// Removes pure instructions whose result is never read, and then the ones that only fed
+    /// those, until every remaining definition is read somewhere or kept for its effects.
+    pub fn dead_variable_elim(&self) -> Function {
+        // Calls and divisions stay for their effects
+        let removable = |instr: &Instruction| instr.is_pure() && !instr.may_trap();
+        let mut uses: HashMap<VarId, usize> = HashMap::new();
+        let mut defs: HashMap<VarId, Vec<(usize, usize)>> = HashMap::new();
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (i, instr) in block.instructions.iter().enumerate() {
+                for &arg in instr.uses() {
+                    *uses.entry(arg).or_default() += 1;
+                }
+                if let (Some(dst), true) = (instr.dst, removable(instr)) {
+                    defs.entry(dst).or_default().push((b, i));
+                }
             }
-            last = func;
         }
-        return last;
+
+        let mut dead: Vec<Vec<bool>> = self
+            .blocks
+            .iter()
+            .map(|block| vec![false; block.instructions.len()])
+            .collect();
+        let mut unread: Vec<VarId> = defs
+            .keys()
+            .filter(|var| !uses.contains_key(var))
+            .copied()
+            .collect();
+        while let Some(var) = unread.pop() {
+            for (b, i) in defs.remove(&var).unwrap_or_default() {
+                dead[b][i] = true;
+                for arg in self.blocks[b].instructions[i].uses() {
+                    let count = uses.get_mut(arg).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        unread.push(*arg);
+                    }
+                }
+            }
+        }
+
+        let mut func = self.clone();
+        for (block, dead) in func.blocks.iter_mut().zip(dead) {
+            let mut dead = dead.into_iter();
+            block.instructions.retain(|_| !dead.next().unwrap());
+        }
+        func
     }
 }
 
@@ -64,32 +178,37 @@ impl BasicBlock {
         let mut last = self.clone();
         loop {
             let mut block = last.clone();
-            let mut unused_defs: HashMap<&String, usize> = HashMap::new();
+            let mut unused_defs: HashMap<VarId, usize> = HashMap::new();
+            let mut dead: HashSet<usize> = HashSet::new();
 
             for (i, instr) in last.instructions.iter().enumerate() {
                 // Check for variable uses
                 if let Some(args) = &instr.args {
                     for var in args.iter() {
-                        if unused_defs.contains_key(&var) {
-                            unused_defs.remove(var);
-                        }
+                        unused_defs.remove(var);
                     }
                 }
-                // Check for variable definitions
-                if let Some(dst) = &instr.dst {
-                    if unused_defs.contains_key(dst) {
-                        block.instructions.remove(unused_defs[dst]);
+                // Check for variable definitions. Calls and divisions stay for their effects.
+                if let Some(dst) = instr.dst {
+                    if let Some(def) = unused_defs.insert(dst, i) {
+                        let instr = &last.instructions[def];
+                        if instr.is_pure() && !instr.may_trap() {
+                            dead.insert(def);
+                        }
                     }
-                    unused_defs.insert(dst, i);
                 }
             }
+            let mut index = 0..;
+            block
+                .instructions
+                .retain(|_| !dead.contains(&index.next().unwrap()));
 
             if block == last {
                 break;
             }
             last = block;
         }
-        return last;
+        last
     }
 
     // pub fn local_value_numbering(&self) -> BasicBlock {
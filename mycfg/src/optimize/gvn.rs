@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::Dominators;
+use crate::core::{BasicBlock, Function, Instruction, LabelId, MiscOp, OpCode, VarId};
+use crate::optimize::lvn::LVNValue;
+use crate::parser::control_flow_graph;
+use crate::ssa::variable_types;
+
+impl Function {
+    /// Dominator-tree value numbering of a function in SSA form. Computations already available
+    /// in a dominating block, copies, and phis whose arguments all carry the same value are
+    /// removed, and their uses read the earlier variable instead.
+    pub fn global_value_numbering(&self) -> Function {
+        let (_, mut blocks) = self.number_values();
+
+        Function {
+            name: self.name,
+            args: self.args.clone(),
+            ret_type: self.ret_type.clone(),
+            blocks: self
+                .blocks
+                .iter()
+                .filter_map(|block| blocks.remove(&block.name))
+                .collect(),
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    /// Value number of every argument and variable defined in a function in SSA form, as
+    /// `global_value_numbering` finds them: variables holding the same value share a number.
+    /// Numbers count up from 0 in the order values first appear in the function.
+    pub fn value_numbers(&self) -> HashMap<VarId, usize> {
+        let (mut gvn, _) = self.number_values();
+
+        let vars = self.args.iter().map(|&(arg, _)| arg).chain(
+            self.blocks
+                .iter()
+                .flat_map(|block| block.instructions.iter())
+                .filter_map(|instr| instr.dst),
+        );
+        let mut renumbered: HashMap<usize, usize> = HashMap::new();
+        let mut numbers: HashMap<VarId, usize> = HashMap::new();
+        for var in vars {
+            let number = gvn.number(gvn.canon(var));
+            let next = renumbered.len();
+            numbers.insert(var, *renumbered.entry(number).or_insert(next));
+        }
+        numbers
+    }
+
+    /// Runs value numbering down the dominator tree, returning the numbering and the blocks
+    /// without their redundant instructions.
+    fn number_values(&self) -> (Gvn, HashMap<LabelId, BasicBlock>) {
+        let cfg = control_flow_graph(self);
+        let doms = Dominators::new(self);
+        let mut blocks: HashMap<LabelId, BasicBlock> = self
+            .blocks
+            .iter()
+            .map(|block| (block.name, block.clone()))
+            .collect();
+
+        let mut gvn = Gvn {
+            defined: variable_types(self).into_keys().collect(),
+            numbers: HashMap::new(),
+            canonical: HashMap::new(),
+            table: HashMap::new(),
+        };
+        if let Some(entry) = self.blocks.first() {
+            gvn.visit(entry.name, &mut blocks, &cfg, &doms);
+        }
+        (gvn, blocks)
+    }
+}
+
+struct Gvn {
+    /// Arguments and destinations: phi arguments that are neither stand for no value.
+    defined: HashSet<VarId>,
+    /// Value number of every variable that represents its value.
+    numbers: HashMap<VarId, usize>,
+    /// Variable holding the value of each variable whose definition was removed.
+    canonical: HashMap<VarId, VarId>,
+    /// Variable computing each value in the dominator tree path being visited.
+    table: HashMap<LVNValue, VarId>,
+}
+
+impl Gvn {
+    fn canon(&self, var: VarId) -> VarId {
+        self.canonical.get(&var).copied().unwrap_or(var)
+    }
+
+    fn number(&mut self, var: VarId) -> usize {
+        let next = self.numbers.len();
+        *self.numbers.entry(var).or_insert(next)
+    }
+
+    fn visit(
+        &mut self,
+        name: LabelId,
+        blocks: &mut HashMap<LabelId, BasicBlock>,
+        cfg: &HashMap<LabelId, Vec<LabelId>>,
+        doms: &Dominators,
+    ) {
+        let mut scope: Vec<LVNValue> = vec![];
+        let block = blocks.get_mut(&name).unwrap();
+        let instructions = std::mem::take(&mut block.instructions);
+        let mut kept: Vec<Instruction> = vec![];
+
+        for mut instr in instructions.into_iter() {
+            if let Some(args) = instr.args.as_mut() {
+                for arg in args.iter_mut() {
+                    *arg = self.canon(*arg);
+                }
+            }
+            let dst = match instr.dst {
+                Some(dst) => dst,
+                None => {
+                    kept.push(instr);
+                    continue;
+                }
+            };
+
+            if instr.op == OpCode::Phi {
+                // Meaningless if every incoming value other than the phi itself is the same
+                let mut incoming: Vec<VarId> = instr
+                    .uses()
+                    .iter()
+                    .copied()
+                    .filter(|&arg| arg != dst)
+                    .collect();
+                incoming.sort();
+                incoming.dedup();
+                if let [value] = incoming.as_slice() {
+                    if self.defined.contains(value) {
+                        self.canonical.insert(dst, *value);
+                        continue;
+                    }
+                }
+                // Redundant if an earlier phi of this block merges the same values
+                let same = kept.iter().find(|other| {
+                    other.op == OpCode::Phi
+                        && other.args == instr.args
+                        && other.labels == instr.labels
+                });
+                if let Some(other) = same {
+                    self.canonical.insert(dst, other.dst.unwrap());
+                    continue;
+                }
+                kept.push(instr);
+                continue;
+            }
+
+            if instr.op == OpCode::Misc(MiscOp::Id) {
+                self.canonical.insert(dst, instr.uses()[0]);
+                continue;
+            }
+
+            let numbers: Vec<usize> = instr.uses().iter().map(|&arg| self.number(arg)).collect();
+            match LVNValue::new(&instr, numbers) {
+                Some(value) => match self.table.get(&value) {
+                    Some(existing) => {
+                        self.canonical.insert(dst, *existing);
+                    }
+                    None => {
+                        self.table.insert(value.clone(), dst);
+                        scope.push(value);
+                        kept.push(instr);
+                    }
+                },
+                None => kept.push(instr),
+            }
+        }
+        block.instructions = kept;
+
+        // Phi arguments flowing from this block are read at its end
+        for succ in cfg[&name].iter() {
+            let succ_block = blocks.get_mut(succ).unwrap();
+            for phi in succ_block
+                .instructions
+                .iter_mut()
+                .filter(|i| i.op == OpCode::Phi)
+            {
+                let labels = phi.labels.as_ref().unwrap();
+                for (arg, label) in phi.args.as_mut().unwrap().iter_mut().zip(labels) {
+                    if *label == name {
+                        *arg = self.canon(*arg);
+                    }
+                }
+            }
+        }
+
+        for &child in doms.children(name) {
+            self.visit(child, blocks, cfg, doms);
+        }
+
+        for value in scope {
+            self.table.remove(&value);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::analysis::dataflow::{solve, LiveVariables};
+use crate::analysis::LoopForest;
+use crate::core::{
+    fresh_name, ArithmeticOp, Function, Instruction, LabelId, MiscOp, OpCode, Type, Value, VarId,
+};
+use crate::parser::control_flow_graph;
+
+type Site = (LabelId, usize);
+
+pub enum InductionChange {
+    /// `var = mul base factor` now copies `replacement`, which is kept equal to `base * factor`
+    /// by adding `factor * step` whenever `base` is updated. `setup` instructions were added
+    /// before the loop, and the loop's own instructions changed by `in_loop`: the update of
+    /// `replacement` unless another variable shares it, and the copy unless reading
+    /// `replacement` directly left it dead, less the multiplication.
+    StrengthReduced {
+        header: LabelId,
+        var: VarId,
+        base: VarId,
+        factor: VarId,
+        replacement: VarId,
+        setup: usize,
+        in_loop: isize,
+    },
+    /// A basic induction variable whose only remaining use in the loop was its own update.
+    Eliminated { header: LabelId, var: VarId },
+}
+
+impl InductionChange {
+    /// How many instructions the change added (or removed, if negative) before the loop and in
+    /// it.
+    pub fn net_effect(&self) -> (usize, isize) {
+        match self {
+            InductionChange::StrengthReduced { setup, in_loop, .. } => (*setup, *in_loop),
+            InductionChange::Eliminated { .. } => (0, -1),
+        }
+    }
+}
+
+impl fmt::Display for InductionChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InductionChange::StrengthReduced {
+                header,
+                var,
+                base,
+                factor,
+                replacement,
+                ..
+            } => write!(
+                f,
+                ".{}: {} = mul {} {} replaced by additive updates of {}",
+                header, var, base, factor, replacement
+            )?,
+            InductionChange::Eliminated { header, var } => write!(
+                f,
+                ".{}: removed redundant induction variable {}",
+                header, var
+            )?,
+        }
+        let (setup, in_loop) = self.net_effect();
+        write!(f, "; +{} before the loop, {:+} in it", setup, in_loop)
+    }
+}
+
+/// A basic induction variable `var = var + step` (or `- step`) with a loop-invariant step.
+struct BasicInduction {
+    var: VarId,
+    site: Site,
+    step: VarId,
+    op: ArithmeticOp,
+}
+
+impl Function {
+    /// Replaces multiplications of basic induction variables by loop-invariant factors with
+    /// additive updates, then drops basic induction variables left without any other use.
+    /// Copies left in place of the multiplications are propagated and removed where they can be.
+    pub fn strength_reduction(&self) -> (Function, Vec<InductionChange>) {
+        let mut func = self.insert_preheaders();
+        let mut changes: Vec<InductionChange> = vec![];
+        let forest = LoopForest::new(&func);
+        let headers: Vec<LabelId> = forest
+            .inner_to_outer()
+            .into_iter()
+            .map(|i| forest.loops[i].header)
+            .collect();
+
+        let constants = constants(&func);
+        let mut created: HashSet<VarId> = HashSet::new();
+        for header in headers {
+            func = reduce_loop(&func, header, &constants, &mut created, &mut changes);
+        }
+        if changes.is_empty() {
+            return (func, changes);
+        }
+
+        let func = func.copy_propagation().dead_variable_elim();
+        let copies: HashSet<(VarId, VarId)> = func
+            .blocks
+            .iter()
+            .flat_map(|block| block.instructions.iter())
+            .filter(|instr| instr.op == OpCode::Misc(MiscOp::Id))
+            .filter_map(|instr| Some((instr.dst?, *instr.uses().first()?)))
+            .collect();
+        for change in changes.iter_mut() {
+            if let InductionChange::StrengthReduced {
+                var,
+                replacement,
+                in_loop,
+                ..
+            } = change
+            {
+                if !copies.contains(&(*var, *replacement)) {
+                    *in_loop -= 1;
+                }
+            }
+        }
+        (func, changes)
+    }
+}
+
+/// Variables whose only definition in `func` is an integer constant, with their values.
+fn constants(func: &Function) -> HashMap<VarId, isize> {
+    let mut defs: HashMap<VarId, Option<isize>> = HashMap::new();
+    for instr in func
+        .blocks
+        .iter()
+        .flat_map(|block| block.instructions.iter())
+    {
+        if let Some(dst) = instr.dst {
+            let value = match (&instr.op, &instr.value) {
+                (OpCode::Const, Some(Value::Int(n))) => Some(*n),
+                _ => None,
+            };
+            defs.entry(dst)
+                .and_modify(|def| *def = None)
+                .or_insert(value);
+        }
+    }
+    defs.into_iter()
+        .filter_map(|(var, value)| Some((var, value?)))
+        .collect()
+}
+
+/// Reduces the multiplications in the loop headed by `header`, leaving alone instructions that
+/// reduced an inner loop, which are named in `created` along with the ones added here.
+fn reduce_loop(
+    func: &Function,
+    header: LabelId,
+    constants: &HashMap<VarId, isize>,
+    created: &mut HashSet<VarId>,
+    changes: &mut Vec<InductionChange>,
+) -> Function {
+    let forest = LoopForest::new(func);
+    let index = forest
+        .loops
+        .iter()
+        .position(|l| l.header == header)
+        .unwrap();
+    let l = &forest.loops[index];
+    let preheader = forest.preheader(func, index).unwrap();
+
+    let mut defs: HashMap<VarId, Vec<Site>> = HashMap::new();
+    for block in func.blocks.iter().filter(|b| l.contains(b.name)) {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            if let Some(dst) = instr.dst {
+                defs.entry(dst).or_default().push((block.name, i));
+            }
+        }
+    }
+    let invariant = |var: &VarId| !defs.contains_key(var);
+    let single_def = |var: &VarId| -> Option<&Site> {
+        match defs.get(var).map(|sites| sites.as_slice()) {
+            Some([site]) => Some(site),
+            _ => None,
+        }
+    };
+
+    let mut basics: Vec<BasicInduction> = vec![];
+    for block in func.blocks.iter().filter(|b| l.contains(b.name)) {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let var = match &instr.dst {
+                Some(dst) if single_def(dst).is_some() && !created.contains(dst) => dst,
+                _ => continue,
+            };
+            let add = OpCode::Arithmetic(ArithmeticOp::Add);
+            let sub = OpCode::Arithmetic(ArithmeticOp::Sub);
+            let (op, step) = match instr.uses() {
+                [a, b] if instr.op == add && a == var && invariant(b) => (ArithmeticOp::Add, b),
+                [a, b] if instr.op == add && b == var && invariant(a) => (ArithmeticOp::Add, a),
+                [a, b] if instr.op == sub && a == var && invariant(b) => (ArithmeticOp::Sub, b),
+                _ => continue,
+            };
+            basics.push(BasicInduction {
+                var: *var,
+                site: (block.name, i),
+                step: *step,
+                op,
+            });
+        }
+    }
+
+    let mut preheader_code: Vec<Instruction> = vec![];
+    let mut after_update: HashMap<Site, Vec<Instruction>> = HashMap::new();
+    let mut replaced: HashMap<Site, Instruction> = HashMap::new();
+    // Derived variables with the same base and factor share one reduced variable
+    let mut reduced: HashMap<(VarId, VarId), VarId> = HashMap::new();
+    let mut taken: HashSet<VarId> = func.variables();
+
+    for block in func.blocks.iter().filter(|b| l.contains(b.name)) {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let var = match &instr.dst {
+                Some(dst) if single_def(dst).is_some() && !created.contains(dst) => dst,
+                _ => continue,
+            };
+            if instr.op != OpCode::Arithmetic(ArithmeticOp::Mul) {
+                continue;
+            }
+            let (iv, factor) = match instr.uses() {
+                [a, b] if invariant(b) && basics.iter().any(|iv| iv.var == *a) => (a, b),
+                [a, b] if invariant(a) && basics.iter().any(|iv| iv.var == *b) => (b, a),
+                _ => continue,
+            };
+            if iv == var {
+                continue;
+            }
+            let basic = basics.iter().find(|b| b.var == *iv).unwrap();
+
+            let key = (*iv, *factor);
+            let (replacement, setup, updates) = match reduced.get(&key) {
+                Some(&name) => (name, 0, 0),
+                None => {
+                    let name = fresh_name(&format!("{}.sr", var), &mut taken);
+                    let step = fresh_name(&format!("{}.step", name), &mut taken);
+                    created.extend([name, step]);
+                    preheader_code.push(
+                        match (constants.get(factor), constants.get(&basic.step)) {
+                            (Some(a), Some(b)) => Instruction::constant(
+                                step,
+                                Type::Int,
+                                Value::Int(a.wrapping_mul(*b)),
+                            ),
+                            _ => Instruction::compute(
+                                OpCode::Arithmetic(ArithmeticOp::Mul),
+                                step,
+                                Type::Int,
+                                vec![*factor, basic.step],
+                            ),
+                        },
+                    );
+                    preheader_code.push(Instruction::compute(
+                        OpCode::Arithmetic(ArithmeticOp::Mul),
+                        name,
+                        Type::Int,
+                        vec![*iv, *factor],
+                    ));
+                    after_update
+                        .entry(basic.site)
+                        .or_default()
+                        .push(Instruction::compute(
+                            OpCode::Arithmetic(basic.op.clone()),
+                            name,
+                            Type::Int,
+                            vec![name, step],
+                        ));
+                    reduced.insert(key, name);
+                    (name, 2, 1)
+                }
+            };
+
+            replaced.insert(
+                (block.name, i),
+                Instruction {
+                    pos: instr.pos,
+                    ..Instruction::compute(
+                        OpCode::Misc(MiscOp::Id),
+                        *var,
+                        Type::Int,
+                        vec![replacement],
+                    )
+                },
+            );
+            changes.push(InductionChange::StrengthReduced {
+                header,
+                var: *var,
+                base: *iv,
+                factor: *factor,
+                replacement,
+                setup,
+                // The update and the copy less the multiplication, until propagation tells
+                // whether the copy stays
+                in_loop: updates,
+            });
+        }
+    }
+
+    // A basic induction variable is redundant once nothing in the loop but its own update reads
+    // it and it is dead on every exit
+    let cfg = control_flow_graph(func);
+    let live = solve(func, &LiveVariables);
+    let exits = l.exits(&cfg);
+    let mut removed: HashSet<Site> = HashSet::new();
+    for basic in basics.iter() {
+        let used_in_loop = func
+            .blocks
+            .iter()
+            .filter(|b| l.contains(b.name))
+            .any(|block| {
+                block.instructions.iter().enumerate().any(|(i, instr)| {
+                    let site = (block.name, i);
+                    let instr = replaced.get(&site).unwrap_or(instr);
+                    site != basic.site && instr.uses().contains(&basic.var)
+                })
+            });
+        let live_after = exits
+            .iter()
+            .any(|(_, outside)| live.block_in[outside].contains(&basic.var));
+        if !used_in_loop && !live_after {
+            removed.insert(basic.site);
+            changes.push(InductionChange::Eliminated {
+                header,
+                var: basic.var,
+            });
+        }
+    }
+
+    let mut result = func.clone();
+    for block in result.blocks.iter_mut() {
+        let name = block.name;
+        let mut instructions: Vec<Instruction> = vec![];
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let site = (name, i);
+            if !removed.contains(&site) {
+                instructions.push(replaced.get(&site).unwrap_or(instr).clone());
+            }
+            if let Some(updates) = after_update.get(&site) {
+                instructions.extend(updates.iter().cloned());
+            }
+        }
+        if name == preheader {
+            let at = match instructions.last() {
+                Some(last) if last.is_terminator() => instructions.len() - 1,
+                _ => instructions.len(),
+            };
+            instructions.splice(at..at, preheader_code.iter().cloned());
+        }
+        block.instructions = instructions;
+    }
+    result
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::core::{ControlOp, Function, Instruction, LabelId, OpCode};
+use crate::profile::Profile;
+
+impl Function {
+    /// Reorders blocks so that the most frequently taken edge out of a block falls through to its
+    /// target where possible, using the greedy chaining of Pettis and Hansen. Blocks that used to
+    /// fall through get an explicit `jmp`, and every `jmp` to the block that now follows is
+    /// dropped. The entry block stays first, and a block that falls off the end of the function
+    /// stays last.
+    pub fn profile_guided_layout(&self, profile: &Profile) -> Function {
+        let n = self.blocks.len();
+        if n == 0 {
+            return self.clone();
+        }
+        let index: HashMap<String, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.name.to_string(), i))
+            .collect();
+
+        let mut func = self.clone();
+        let mut falls_off_end = None;
+        for i in 0..n {
+            if func.blocks[i]
+                .instructions
+                .last()
+                .is_some_and(|instr| instr.is_terminator())
+            {
+                continue;
+            }
+            match self.blocks.get(i + 1) {
+                Some(next) => func.blocks[i]
+                    .instructions
+                    .push(Instruction::jmp(next.name)),
+                None => falls_off_end = Some(i),
+            }
+        }
+
+        // Every block starts as a chain of its own; chains are joined tail to head along edges,
+        // hottest first
+        let mut edges: Vec<(u64, usize, usize)> = vec![];
+        if let Some(counts) = profile.functions.get(&*self.name.name()) {
+            for ((from, to), count) in counts.edges.iter() {
+                if let (Some(&from), Some(&to)) = (index.get(from), index.get(to)) {
+                    if *count > 0 {
+                        edges.push((*count, from, to));
+                    }
+                }
+            }
+        }
+        edges.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        let mut chains: Vec<Vec<usize>> = (0..n).map(|i| vec![i]).collect();
+        let mut chain_of: Vec<usize> = (0..n).collect();
+        for (_, from, to) in edges {
+            let (a, b) = (chain_of[from], chain_of[to]);
+            // Joining the entry to the end of the function would leave no room for other chains
+            let pinned = |c: usize| falls_off_end.is_some_and(|i| chain_of[i] == c);
+            if a == b
+                || to == 0
+                || (a == chain_of[0] && pinned(b))
+                || chains[a].last() != Some(&from)
+                || chains[b].first() != Some(&to)
+                || !branches_to(&func.blocks[from].instructions, self.blocks[to].name)
+            {
+                continue;
+            }
+            let moved = std::mem::take(&mut chains[b]);
+            for block in moved.iter() {
+                chain_of[*block] = a;
+            }
+            chains[a].extend(moved);
+        }
+
+        // The entry chain goes first and the chain ending the function last; the rest follow in
+        // order of how often their first block ran
+        let count = |i: usize| profile.block(&self.name.name(), &self.blocks[i].name.name());
+        let mut order: Vec<usize> = (0..n).filter(|&c| !chains[c].is_empty()).collect();
+        let last = falls_off_end.map(|i| chain_of[i]);
+        order.sort_by_key(|&c| {
+            (
+                c != 0,
+                Some(c) == last,
+                std::cmp::Reverse(count(chains[c][0])),
+                c,
+            )
+        });
+
+        let blocks = std::mem::take(&mut func.blocks);
+        let placed: Vec<usize> = order.iter().flat_map(|&c| chains[c].clone()).collect();
+        for (pos, &i) in placed.iter().enumerate() {
+            let mut block = blocks[i].clone();
+            if let (Some(next), Some(last)) = (placed.get(pos + 1), block.instructions.last()) {
+                if last.op == OpCode::Control(ControlOp::Jmp)
+                    && last.labels.as_ref().unwrap()[0] == blocks[*next].name
+                {
+                    block.instructions.pop();
+                }
+            }
+            func.blocks.push(block);
+        }
+        func
+    }
+}
+
+fn branches_to(instructions: &[Instruction], target: LabelId) -> bool {
+    instructions
+        .last()
+        .and_then(|instr| instr.labels.as_ref())
+        .is_some_and(|labels| labels.contains(&target))
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::analysis::dataflow::{
+    solve, DefSite, DefinedVariables, Definition, LiveVariables, ReachingDefinitions,
+};
+use crate::analysis::{Dominators, LoopForest};
+use crate::core::{BasicBlock, Function, Instruction, LabelId, OpCode, Value, VarId};
+use crate::parser::control_flow_graph;
+
+impl Function {
+    /// Hoists loop-invariant pure instructions into loop preheaders, innermost loops first so
+    /// that code lifted out of an inner loop can keep moving outwards.
+    pub fn loop_invariant_code_motion(&self) -> Function {
+        let mut func = self.insert_preheaders();
+        let forest = LoopForest::new(&func);
+        let headers: Vec<LabelId> = forest
+            .inner_to_outer()
+            .into_iter()
+            .map(|i| forest.loops[i].header)
+            .collect();
+
+        for header in headers {
+            func = hoist_invariants(&func, header);
+        }
+        func
+    }
+}
+
+/// Hoists out of the loop headed by `header` every instruction that
+/// - is pure and only reads variables assigned on every path into the loop and never in it, or
+///   defined by other hoisted instructions. Reading an unassigned variable is an error, which the
+///   preheader would raise even when the loop never reaches the read,
+/// - is the only definition of its destination in the loop,
+/// - defines a variable that is not live into the header, so no use in the loop sees an older value,
+/// - dominates every loop exit, or defines a variable that is dead after the loop,
+/// - cannot trap, or is a division by a constant other than 0 and -1. Hoisting a division that
+///   may fail would raise the error before anything the loop prints or calls first.
+fn hoist_invariants(func: &Function, header: LabelId) -> Function {
+    let forest = LoopForest::new(func);
+    let index = forest
+        .loops
+        .iter()
+        .position(|l| l.header == header)
+        .unwrap();
+    let l = &forest.loops[index];
+    let preheader = forest.preheader(func, index).unwrap();
+
+    let cfg = control_flow_graph(func);
+    let doms = Dominators::new(func);
+    let reaching = solve(func, &ReachingDefinitions);
+    let live = solve(func, &LiveVariables);
+    let defined = solve(func, &DefinedVariables);
+    let entering = &defined.block_out[&preheader];
+
+    let exits = l.exits(&cfg);
+    let mut defs_in_loop: HashMap<VarId, usize> = HashMap::new();
+    for block in func.blocks.iter().filter(|b| l.contains(b.name)) {
+        for instr in block.instructions.iter() {
+            if let Some(dst) = instr.dst {
+                *defs_in_loop.entry(dst).or_default() += 1;
+            }
+        }
+    }
+
+    let blocks: HashMap<LabelId, &BasicBlock> = func
+        .blocks
+        .iter()
+        .map(|block| (block.name, block))
+        .collect();
+    let mut hoisted: HashSet<(LabelId, usize)> = HashSet::new();
+    let mut moved: Vec<Instruction> = vec![];
+    // Reverse postorder visits definitions before the uses they dominate
+    for &name in doms.reverse_postorder().iter().filter(|&&b| l.contains(b)) {
+        let block = blocks[&name];
+        let facts = reaching.instruction_facts(&ReachingDefinitions, block);
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let dst = match instr.dst {
+                Some(dst) if instr.is_pure() => dst,
+                _ => continue,
+            };
+
+            // Assigned before the loop and every reaching definition is outside it, or the only
+            // one is already hoisted
+            let operands_invariant = instr.uses().iter().all(|arg| {
+                let defs: Vec<&DefSite> = facts[i]
+                    .0
+                    .iter()
+                    .filter(|d| d.var == *arg)
+                    .map(|d| &d.site)
+                    .collect();
+                entering.contains(arg)
+                    && defs.iter().all(|site| match site {
+                        DefSite::Argument => true,
+                        DefSite::Instruction { block, .. } => !l.contains(*block),
+                    })
+                    || match defs.as_slice() {
+                        [DefSite::Instruction { block, index }] => {
+                            hoisted.contains(&(*block, *index))
+                        }
+                        _ => false,
+                    }
+            });
+            if !operands_invariant
+                || defs_in_loop[&dst] != 1
+                || live.block_in[&header].contains(&dst)
+            {
+                continue;
+            }
+
+            let dominates_exits = exits
+                .iter()
+                .all(|&(inside, _)| doms.dominates(block.name, inside));
+            let dead_after_loop = exits
+                .iter()
+                .all(|(_, outside)| !live.block_in[outside].contains(&dst));
+            if !dominates_exits && !dead_after_loop {
+                continue;
+            }
+            if instr.may_trap() && !divides_safely(instr, &facts[i].0, &blocks) {
+                continue;
+            }
+
+            hoisted.insert((block.name, i));
+            moved.push(instr.clone());
+        }
+    }
+
+    let mut result = func.clone();
+    for block in result.blocks.iter_mut() {
+        if block.name == preheader {
+            let at = match block.instructions.last() {
+                Some(last) if last.is_terminator() => block.instructions.len() - 1,
+                _ => block.instructions.len(),
+            };
+            block.instructions.splice(at..at, moved.iter().cloned());
+        } else if l.contains(block.name) {
+            let name = block.name;
+            let mut i = 0;
+            block.instructions.retain(|_| {
+                i += 1;
+                !hoisted.contains(&(name, i - 1))
+            });
+        }
+    }
+    result
+}
+
+/// Whether `instr`, a division, can't fail because the only definition of its divisor reaching
+/// it is a constant other than 0 and -1.
+fn divides_safely(
+    instr: &Instruction,
+    reaching: &BTreeSet<Definition>,
+    blocks: &HashMap<LabelId, &BasicBlock>,
+) -> bool {
+    let divisor = instr.uses()[1];
+    let mut defs = reaching.iter().filter(|d| d.var == divisor);
+    match (defs.next(), defs.next()) {
+        (
+            Some(Definition {
+                site: DefSite::Instruction { block, index },
+                ..
+            }),
+            None,
+        ) => {
+            let def = &blocks[block].instructions[*index];
+            def.op == OpCode::Const && !matches!(def.value, Some(Value::Int(0 | -1)))
+        }
+        _ => false,
+    }
+}
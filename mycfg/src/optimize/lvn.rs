@@ -1,11 +1,43 @@
+use crate::core::{ArithmeticOp, ComparisonOp, Instruction, LogicOp, OpCode, Type, Value};
+
 // use std::rc::Rc;
 // use std::{collections::HashMap, hash::Hash};
 
-// use crate::core::Value::Int;
-// use crate::core::{Instruction, LogicOp, OpCode};
+/// Key identifying the value an instruction computes, in terms of the value numbers of its
+/// operands.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LVNValue {
+    Const(Type, Value),
+    Op(OpCode, Vec<usize>),
+}
 
-// #[derive(Eq, Hash, PartialEq)]
-// pub struct LVNValue(OpCode, usize, usize);
+impl LVNValue {
+    /// Builds the key of a pure, non-copy instruction whose arguments have value numbers `args`.
+    /// Operands of commutative operations are sorted so that `add a b` and `add b a` share a key.
+    pub fn new(instr: &Instruction, mut args: Vec<usize>) -> Option<LVNValue> {
+        if !instr.is_pure() {
+            return None;
+        }
+        match &instr.op {
+            OpCode::Const => Some(LVNValue::Const(
+                instr.dst_type.clone().unwrap(),
+                instr.value.clone().unwrap(),
+            )),
+            OpCode::Arithmetic(ArithmeticOp::Add)
+            | OpCode::Arithmetic(ArithmeticOp::Mul)
+            | OpCode::Comparison(ComparisonOp::Eq)
+            | OpCode::Logic(LogicOp::And)
+            | OpCode::Logic(LogicOp::Or) => {
+                args.sort();
+                Some(LVNValue::Op(instr.op.clone(), args))
+            }
+            OpCode::Arithmetic(_) | OpCode::Comparison(_) | OpCode::Logic(_) => {
+                Some(LVNValue::Op(instr.op.clone(), args))
+            }
+            _ => None,
+        }
+    }
+}
 
 // pub struct LVN {
 //     pub table: HashMap<Rc<LVNValue>, (usize, String)>,
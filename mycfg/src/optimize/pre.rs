@@ -0,0 +1,426 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::analysis::dataflow::{solve, Analysis, Direction};
+use crate::core::{
+    fresh_name, BasicBlock, ControlOp, Function, Instruction, LabelId, MiscOp, OpCode, Type, VarId,
+};
+use crate::parser::control_flow_graph;
+
+type Exprs = BTreeSet<usize>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Expression {
+    op: OpCode,
+    args: Vec<VarId>,
+}
+
+impl Expression {
+    /// The expression an instruction computes, if partial redundancy elimination may move it.
+    /// Constants and copies are left to other passes and trapping operations are never moved.
+    fn of(instr: &Instruction) -> Option<Expression> {
+        if !instr.is_pure()
+            || instr.may_trap()
+            || instr.op == OpCode::Const
+            || instr.op == OpCode::Misc(MiscOp::Id)
+        {
+            return None;
+        }
+        Some(Expression {
+            op: instr.op.clone(),
+            args: instr.uses().to_vec(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct PreStats {
+    /// Computations added at their latest safe placement.
+    pub inserted: usize,
+    /// Computations turned into copies of an earlier one.
+    pub deleted: usize,
+}
+
+impl fmt::Display for PreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} computation(s) inserted, {} deleted",
+            self.inserted, self.deleted
+        )
+    }
+}
+
+/// Every expression in the function, numbered in the order they first appear.
+struct Expressions {
+    exprs: Vec<Expression>,
+    types: Vec<Type>,
+    index: HashMap<Expression, usize>,
+    /// Expressions reading each variable.
+    readers: HashMap<VarId, Vec<usize>>,
+}
+
+impl Expressions {
+    fn new(func: &Function) -> Expressions {
+        let mut expressions = Expressions {
+            exprs: vec![],
+            types: vec![],
+            index: HashMap::new(),
+            readers: HashMap::new(),
+        };
+        for block in func.blocks.iter() {
+            for instr in block.instructions.iter() {
+                if let Some(expr) = Expression::of(instr) {
+                    if !expressions.index.contains_key(&expr) {
+                        let e = expressions.exprs.len();
+                        for &arg in expr.args.iter() {
+                            let readers = expressions.readers.entry(arg).or_default();
+                            if !readers.contains(&e) {
+                                readers.push(e);
+                            }
+                        }
+                        expressions.index.insert(expr.clone(), e);
+                        expressions.exprs.push(expr);
+                        expressions.types.push(instr.dst_type.clone().unwrap());
+                    }
+                }
+            }
+        }
+        expressions
+    }
+
+    /// Number of the expression `instr` computes, if it's one partial redundancy elimination
+    /// may move.
+    fn of(&self, instr: &Instruction) -> Option<usize> {
+        Expression::of(instr).map(|expr| self.index[&expr])
+    }
+
+    /// Expressions that defining `var` kills.
+    fn killed_by(&self, var: VarId) -> &[usize] {
+        self.readers.get(&var).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Per-block local properties of every expression in the function.
+struct LocalSets {
+    universe: Exprs,
+    /// Expressions computed in the block before any of their operands is redefined there.
+    used: HashMap<LabelId, Exprs>,
+    /// Expressions with an operand defined in the block.
+    killed: HashMap<LabelId, Exprs>,
+}
+
+impl LocalSets {
+    fn new(func: &Function, exprs: &Expressions) -> LocalSets {
+        let mut used: HashMap<LabelId, Exprs> = HashMap::new();
+        let mut killed: HashMap<LabelId, Exprs> = HashMap::new();
+        for block in func.blocks.iter() {
+            let mut block_used = Exprs::new();
+            let mut block_killed = Exprs::new();
+            for instr in block.instructions.iter() {
+                if let Some(index) = exprs.of(instr) {
+                    if !block_killed.contains(&index) {
+                        block_used.insert(index);
+                    }
+                }
+                if let Some(dst) = instr.dst {
+                    block_killed.extend(exprs.killed_by(dst));
+                }
+            }
+            used.insert(block.name, block_used);
+            killed.insert(block.name, block_killed);
+        }
+        LocalSets {
+            universe: (0..exprs.exprs.len()).collect(),
+            used,
+            killed,
+        }
+    }
+}
+
+struct Anticipated<'a> {
+    sets: &'a LocalSets,
+}
+
+impl Analysis for Anticipated<'_> {
+    type Fact = Exprs;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self, _func: &Function) -> Exprs {
+        Exprs::new()
+    }
+
+    fn initial(&self, _func: &Function) -> Exprs {
+        self.sets.universe.clone()
+    }
+
+    fn meet(&self, a: &Exprs, b: &Exprs) -> Exprs {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_block(&self, block: &BasicBlock, out: &Exprs) -> Exprs {
+        let mut result: Exprs = out
+            .difference(&self.sets.killed[&block.name])
+            .cloned()
+            .collect();
+        result.extend(self.sets.used[&block.name].iter());
+        result
+    }
+}
+
+/// Expressions that will be available at a point if every anticipated expression is computed
+/// as early as possible.
+struct WillBeAvailable<'a> {
+    sets: &'a LocalSets,
+    anticipated: &'a HashMap<LabelId, Exprs>,
+}
+
+impl Analysis for WillBeAvailable<'_> {
+    type Fact = Exprs;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _func: &Function) -> Exprs {
+        Exprs::new()
+    }
+
+    fn initial(&self, _func: &Function) -> Exprs {
+        self.sets.universe.clone()
+    }
+
+    fn meet(&self, a: &Exprs, b: &Exprs) -> Exprs {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_block(&self, block: &BasicBlock, input: &Exprs) -> Exprs {
+        self.anticipated[&block.name]
+            .union(input)
+            .filter(|e| !self.sets.killed[&block.name].contains(e))
+            .cloned()
+            .collect()
+    }
+}
+
+struct Postponable<'a> {
+    sets: &'a LocalSets,
+    earliest: &'a HashMap<LabelId, Exprs>,
+}
+
+impl Analysis for Postponable<'_> {
+    type Fact = Exprs;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _func: &Function) -> Exprs {
+        Exprs::new()
+    }
+
+    fn initial(&self, _func: &Function) -> Exprs {
+        self.sets.universe.clone()
+    }
+
+    fn meet(&self, a: &Exprs, b: &Exprs) -> Exprs {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_block(&self, block: &BasicBlock, input: &Exprs) -> Exprs {
+        self.earliest[&block.name]
+            .union(input)
+            .filter(|e| !self.sets.used[&block.name].contains(e))
+            .cloned()
+            .collect()
+    }
+}
+
+struct Used<'a> {
+    sets: &'a LocalSets,
+    latest: &'a HashMap<LabelId, Exprs>,
+}
+
+impl Analysis for Used<'_> {
+    type Fact = Exprs;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self, _func: &Function) -> Exprs {
+        Exprs::new()
+    }
+
+    fn initial(&self, _func: &Function) -> Exprs {
+        Exprs::new()
+    }
+
+    fn meet(&self, a: &Exprs, b: &Exprs) -> Exprs {
+        a.union(b).cloned().collect()
+    }
+
+    fn transfer_block(&self, block: &BasicBlock, out: &Exprs) -> Exprs {
+        self.sets.used[&block.name]
+            .union(out)
+            .filter(|e| !self.latest[&block.name].contains(e))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Function {
+    /// Partial redundancy elimination by lazy code motion. Each expression is computed into a
+    /// temporary at the latest points where it is anticipated on every path, and computations
+    /// made redundant by those placements become copies of the temporary.
+    pub fn partial_redundancy_elimination(&self) -> (Function, PreStats) {
+        let func = self.split_critical_edges();
+        let mut stats = PreStats::default();
+
+        let exprs = Expressions::new(&func);
+        let sets = LocalSets::new(&func, &exprs);
+        let cfg = control_flow_graph(&func);
+
+        let anticipated = solve(&func, &Anticipated { sets: &sets }).block_in;
+        let available = solve(
+            &func,
+            &WillBeAvailable {
+                sets: &sets,
+                anticipated: &anticipated,
+            },
+        )
+        .block_in;
+        let earliest: HashMap<LabelId, Exprs> = func
+            .blocks
+            .iter()
+            .map(|block| {
+                let name = &block.name;
+                let set = anticipated[name].difference(&available[name]);
+                (*name, set.cloned().collect())
+            })
+            .collect();
+        let postponable = solve(
+            &func,
+            &Postponable {
+                sets: &sets,
+                earliest: &earliest,
+            },
+        )
+        .block_in;
+        let latest: HashMap<LabelId, Exprs> = func
+            .blocks
+            .iter()
+            .map(|block| {
+                let name = &block.name;
+                let candidates: Exprs = earliest[name].union(&postponable[name]).cloned().collect();
+                // Placement can't be delayed past this block if some successor can't take it
+                let set = candidates
+                    .iter()
+                    .filter(|e| {
+                        sets.used[name].contains(e)
+                            || cfg[name].iter().any(|succ| {
+                                !earliest[succ].contains(e) && !postponable[succ].contains(e)
+                            })
+                    })
+                    .cloned()
+                    .collect();
+                (*name, set)
+            })
+            .collect();
+        let used = solve(
+            &func,
+            &Used {
+                sets: &sets,
+                latest: &latest,
+            },
+        )
+        .block_out;
+
+        let mut temps: HashMap<usize, VarId> = HashMap::new();
+        let mut taken: HashSet<VarId> = func.variables();
+        let mut temp = |e: usize| {
+            *temps
+                .entry(e)
+                .or_insert_with(|| fresh_name(&format!("pre.{}", e), &mut taken))
+        };
+        let mut result = func.clone();
+        for block in result.blocks.iter_mut() {
+            let name = block.name;
+            let mut instructions: Vec<Instruction> = vec![];
+            for &e in latest[&name].intersection(&used[&name]) {
+                instructions.push(Instruction::compute(
+                    exprs.exprs[e].op.clone(),
+                    temp(e),
+                    exprs.types[e].clone(),
+                    exprs.exprs[e].args.clone(),
+                ));
+                stats.inserted += 1;
+            }
+
+            // Only the upward-exposed computation of each expression is replaced
+            let mut killed = Exprs::new();
+            for instr in block.instructions.iter() {
+                let replace = exprs.of(instr).filter(|e| {
+                    !killed.contains(e) && (!latest[&name].contains(e) || used[&name].contains(e))
+                });
+                if let Some(dst) = instr.dst {
+                    killed.extend(exprs.killed_by(dst));
+                }
+                match replace {
+                    Some(e) => {
+                        instructions.push(Instruction {
+                            pos: instr.pos,
+                            ..Instruction::compute(
+                                OpCode::Misc(MiscOp::Id),
+                                instr.dst.unwrap(),
+                                instr.dst_type.clone().unwrap(),
+                                vec![temp(e)],
+                            )
+                        });
+                        stats.deleted += 1;
+                    }
+                    None => instructions.push(instr.clone()),
+                }
+            }
+            block.instructions = instructions;
+        }
+
+        (remove_unused_splits(self, &result), stats)
+    }
+}
+
+/// Drops blocks added by critical edge splitting that received no code, pointing their
+/// predecessor's branch back at the original target.
+fn remove_unused_splits(original: &Function, func: &Function) -> Function {
+    let jmp = OpCode::Control(ControlOp::Jmp);
+    let original: HashSet<LabelId> = original.blocks.iter().map(|block| block.name).collect();
+    let empty: HashMap<LabelId, LabelId> = func
+        .blocks
+        .iter()
+        .filter(|block| !original.contains(&block.name))
+        .filter_map(|block| match block.instructions.as_slice() {
+            [only] if only.op == jmp => Some((block.name, only.labels.as_ref().unwrap()[0])),
+            _ => None,
+        })
+        .collect();
+
+    let mut result = func.clone();
+    result
+        .blocks
+        .retain(|block| !empty.contains_key(&block.name));
+    for block in result.blocks.iter_mut() {
+        if let Some(last) = block.instructions.last_mut() {
+            if let Some(labels) = last.labels.as_mut() {
+                for label in labels.iter_mut() {
+                    if let Some(&target) = empty.get(label) {
+                        *label = target;
+                    }
+                }
+            }
+        }
+    }
+    result
+}
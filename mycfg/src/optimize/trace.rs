@@ -0,0 +1,284 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::analysis::dataflow::{solve, LiveVariables};
+use crate::analysis::LoopForest;
+use crate::core::{
+    fresh_name, ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, Function, Instruction, LabelId,
+    LogicOp, MiscOp, OpCode, SpeculationOp, Type, Value, VarId,
+};
+
+impl Function {
+    /// Straightens `path`, the blocks one call of this function ran through starting at its
+    /// entry, into a new entry block that runs them speculatively. Each branch on the path
+    /// becomes a guard that rolls back and restarts at the original entry when execution would
+    /// leave the path. The trace ends before the first block with side effects, a return or
+    /// speculation of its own, is optimized as a single block, and then commits and jumps to
+    /// where the path continues, which is outside every loop or at a loop header. Functions in
+    /// SSA form, and traces that wouldn't run fewer instructions than the code they replace, are
+    /// returned unchanged.
+    pub fn speculate_trace(&self, path: &[LabelId]) -> Function {
+        let entry = match self.blocks.first() {
+            Some(entry) => entry.name,
+            None => return self.clone(),
+        };
+        let has_phis = self
+            .blocks
+            .iter()
+            .any(|block| block.instructions.iter().any(|i| i.op == OpCode::Phi));
+        if path.first() != Some(&entry) || has_phis {
+            return self.clone();
+        }
+
+        let mut taken: HashSet<VarId> = self.variables();
+        // Negations of the same condition can share a variable since the trace is straight-line
+        let mut negations: HashMap<VarId, VarId> = HashMap::new();
+        // Jumping into a loop anywhere but its header would make the loop irreducible, so the
+        // trace is cut back to the last point where it leaves off outside loops or at a header
+        let forest = LoopForest::new(self);
+        let can_resume_at = |block: LabelId| {
+            forest
+                .loops
+                .iter()
+                .all(|l| !l.contains(block) || l.header == block)
+        };
+        let mut trace: Vec<Instruction> = vec![];
+        let mut resume = entry;
+        let mut cut = 0;
+        // Instructions the original code runs along the path, in total and up to the cut
+        let (mut executed, mut replaced) = (0, 0);
+        let indices: HashMap<LabelId, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.name, i))
+            .collect();
+        for (k, name) in path.iter().enumerate() {
+            let index = match indices.get(name) {
+                Some(&index) => index,
+                None => break,
+            };
+            let block = &self.blocks[index];
+            if block.instructions.iter().any(has_side_effects) {
+                break;
+            }
+            let next = path.get(k + 1);
+            let (body, succ): (&[Instruction], LabelId) = match block.instructions.last() {
+                Some(last) if last.op == OpCode::Control(ControlOp::Br) => {
+                    let labels = last.labels.as_ref().unwrap();
+                    let next = match next {
+                        Some(next) if labels.contains(next) => next,
+                        _ => break,
+                    };
+                    let body = &block.instructions[..block.instructions.len() - 1];
+                    (body, *next)
+                }
+                Some(last) if last.op == OpCode::Control(ControlOp::Jmp) => {
+                    let body = &block.instructions[..block.instructions.len() - 1];
+                    (body, last.labels.as_ref().unwrap()[0])
+                }
+                // Falling off the end of the function returns
+                _ => match self.blocks.get(index + 1) {
+                    Some(following) => (&block.instructions[..], following.name),
+                    None => break,
+                },
+            };
+            if next.is_some_and(|next| *next != succ) {
+                break;
+            }
+            trace.extend(body.iter().cloned());
+            executed += block.instructions.len();
+            if let Some(last) = block.instructions.last() {
+                if last.op == OpCode::Control(ControlOp::Br) {
+                    let labels = last.labels.as_ref().unwrap();
+                    let cond = last.uses()[0];
+                    if labels[0] == labels[1] {
+                        // Both ways stay on the path
+                    } else if labels[0] == succ {
+                        trace.push(guard(cond, entry));
+                    } else {
+                        let negated = *negations
+                            .entry(cond)
+                            .or_insert_with(|| fresh_name(&format!("{}.not", cond), &mut taken));
+                        trace.push(Instruction::compute(
+                            OpCode::Logic(LogicOp::Not),
+                            negated,
+                            Type::Bool,
+                            vec![cond],
+                        ));
+                        trace.push(guard(negated, entry));
+                    }
+                }
+            }
+            if can_resume_at(succ) {
+                resume = succ;
+                cut = trace.len();
+                replaced = executed;
+            }
+        }
+        trace.truncate(cut);
+        if trace.is_empty() {
+            return self.clone();
+        }
+
+        let live = solve(self, &LiveVariables);
+        let mut instructions = vec![speculation(SpeculationOp::Speculate)];
+        instructions.extend(optimize_trace(trace, &live.block_in[&resume]));
+        instructions.push(speculation(SpeculationOp::Commit));
+        instructions.push(Instruction::jmp(resume));
+        if instructions.len() >= replaced {
+            return self.clone();
+        }
+        let mut func = self.clone();
+        func.blocks.insert(
+            0,
+            BasicBlock {
+                name: self.fresh_block_name("trace"),
+                instructions,
+                pos: None,
+                synthetic: true,
+            },
+        );
+        func
+    }
+}
+
+/// Whether running `instr` can be observed outside the function or can't be undone by a guard.
+fn has_side_effects(instr: &Instruction) -> bool {
+    matches!(
+        instr.op,
+        OpCode::Misc(MiscOp::Print)
+            | OpCode::Control(ControlOp::Call)
+            | OpCode::Control(ControlOp::Ret)
+            | OpCode::Speculation(_)
+    )
+}
+
+fn speculation(op: SpeculationOp) -> Instruction {
+    Instruction {
+        op: OpCode::Speculation(op),
+        dst: None,
+        dst_type: None,
+        args: None,
+        funcs: None,
+        labels: None,
+        value: None,
+        pos: None,
+        extensions: None,
+    }
+}
+
+fn guard(cond: VarId, label: LabelId) -> Instruction {
+    Instruction {
+        args: Some([cond].into()),
+        labels: Some([label].into()),
+        ..speculation(SpeculationOp::Guard)
+    }
+}
+
+/// Folds constants through the straight-line trace, drops guards that always pass and then
+/// removes instructions whose results are neither used later in the trace nor in `live_out`.
+/// A failing guard restores every variable, so values computed on the trace only matter to the
+/// rest of the trace and to the code it commits to.
+fn optimize_trace(trace: Vec<Instruction>, live_out: &BTreeSet<VarId>) -> Vec<Instruction> {
+    let mut constants: HashMap<VarId, Value> = HashMap::new();
+    let mut folded: Vec<Instruction> = vec![];
+    for instr in trace {
+        if instr.op == OpCode::Speculation(SpeculationOp::Guard)
+            && constants.get(&instr.uses()[0]) == Some(&Value::Bool(true))
+        {
+            continue;
+        }
+        let value = fold(&instr, &constants);
+        let instr = match (&value, &instr.dst) {
+            (Some(value), Some(dst)) => Instruction {
+                op: OpCode::Const,
+                dst: Some(*dst),
+                dst_type: instr.dst_type.clone(),
+                args: None,
+                funcs: None,
+                labels: None,
+                value: Some(value.clone()),
+                pos: instr.pos,
+                extensions: instr.extensions.clone(),
+            },
+            _ => instr,
+        };
+        if let Some(dst) = &instr.dst {
+            match value {
+                Some(value) => constants.insert(*dst, value),
+                None => constants.remove(dst),
+            };
+        }
+        folded.push(instr);
+    }
+
+    let mut live = live_out.clone();
+    let mut kept: Vec<Instruction> = vec![];
+    for instr in folded.into_iter().rev() {
+        let needed = match &instr.dst {
+            Some(dst) => live.contains(dst) || !instr.is_pure() || instr.may_trap(),
+            None => instr.op != OpCode::Misc(MiscOp::Nop),
+        };
+        if !needed {
+            continue;
+        }
+        if let Some(dst) = &instr.dst {
+            live.remove(dst);
+        }
+        live.extend(instr.uses().iter().cloned());
+        kept.push(instr);
+    }
+    kept.reverse();
+    kept
+}
+
+/// The value `instr` computes when all its arguments are known constants.
+fn fold(instr: &Instruction, constants: &HashMap<VarId, Value>) -> Option<Value> {
+    if instr.op == OpCode::Const {
+        return instr.value.clone();
+    }
+    if !instr.is_pure() {
+        return None;
+    }
+    let args: Vec<&Value> = instr
+        .uses()
+        .iter()
+        .map(|arg| constants.get(arg))
+        .collect::<Option<_>>()?;
+    let int = |i: usize| match args.get(i) {
+        Some(Value::Int(n)) => Some(*n),
+        _ => None,
+    };
+    let bool = |i: usize| match args.get(i) {
+        Some(Value::Bool(b)) => Some(*b),
+        _ => None,
+    };
+    Some(match &instr.op {
+        OpCode::Arithmetic(aop) => {
+            let (a, b) = (int(0)?, int(1)?);
+            Value::Int(match aop {
+                ArithmeticOp::Add => a.wrapping_add(b),
+                ArithmeticOp::Sub => a.wrapping_sub(b),
+                ArithmeticOp::Mul => a.wrapping_mul(b),
+                // Leave division by zero to fail at run time
+                ArithmeticOp::Div if b == 0 => return None,
+                ArithmeticOp::Div => a.wrapping_div(b),
+            })
+        }
+        OpCode::Comparison(cop) => {
+            let (a, b) = (int(0)?, int(1)?);
+            Value::Bool(match cop {
+                ComparisonOp::Eq => a == b,
+                ComparisonOp::Lt => a < b,
+                ComparisonOp::Gt => a > b,
+                ComparisonOp::Le => a <= b,
+                ComparisonOp::Ge => a >= b,
+            })
+        }
+        OpCode::Logic(LogicOp::Not) => Value::Bool(!bool(0)?),
+        OpCode::Logic(LogicOp::And) => Value::Bool(bool(0)? && bool(1)?),
+        OpCode::Logic(LogicOp::Or) => Value::Bool(bool(0)? || bool(1)?),
+        OpCode::Misc(MiscOp::Id) => args[0].clone(),
+        _ => return None,
+    })
+}
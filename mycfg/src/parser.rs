@@ -1,52 +1,91 @@
 mod blockgen;
+mod stream;
 
 use std::collections::HashMap;
+use std::error::Error;
 use std::vec;
 
 use json;
 use json::JsonValue;
 
 use crate::core::{
-    ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, Function, Instruction, LogicOp, MiscOp,
-    OpCode, Program, Type, Value,
+    at, ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, Extensions, FuncId, Function,
+    Instruction, LabelId, LogicOp, MiscOp, OpCode, Operands, Position, Program, SpeculationOp,
+    Type, Value, VarId,
 };
 use crate::parser::blockgen::BlockGen;
 
-const TERMINATORS: [OpCode; 3] = [
-    OpCode::Control(ControlOp::Jmp),
-    OpCode::Control(ControlOp::Br),
-    OpCode::Control(ControlOp::Ret),
+pub use crate::parser::stream::read_program;
+
+/// Fields of instructions and functions that are parsed rather than kept as extensions.
+const INSTRUCTION_FIELDS: [&str; 8] = [
+    "op", "dest", "type", "args", "funcs", "labels", "value", "pos",
 ];
+const FUNCTION_FIELDS: [&str; 4] = ["name", "args", "type", "instrs"];
 
-fn parse_function_args(json: &JsonValue) -> Vec<(String, Type)> {
-    if json.has_key("args") {
-        json["args"]
-            .members()
-            .map(|arg| -> (String, Type) {
-                (
-                    String::from(arg["name"].as_str().unwrap()),
-                    parse_type(&arg["type"]),
-                )
-            })
-            .collect()
-    } else {
-        vec![]
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn parse_function_args(json: &JsonValue) -> Result<Vec<(VarId, Type)>> {
+    json["args"]
+        .members()
+        .map(|arg| -> Result<(VarId, Type)> {
+            Ok((
+                VarId::new(parse_str(&arg["name"], "an argument name", None)?),
+                parse_type(&arg["type"], None)?,
+            ))
+        })
+        .collect()
+}
+
+/// The `pos` that `bril2json -p` adds to instructions and labels.
+fn parse_position(json: &JsonValue) -> Option<Position> {
+    let pos = &json["pos"];
+    match (pos["row"].as_usize(), pos["col"].as_usize()) {
+        (Some(row), Some(col)) => Some(Position { row, col }),
+        _ => None,
     }
 }
 
-fn parse_type(json: &JsonValue) -> Type {
-    if json.as_str().unwrap() == "int" {
-        Type::Int
-    } else if json.as_str().unwrap() == "bool" {
-        Type::Bool
+/// Every field of the object `json` that isn't one of `known`.
+fn parse_extensions(json: &JsonValue, known: &[&str]) -> Extensions {
+    json.entries()
+        .filter(|(key, _)| !known.contains(key))
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect()
+}
+
+/// The extensions of an instruction, which are only allocated if there are any.
+pub(crate) fn instruction_extensions(extensions: Extensions) -> Option<Box<Extensions>> {
+    if extensions.is_empty() {
+        None
     } else {
-        panic!("Invalid json passed to resolveType: {:#?}", json);
+        Some(Box::new(extensions))
     }
 }
 
-fn parse_op_code(json: &JsonValue) -> OpCode {
-    match json.as_str().unwrap() {
+/// The string `json`, where `what` says what it should have been.
+fn parse_str<'a>(json: &'a JsonValue, what: &str, pos: Option<Position>) -> Result<&'a str> {
+    json.as_str()
+        .ok_or_else(|| format!("expected {}{}", what, at(pos)).into())
+}
+
+fn type_named(name: &str) -> Option<Type> {
+    match name {
+        "int" => Some(Type::Int),
+        "bool" => Some(Type::Bool),
+        _ => None,
+    }
+}
+
+fn parse_type(json: &JsonValue, pos: Option<Position>) -> Result<Type> {
+    let name = parse_str(json, "a type", pos)?;
+    type_named(name).ok_or_else(|| format!("unknown type {}{}", name, at(pos)).into())
+}
+
+fn op_code_named(name: &str) -> Option<OpCode> {
+    let op = match name {
         "const" => OpCode::Const,
+        "phi" => OpCode::Phi,
         "add" => OpCode::Arithmetic(ArithmeticOp::Add),
         "sub" => OpCode::Arithmetic(ArithmeticOp::Sub),
         "mul" => OpCode::Arithmetic(ArithmeticOp::Mul),
@@ -66,116 +105,173 @@ fn parse_op_code(json: &JsonValue) -> OpCode {
         "id" => OpCode::Misc(MiscOp::Id),
         "print" => OpCode::Misc(MiscOp::Print),
         "nop" => OpCode::Misc(MiscOp::Nop),
-        _ => {
-            panic!("Invalid JSON passed to parse_op_code: {}", json);
-        }
-    }
+        "speculate" => OpCode::Speculation(SpeculationOp::Speculate),
+        "commit" => OpCode::Speculation(SpeculationOp::Commit),
+        "guard" => OpCode::Speculation(SpeculationOp::Guard),
+        _ => return None,
+    };
+    Some(op)
 }
 
-fn parse_json_str_arr(json: &JsonValue) -> Vec<String> {
+fn parse_op_code(json: &JsonValue, pos: Option<Position>) -> Result<OpCode> {
+    let name = parse_str(json, "an operation", pos)?;
+    op_code_named(name).ok_or_else(|| format!("unknown operation {}{}", name, at(pos)).into())
+}
+
+fn parse_json_str_arr<T: for<'a> From<&'a str> + Copy>(
+    json: &JsonValue,
+    what: &str,
+    pos: Option<Position>,
+) -> Result<Operands<T>> {
     json.members()
-        .map(|member| -> String { String::from(member.as_str().unwrap()) })
+        .map(|member| -> Result<T> { Ok(T::from(parse_str(member, what, pos)?)) })
         .collect()
 }
 
-fn parse_instruction(json: &JsonValue) -> Instruction {
-    Instruction {
-        op: parse_op_code(&json["op"]),
+fn parse_instruction(json: &JsonValue) -> Result<Instruction> {
+    let pos = parse_position(json);
+    Ok(Instruction {
+        op: parse_op_code(&json["op"], pos)?,
         dst: if json.has_key("dest") {
-            Some(String::from(json["dest"].as_str().unwrap()))
+            Some(VarId::new(parse_str(&json["dest"], "a destination", pos)?))
         } else {
             None
         },
         dst_type: if json.has_key("type") {
-            Some(parse_type(&json["type"]))
+            Some(parse_type(&json["type"], pos)?)
         } else {
             None
         },
         args: if json.has_key("args") {
-            Some(parse_json_str_arr(&json["args"]))
+            Some(parse_json_str_arr(&json["args"], "a variable", pos)?)
         } else {
             None
         },
         funcs: if json.has_key("funcs") {
-            Some(parse_json_str_arr(&json["funcs"]))
+            Some(parse_json_str_arr(&json["funcs"], "a function name", pos)?)
         } else {
             None
         },
         labels: if json.has_key("labels") {
-            Some(parse_json_str_arr(&json["labels"]))
+            Some(parse_json_str_arr(&json["labels"], "a label", pos)?)
         } else {
             None
         },
         value: if json.has_key("value") {
-            match json["value"].is_boolean() {
-                true => Some(Value::Bool(json["value"].as_bool().unwrap())),
-                false => Some(Value::Int(json["value"].as_isize().unwrap())),
+            match json["value"].as_bool() {
+                Some(value) => Some(Value::Bool(value)),
+                None => Some(Value::Int(
+                    parse_integer(&json["value"])
+                        .ok_or_else(|| format!("expected an integer{}", at(pos)))?,
+                )),
             }
         } else {
             None
         },
+        pos,
+        extensions: instruction_extensions(parse_extensions(json, &INSTRUCTION_FIELDS)),
+    })
+}
+
+/// Like `as_isize`, which overflows on `i64::MIN`.
+fn parse_integer(json: &JsonValue) -> Option<isize> {
+    match json.as_number()?.as_parts() {
+        (false, mantissa, 0) if mantissa == isize::MIN.unsigned_abs() as u64 => Some(isize::MIN),
+        _ => json.as_isize(),
     }
 }
 
-fn parse_basic_blocks(json: &JsonValue) -> Vec<BasicBlock> {
-    let mut block_gen: BlockGen = BlockGen {
-        blocks: vec![],
-        instructions: vec![],
-        name: String::new(),
-    };
+fn parse_basic_blocks(json: &JsonValue) -> Result<Vec<BasicBlock>> {
+    let mut block_gen = BlockGen::new();
 
     for op in json.members() {
         if op.has_key("op") {
-            let instr: Instruction = parse_instruction(&op);
-            block_gen.push_instruction(instr.clone());
-            if TERMINATORS.contains(&instr.op) {
-                block_gen.finalize_block();
-            }
+            block_gen.add_instruction(parse_instruction(op)?);
         } else if op.has_key("label") {
-            block_gen.finalize_block();
-            block_gen.set_cur_name(String::from(op["label"].as_str().unwrap()));
+            let pos = parse_position(op);
+            block_gen.add_label(LabelId::new(parse_str(&op["label"], "a label", pos)?), pos);
         }
     }
     block_gen.finalize_block();
 
-    block_gen.yield_blocks()
+    Ok(block_gen.yield_blocks())
 }
 
-fn parse_function(json: &JsonValue) -> Function {
-    Function {
-        name: String::from(json["name"].as_str().unwrap()),
-        args: parse_function_args(&json),
+fn parse_function(json: &JsonValue) -> Result<Function> {
+    let mut func = Function {
+        name: FuncId::new(parse_str(&json["name"], "a function name", None)?),
+        args: parse_function_args(json)?,
         ret_type: if json.has_key("type") {
-            Some(parse_type(&json["type"]))
+            Some(parse_type(&json["type"], None)?)
         } else {
             None
         },
-        blocks: parse_basic_blocks(&json["instrs"]),
+        blocks: parse_basic_blocks(&json["instrs"])?,
+        extensions: parse_extensions(json, &FUNCTION_FIELDS),
+    };
+    rename_synthetic_blocks(&mut func);
+    Ok(func)
+}
+
+/// Renames blocks the parser named that have the same name as a label further down.
+fn rename_synthetic_blocks(func: &mut Function) {
+    for i in 0..func.blocks.len() {
+        let block = &func.blocks[i];
+        let clashes = block.synthetic
+            && func
+                .blocks
+                .iter()
+                .any(|other| !other.synthetic && other.name == block.name);
+        if clashes {
+            func.blocks[i].name = func.fresh_block_name(&block.name.name());
+        }
     }
 }
 
-pub fn parse_program(json: &JsonValue) -> Program {
-    Program {
+/// Builds the program from Bril JSON that's already been parsed. Errors point at the `pos` of
+/// the instruction where it went wrong, if it has one.
+pub fn parse_program(json: &JsonValue) -> Result<Program> {
+    Ok(Program {
         functions: json["functions"]
             .members()
-            .map(|func| -> Function { parse_function(func) })
-            .collect(),
-    }
+            .map(parse_function)
+            .collect::<Result<_>>()?,
+    })
 }
 
-pub fn control_flow_graph(func: &Function) -> HashMap<String, Vec<String>> {
-    let mut cfg: HashMap<String, Vec<String>> = HashMap::new();
+pub fn control_flow_graph(func: &Function) -> HashMap<LabelId, Vec<LabelId>> {
+    let mut cfg: HashMap<LabelId, Vec<LabelId>> = HashMap::new();
 
-    for i in 0..func.blocks.len() - 1 {
-        let block = &func.blocks[i];
-        let last = &block.instructions[block.instructions.len() - 1];
-        if last.op == OpCode::Control(ControlOp::Jmp) || last.op == OpCode::Control(ControlOp::Br) {
-            cfg.insert(block.name.clone(), last.labels.as_ref().unwrap().clone());
-        } else {
-            cfg.insert(block.name.clone(), vec![func.blocks[i + 1].name.clone()]);
+    for (i, block) in func.blocks.iter().enumerate() {
+        let fallthrough = || -> Vec<LabelId> {
+            match func.blocks.get(i + 1) {
+                Some(next) => vec![next.name],
+                None => vec![],
+            }
+        };
+        let mut succs = match block.instructions.last() {
+            Some(last) => match last.op {
+                OpCode::Control(ControlOp::Jmp) | OpCode::Control(ControlOp::Br) => {
+                    let mut labels = last.labels.as_ref().unwrap().to_vec();
+                    labels.dedup();
+                    labels
+                }
+                OpCode::Control(ControlOp::Ret) => vec![],
+                _ => fallthrough(),
+            },
+            None => fallthrough(),
+        };
+        // A failing guard leaves the block from the middle
+        for instr in block.instructions.iter() {
+            if instr.op == OpCode::Speculation(SpeculationOp::Guard) {
+                let target = instr.labels.as_ref().unwrap()[0];
+                if !succs.contains(&target) {
+                    succs.push(target);
+                }
+            }
         }
+        cfg.insert(block.name, succs);
     }
-    cfg.insert(func.blocks[func.blocks.len() - 1].name.clone(), vec![]);
 
-    return cfg;
+    cfg
 }
@@ -1,24 +1,50 @@
-use crate::core::{BasicBlock, Instruction};
+use crate::core::{BasicBlock, Instruction, LabelId, Position};
 
+/// Splits the instructions and labels of a function into basic blocks as they come.
 pub struct BlockGen {
     pub blocks: Vec<BasicBlock>,
     pub instructions: Vec<Instruction>,
-    pub name: String,
+    pub name: Option<LabelId>,
+    /// Position of the current block's label.
+    pub pos: Option<Position>,
 }
 
 impl BlockGen {
+    pub fn new() -> BlockGen {
+        BlockGen {
+            blocks: vec![],
+            instructions: vec![],
+            name: None,
+            pos: None,
+        }
+    }
+
+    /// Adds an instruction to the current block, which ends after a terminator.
+    pub fn add_instruction(&mut self, instr: Instruction) {
+        let is_terminator = instr.is_terminator();
+        self.push_instruction(instr);
+        if is_terminator {
+            self.finalize_block();
+        }
+    }
+
+    /// Starts a block with the label `name` at `pos`.
+    pub fn add_label(&mut self, name: LabelId, pos: Option<Position>) {
+        self.finalize_block();
+        self.set_cur_name(name);
+        self.pos = pos;
+    }
+
     pub fn finalize_block(&mut self) {
-        if !self.instructions.is_empty() {
+        // A labelled block is kept even when empty since branches may target it
+        if !self.instructions.is_empty() || self.name.is_some() {
+            let name = self.name.take();
             self.blocks.push(BasicBlock {
-                name: if self.name.is_empty() {
-                    format!("b{}", self.blocks.len())
-                } else {
-                    self.name.clone()
-                },
-                instructions: self.instructions.clone(),
+                name: name.unwrap_or_else(|| LabelId::new(&format!("b{}", self.blocks.len()))),
+                instructions: std::mem::take(&mut self.instructions),
+                pos: self.pos.take(),
+                synthetic: name.is_none(),
             });
-            self.instructions.clear();
-            self.name.clear()
         }
     }
 
@@ -26,11 +52,11 @@ impl BlockGen {
         self.instructions.push(instr);
     }
 
-    pub fn set_cur_name(&mut self, name: String) {
-        self.name = name;
+    pub fn set_cur_name(&mut self, name: LabelId) {
+        self.name = Some(name);
     }
 
-    pub fn yield_blocks(&self) -> Vec<BasicBlock> {
-        self.blocks.clone()
+    pub fn yield_blocks(self) -> Vec<BasicBlock> {
+        self.blocks
     }
 }
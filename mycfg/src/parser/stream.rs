@@ -0,0 +1,455 @@
+use std::error::Error;
+use std::io::{self, BufReader, Read};
+
+use json::JsonValue;
+
+use crate::core::{
+    Extensions, FuncId, Function, Instruction, LabelId, OpCode, Operands, Position, Program, Type,
+    Value, VarId,
+};
+use crate::parser::blockgen::BlockGen;
+use crate::parser::{instruction_extensions, op_code_named, rename_synthetic_blocks, type_named};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Reads a program in Bril JSON from `input` as it comes, without holding the whole text or a
+/// `JsonValue` tree of it: each instruction is added to its function's blocks as soon as it's
+/// read. Only the values of fields kept as extensions are built as `JsonValue`s. Gives the same
+/// program as `parse_program`, and errors point at the row and column of the input where it
+/// went wrong.
+pub fn read_program(input: impl Read) -> Result<Program> {
+    let mut reader = Reader {
+        bytes: BufReader::new(input).bytes(),
+        peeked: None,
+        row: 1,
+        col: 1,
+        scratch: vec![],
+        keys: vec![],
+    };
+    let prog = reader.program()?;
+    reader.skip_whitespace()?;
+    match reader.peek()? {
+        Some(_) => Err(reader.error("unexpected text after the program")),
+        None => Ok(prog),
+    }
+}
+
+/// A JSON tokenizer over a stream of bytes that knows the shape of a Bril program.
+struct Reader<R: Read> {
+    bytes: io::Bytes<BufReader<R>>,
+    peeked: Option<u8>,
+    /// Position of the next byte.
+    row: usize,
+    col: usize,
+    /// Contents of the string or number being read.
+    scratch: Vec<u8>,
+    /// Buffers for the key of each object being read, innermost last, kept for the next object
+    /// at the same depth rather than allocated for every key.
+    keys: Vec<String>,
+}
+
+/// An instruction or label object, before it's known which one it is.
+#[derive(Default)]
+struct Entry {
+    op: Option<OpCode>,
+    dst: Option<VarId>,
+    dst_type: Option<Type>,
+    args: Option<Vec<VarId>>,
+    funcs: Option<Vec<FuncId>>,
+    labels: Option<Vec<LabelId>>,
+    value: Option<Value>,
+    pos: Option<Position>,
+    label: Option<LabelId>,
+    extensions: Extensions,
+}
+
+impl<R: Read> Reader<R> {
+    fn error(&self, message: &str) -> Box<dyn Error> {
+        format!("{} at {}:{}", message, self.row, self.col).into()
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = self.bytes.next().transpose()?;
+        }
+        Ok(self.peeked)
+    }
+
+    fn bump(&mut self) -> Result<Option<u8>> {
+        let byte = self.peek()?;
+        self.peeked = None;
+        match byte {
+            Some(b'\n') => {
+                self.row += 1;
+                self.col = 1;
+            }
+            // Continuation bytes of UTF-8 characters don't start a column
+            Some(byte) if byte & 0xC0 != 0x80 => self.col += 1,
+            _ => {}
+        }
+        Ok(byte)
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek()? {
+            self.bump()?;
+        }
+        Ok(())
+    }
+
+    /// Skips `byte` if it comes next.
+    fn eat(&mut self, byte: u8) -> Result<bool> {
+        self.skip_whitespace()?;
+        if self.peek()? == Some(byte) {
+            self.bump()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if !self.eat(byte)? {
+            return Err(self.error(&format!("expected `{}`", byte as char)));
+        }
+        Ok(())
+    }
+
+    /// Calls `field` with each key of an object, which has to read its value.
+    fn object(&mut self, mut field: impl FnMut(&mut Self, &str) -> Result<()>) -> Result<()> {
+        self.expect(b'{')?;
+        if self.eat(b'}')? {
+            return Ok(());
+        }
+        let mut key = self.keys.pop().unwrap_or_default();
+        let result = self.fields(&mut key, &mut field);
+        self.keys.push(key);
+        result
+    }
+
+    fn fields(
+        &mut self,
+        key: &mut String,
+        field: &mut impl FnMut(&mut Self, &str) -> Result<()>,
+    ) -> Result<()> {
+        loop {
+            self.string()?;
+            key.clear();
+            key.push_str(self.scratch_str()?);
+            self.expect(b':')?;
+            field(self, key)?;
+            if !self.eat(b',')? {
+                return self.expect(b'}');
+            }
+        }
+    }
+
+    /// Calls `element` to read each element of an array.
+    fn array(&mut self, mut element: impl FnMut(&mut Self) -> Result<()>) -> Result<()> {
+        self.expect(b'[')?;
+        if self.eat(b']')? {
+            return Ok(());
+        }
+        loop {
+            element(self)?;
+            if !self.eat(b',')? {
+                return self.expect(b']');
+            }
+        }
+    }
+
+    /// Reads a string into `scratch`.
+    fn string(&mut self) -> Result<()> {
+        self.expect(b'"')?;
+        self.scratch.clear();
+        loop {
+            match self.bump()? {
+                Some(b'"') => return Ok(()),
+                Some(b'\\') => {
+                    let escaped = match self.bump()? {
+                        Some(b'u') => self.unicode_escape()?,
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(byte @ (b'"' | b'\\' | b'/')) => byte as char,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buffer = [0; 4];
+                    let encoded = escaped.encode_utf8(&mut buffer);
+                    self.scratch.extend_from_slice(encoded.as_bytes());
+                }
+                Some(byte) => self.scratch.push(byte),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, which takes two for characters outside the BMP.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.bump()? != Some(b'\\') || self.bump()? != Some(b'u') {
+                return Err(self.error("unpaired surrogate"));
+            }
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid character escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .bump()?
+                .and_then(|byte| (byte as char).to_digit(16))
+                .ok_or_else(|| self.error("invalid character escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn scratch_str(&self) -> Result<&str> {
+        std::str::from_utf8(&self.scratch).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// Reads the name of a variable, label or function.
+    fn name<T: for<'a> From<&'a str>>(&mut self) -> Result<T> {
+        self.skip_whitespace()?;
+        self.string()?;
+        Ok(T::from(self.scratch_str()?))
+    }
+
+    fn names<T: for<'a> From<&'a str>>(&mut self) -> Result<Vec<T>> {
+        let mut names = vec![];
+        self.array(|reader| {
+            names.push(reader.name()?);
+            Ok(())
+        })?;
+        Ok(names)
+    }
+
+    fn ty(&mut self) -> Result<Type> {
+        self.skip_whitespace()?;
+        self.string()?;
+        let name = self.scratch_str()?;
+        type_named(name).ok_or_else(|| self.error(&format!("unknown type {}", name)))
+    }
+
+    /// Reads the text of a number into `scratch`.
+    fn number(&mut self) -> Result<()> {
+        self.skip_whitespace()?;
+        self.scratch.clear();
+        while let Some(byte @ (b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')) = self.peek()? {
+            self.scratch.push(byte);
+            self.bump()?;
+        }
+        if self.scratch.is_empty() {
+            return Err(self.error("expected a value"));
+        }
+        Ok(())
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        self.number()?;
+        self.scratch_str()?
+            .parse()
+            .map_err(|_| self.error("expected an integer"))
+    }
+
+    /// Reads `true`, `false` or `null`.
+    fn keyword(&mut self) -> Result<JsonValue> {
+        self.skip_whitespace()?;
+        let mut word = String::new();
+        while let Some(byte @ b'a'..=b'z') = self.peek()? {
+            word.push(byte as char);
+            self.bump()?;
+        }
+        match word.as_str() {
+            "true" => Ok(true.into()),
+            "false" => Ok(false.into()),
+            "null" => Ok(JsonValue::Null),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Reads any JSON value.
+    fn value(&mut self) -> Result<JsonValue> {
+        self.skip_whitespace()?;
+        match self.peek()? {
+            Some(b'{') => {
+                let mut object = JsonValue::new_object();
+                self.object(|reader, key| {
+                    object[key] = reader.value()?;
+                    Ok(())
+                })?;
+                Ok(object)
+            }
+            Some(b'[') => {
+                let mut array = JsonValue::new_array();
+                self.array(|reader| {
+                    array.push(reader.value()?)?;
+                    Ok(())
+                })?;
+                Ok(array)
+            }
+            Some(b'"') => {
+                self.string()?;
+                Ok(self.scratch_str()?.into())
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                self.number()?;
+                let text = self.scratch_str()?;
+                match (text.parse::<i64>(), text.parse::<f64>()) {
+                    (Ok(n), _) => Ok(n.into()),
+                    (_, Ok(x)) => Ok(x.into()),
+                    _ => Err(self.error("invalid number")),
+                }
+            }
+            _ => self.keyword(),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program> {
+        let mut functions = vec![];
+        self.object(|reader, key| match key {
+            "functions" => reader.array(|reader| {
+                functions.push(reader.function()?);
+                Ok(())
+            }),
+            _ => reader.value().map(drop),
+        })?;
+        Ok(Program { functions })
+    }
+
+    fn function(&mut self) -> Result<Function> {
+        let mut name = None;
+        let mut args = vec![];
+        let mut ret_type = None;
+        let mut block_gen = BlockGen::new();
+        let mut extensions = Extensions::new();
+        self.object(|reader, key| {
+            match key {
+                "name" => name = Some(reader.name()?),
+                "args" => reader.array(|reader| {
+                    let (mut arg, mut ty) = (None, None);
+                    reader.object(|reader, key| {
+                        match key {
+                            "name" => arg = Some(reader.name()?),
+                            "type" => ty = Some(reader.ty()?),
+                            _ => drop(reader.value()?),
+                        }
+                        Ok(())
+                    })?;
+                    match (arg, ty) {
+                        (Some(arg), Some(ty)) => args.push((arg, ty)),
+                        _ => return Err(reader.error("argument without a name and a type")),
+                    }
+                    Ok(())
+                })?,
+                "type" => ret_type = Some(reader.ty()?),
+                "instrs" => reader.array(|reader| {
+                    let mut entry = reader.entry()?;
+                    match entry {
+                        Entry { op: Some(op), .. } => block_gen.add_instruction(Instruction {
+                            op,
+                            dst: entry.dst,
+                            dst_type: entry.dst_type,
+                            args: entry.args.map(Operands::from),
+                            funcs: entry.funcs.map(Operands::from),
+                            labels: entry.labels.map(Operands::from),
+                            value: entry.value,
+                            pos: entry.pos,
+                            extensions: {
+                                // An instruction's `label` is just another field
+                                if let Some(label) = entry.label {
+                                    entry
+                                        .extensions
+                                        .insert("label".into(), label.to_string().into());
+                                }
+                                instruction_extensions(entry.extensions)
+                            },
+                        }),
+                        Entry {
+                            label: Some(label), ..
+                        } => block_gen.add_label(label, entry.pos),
+                        _ => {}
+                    }
+                    Ok(())
+                })?,
+                _ => {
+                    extensions.insert(key.to_string(), reader.value()?);
+                }
+            }
+            Ok(())
+        })?;
+        block_gen.finalize_block();
+
+        let mut func = Function {
+            name: name.ok_or_else(|| self.error("function without a name"))?,
+            args,
+            ret_type,
+            blocks: block_gen.yield_blocks(),
+            extensions,
+        };
+        rename_synthetic_blocks(&mut func);
+        Ok(func)
+    }
+
+    /// Reads an element of `instrs`.
+    fn entry(&mut self) -> Result<Entry> {
+        let mut entry = Entry::default();
+        self.object(|reader, key| {
+            match key {
+                "op" => {
+                    reader.skip_whitespace()?;
+                    reader.string()?;
+                    let op = reader.scratch_str()?;
+                    entry.op = Some(
+                        op_code_named(op)
+                            .ok_or_else(|| reader.error(&format!("unknown operation {}", op)))?,
+                    );
+                }
+                "dest" => entry.dst = Some(reader.name()?),
+                "type" => entry.dst_type = Some(reader.ty()?),
+                "args" => entry.args = Some(reader.names()?),
+                "funcs" => entry.funcs = Some(reader.names()?),
+                "labels" => entry.labels = Some(reader.names()?),
+                "value" => {
+                    reader.skip_whitespace()?;
+                    entry.value = Some(match reader.peek()? {
+                        Some(b't' | b'f') => Value::Bool(reader.keyword()?.as_bool().unwrap()),
+                        _ => Value::Int(reader.integer()? as isize),
+                    });
+                }
+                "pos" => entry.pos = reader.position()?,
+                "label" => entry.label = Some(reader.name()?),
+                // Labels don't keep other fields, but they have to be read either way
+                _ => {
+                    entry.extensions.insert(key.to_string(), reader.value()?);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(entry)
+    }
+
+    fn position(&mut self) -> Result<Option<Position>> {
+        let (mut row, mut col) = (None, None);
+        self.object(|reader, key| {
+            match key {
+                "row" => row = Some(reader.integer()? as usize),
+                "col" => col = Some(reader.integer()? as usize),
+                _ => drop(reader.value()?),
+            }
+            Ok(())
+        })?;
+        Ok(row.zip(col).map(|(row, col)| Position { row, col }))
+    }
+}
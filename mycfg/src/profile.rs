@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use json::{object, JsonValue};
+
+/// How often each part of a function ran.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub blocks: HashMap<String, u64>,
+    /// Times control passed from the first block to the second.
+    pub edges: HashMap<(String, String), u64>,
+}
+
+/// Execution counts of one or more runs of a program, keyed by function name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub functions: HashMap<String, FunctionProfile>,
+}
+
+impl Profile {
+    pub fn calls(&self, func: &str) -> u64 {
+        self.functions.get(func).map_or(0, |f| f.calls)
+    }
+
+    pub fn block(&self, func: &str, block: &str) -> u64 {
+        self.functions
+            .get(func)
+            .and_then(|f| f.blocks.get(block))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn edge(&self, func: &str, from: &str, to: &str) -> u64 {
+        self.functions
+            .get(func)
+            .and_then(|f| f.edges.get(&(from.to_string(), to.to_string())))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Adds the counts of `other`, e.g. to combine runs on several inputs.
+    pub fn merge(&mut self, other: &Profile) {
+        for (name, theirs) in other.functions.iter() {
+            let ours = self.functions.entry(name.clone()).or_default();
+            ours.calls += theirs.calls;
+            for (block, count) in theirs.blocks.iter() {
+                *ours.blocks.entry(block.clone()).or_default() += count;
+            }
+            for (edge, count) in theirs.edges.iter() {
+                *ours.edges.entry(edge.clone()).or_default() += count;
+            }
+        }
+    }
+
+    /// Encodes the profile as
+    /// `{"f": {"calls": n, "blocks": {"b": n}, "edges": {"from": {"to": n}}}}`.
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::new_object();
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        for name in names {
+            let func = &self.functions[name];
+            let mut blocks = JsonValue::new_object();
+            let mut sorted: Vec<(&String, &u64)> = func.blocks.iter().collect();
+            sorted.sort();
+            for (block, count) in sorted {
+                blocks[block.as_str()] = (*count).into();
+            }
+            let mut edges = JsonValue::new_object();
+            let mut sorted: Vec<(&(String, String), &u64)> = func.edges.iter().collect();
+            sorted.sort();
+            for ((from, to), count) in sorted {
+                if !edges.has_key(from) {
+                    edges[from.as_str()] = JsonValue::new_object();
+                }
+                edges[from.as_str()][to.as_str()] = (*count).into();
+            }
+            json[name.as_str()] = object! {
+                "calls" => func.calls,
+                "blocks" => blocks,
+                "edges" => edges,
+            };
+        }
+        json
+    }
+
+    pub fn from_json(json: &JsonValue) -> Result<Profile, Box<dyn Error>> {
+        let count = |value: &JsonValue| -> Result<u64, Box<dyn Error>> {
+            value
+                .as_u64()
+                .ok_or_else(|| format!("invalid count {} in profile", value).into())
+        };
+        let mut profile = Profile::default();
+        for (name, func) in json.entries() {
+            let mut parsed = FunctionProfile {
+                calls: count(&func["calls"])?,
+                ..Default::default()
+            };
+            for (block, n) in func["blocks"].entries() {
+                parsed.blocks.insert(block.to_string(), count(n)?);
+            }
+            for (from, targets) in func["edges"].entries() {
+                for (to, n) in targets.entries() {
+                    parsed
+                        .edges
+                        .insert((from.to_string(), to.to_string()), count(n)?);
+                }
+            }
+            profile.functions.insert(name.to_string(), parsed);
+        }
+        Ok(profile)
+    }
+}
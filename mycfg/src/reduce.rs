@@ -0,0 +1,142 @@
+use std::ops::Range;
+
+use crate::core::{ControlOp, Instruction, OpCode, Program};
+
+/// Shrinks `prog` to a smaller program that `interesting` still holds for, by deleting
+/// functions, blocks and instructions and by turning branches into jumps. Deletions are tried in
+/// chunks that halve in size down to single items, like delta debugging, and the whole sequence
+/// repeats until nothing more can go. Only candidates that pass `Program::verify` are offered to
+/// `interesting`, so the result stays well-formed.
+pub fn minimize(prog: &Program, interesting: impl FnMut(&Program) -> bool) -> Program {
+    let mut reducer = Reducer {
+        best: prog.clone(),
+        interesting,
+    };
+    loop {
+        let mut changed = reducer.delete(function_count, without_functions);
+        changed |= reducer.straighten_branches();
+        changed |= reducer.delete(block_count, without_blocks);
+        changed |= reducer.delete(instruction_count, without_instructions);
+        if !changed {
+            return reducer.best;
+        }
+    }
+}
+
+struct Reducer<F: FnMut(&Program) -> bool> {
+    best: Program,
+    interesting: F,
+}
+
+impl<F: FnMut(&Program) -> bool> Reducer<F> {
+    /// Makes `candidate` the best program so far if it's well-formed and interesting.
+    fn accept(&mut self, candidate: Program) -> bool {
+        if candidate.verify().is_err() || !(self.interesting)(&candidate) {
+            return false;
+        }
+        self.best = candidate;
+        true
+    }
+
+    /// Deletes chunks of the items `count` counts, using `without` to remove a range of them.
+    fn delete(
+        &mut self,
+        count: fn(&Program) -> usize,
+        without: fn(&Program, Range<usize>) -> Program,
+    ) -> bool {
+        let mut changed = false;
+        let mut chunk = count(&self.best).div_ceil(2).max(1);
+        loop {
+            let mut start = 0;
+            while start < count(&self.best) {
+                let end = (start + chunk).min(count(&self.best));
+                if self.accept(without(&self.best, start..end)) {
+                    changed = true;
+                } else {
+                    start += chunk;
+                }
+            }
+            if chunk == 1 {
+                return changed;
+            }
+            chunk = chunk.div_ceil(2);
+        }
+    }
+
+    /// Replaces branches with a jump to either of their targets.
+    fn straighten_branches(&mut self) -> bool {
+        let mut changed = false;
+        for f in 0..self.best.functions.len() {
+            for b in 0..self.best.functions[f].blocks.len() {
+                for i in 0..self.best.functions[f].blocks[b].instructions.len() {
+                    let instr = &self.best.functions[f].blocks[b].instructions[i];
+                    if instr.op != OpCode::Control(ControlOp::Br) {
+                        continue;
+                    }
+                    let pos = instr.pos;
+                    for label in instr.labels.clone().unwrap().iter().copied() {
+                        let mut candidate = self.best.clone();
+                        candidate.functions[f].blocks[b].instructions[i] = Instruction {
+                            pos,
+                            ..Instruction::jmp(label)
+                        };
+                        if self.accept(candidate) {
+                            changed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn function_count(prog: &Program) -> usize {
+    prog.functions.len()
+}
+
+fn block_count(prog: &Program) -> usize {
+    prog.functions.iter().map(|func| func.blocks.len()).sum()
+}
+
+fn instruction_count(prog: &Program) -> usize {
+    prog.functions
+        .iter()
+        .flat_map(|func| func.blocks.iter())
+        .map(|block| block.instructions.len())
+        .sum()
+}
+
+fn without_functions(prog: &Program, range: Range<usize>) -> Program {
+    let mut prog = prog.clone();
+    let mut index = 0..;
+    prog.functions
+        .retain(|_| !range.contains(&index.next().unwrap()));
+    prog
+}
+
+fn without_blocks(prog: &Program, range: Range<usize>) -> Program {
+    let mut prog = prog.clone();
+    let mut index = 0..;
+    for func in prog.functions.iter_mut() {
+        func.blocks
+            .retain(|_| !range.contains(&index.next().unwrap()));
+    }
+    prog
+}
+
+fn without_instructions(prog: &Program, range: Range<usize>) -> Program {
+    let mut prog = prog.clone();
+    let mut index = 0..;
+    for block in prog
+        .functions
+        .iter_mut()
+        .flat_map(|func| func.blocks.iter_mut())
+    {
+        block
+            .instructions
+            .retain(|_| !range.contains(&index.next().unwrap()));
+    }
+    prog
+}
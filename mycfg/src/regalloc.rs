@@ -0,0 +1,127 @@
+mod coloring;
+mod linear_scan;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::core::{FuncId, Function, MiscOp, OpCode, Type, VarId};
+use crate::ssa::variable_types;
+
+pub(crate) use crate::regalloc::coloring::InterferenceGraph;
+pub use crate::regalloc::linear_scan::{live_intervals, Interval};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Register(usize),
+    Stack(usize),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Register(r) => write!(f, "r{}", r),
+            Location::Stack(slot) => write!(f, "stack{}", slot),
+        }
+    }
+}
+
+/// Where each variable of a function lives.
+pub struct Allocation {
+    pub function: FuncId,
+    pub registers: usize,
+    pub locations: HashMap<VarId, Location>,
+    /// Copies whose source and destination were given the same location.
+    pub coalesced: usize,
+}
+
+impl Allocation {
+    pub fn new(
+        func: &Function,
+        registers: usize,
+        locations: HashMap<VarId, Location>,
+    ) -> Allocation {
+        let coalesced = func
+            .blocks
+            .iter()
+            .flat_map(|block| block.instructions.iter())
+            .filter(|instr| instr.op == OpCode::Misc(MiscOp::Id))
+            .filter(|instr| {
+                locations.get(instr.dst.as_ref().unwrap()) == locations.get(&instr.uses()[0])
+            })
+            .count();
+        Allocation {
+            function: func.name,
+            registers,
+            locations,
+            coalesced,
+        }
+    }
+
+    pub fn spills(&self) -> usize {
+        self.locations
+            .values()
+            .filter(|loc| matches!(loc, Location::Stack(_)))
+            .count()
+    }
+
+    pub fn stack_slots(&self) -> usize {
+        self.locations
+            .values()
+            .filter_map(|loc| match loc {
+                Location::Stack(slot) => Some(slot + 1),
+                Location::Register(_) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Rewrites `func` so every variable is named after its location. Variables sharing a
+    /// location are never live at the same time, so the result computes the same values. Copies
+    /// that end up moving a location into itself are dropped. Boolean variables get a `.bool`
+    /// suffix, since a Bril variable can only have one type even where a register holds both.
+    pub fn apply(&self, func: &Function) -> Function {
+        let types = variable_types(func);
+        let rename = |var: &VarId| -> VarId {
+            match (self.locations.get(var), types.get(var)) {
+                (Some(loc), Some(Type::Bool)) => VarId::new(&format!("{}.bool", loc)),
+                (Some(loc), _) => VarId::new(&loc.to_string()),
+                (None, _) => *var,
+            }
+        };
+        let mut result = func.clone();
+        for (arg, _) in result.args.iter_mut() {
+            *arg = rename(arg);
+        }
+        for block in result.blocks.iter_mut() {
+            for instr in block.instructions.iter_mut() {
+                if let Some(dst) = instr.dst.as_mut() {
+                    *dst = rename(dst);
+                }
+                if let Some(args) = instr.args.as_mut() {
+                    for arg in args.iter_mut() {
+                        *arg = rename(arg);
+                    }
+                }
+            }
+            block.instructions.retain(|instr| {
+                instr.op != OpCode::Misc(MiscOp::Id) || instr.dst.as_ref() != Some(&instr.uses()[0])
+            });
+        }
+        result
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "@{}: {} variable(s) on {} register(s), {} spilled to {} stack slot(s), {} move(s) coalesced",
+            self.function,
+            self.locations.len(),
+            self.registers,
+            self.spills(),
+            self.stack_slots(),
+            self.coalesced
+        )
+    }
+}
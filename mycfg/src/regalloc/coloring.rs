@@ -0,0 +1,247 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::analysis::dataflow::{solve, LiveVariables};
+use crate::analysis::LoopForest;
+use crate::core::{Function, MiscOp, OpCode, VarId};
+use crate::regalloc::{Allocation, Location};
+
+/// Base of the spill cost weighting: an access at loop depth `d` costs `LOOP_WEIGHT^d`.
+const LOOP_WEIGHT: f64 = 10.0;
+
+pub(crate) struct InterferenceGraph {
+    pub nodes: Vec<VarId>,
+    pub edges: HashMap<VarId, BTreeSet<VarId>>,
+    /// Pairs of variables connected by an `id` copy, as (destination, source).
+    pub moves: Vec<(VarId, VarId)>,
+}
+
+impl InterferenceGraph {
+    /// A definition interferes with every variable live after it, except that the destination
+    /// of a copy does not interfere with its source or with anything copied from the same source
+    /// earlier in the block, since they hold the same value. Arguments are all defined on entry, so they
+    /// interfere with each other and with everything live into the function.
+    pub fn new(func: &Function) -> InterferenceGraph {
+        let live = solve(func, &LiveVariables);
+        let mut graph = InterferenceGraph {
+            nodes: vec![],
+            edges: HashMap::new(),
+            moves: vec![],
+        };
+        for (arg, _) in func.args.iter() {
+            graph.add_node(*arg);
+        }
+        for block in func.blocks.iter() {
+            for instr in block.instructions.iter() {
+                if let Some(dst) = instr.dst {
+                    graph.add_node(dst);
+                }
+                for &arg in instr.uses() {
+                    graph.add_node(arg);
+                }
+            }
+        }
+
+        if let Some(entry) = func.blocks.first() {
+            let mut defined: BTreeSet<VarId> = live.block_in[&entry.name].clone();
+            defined.extend(func.args.iter().map(|(arg, _)| *arg));
+            for (arg, _) in func.args.iter() {
+                for &other in defined.iter() {
+                    graph.add_edge(*arg, other);
+                }
+            }
+        }
+
+        for block in func.blocks.iter() {
+            let facts = live.instruction_facts(&LiveVariables, block);
+            // Variables copied from another one earlier in the block, mapped to the first source
+            let mut values: HashMap<VarId, VarId> = HashMap::new();
+            for (instr, (_, live_out)) in block.instructions.iter().zip(facts.iter()) {
+                let dst = match instr.dst {
+                    Some(dst) => dst,
+                    None => continue,
+                };
+                let value = |values: &HashMap<VarId, VarId>, var: VarId| -> VarId {
+                    values.get(&var).copied().unwrap_or(var)
+                };
+                values.retain(|_, source| *source != dst);
+                match instr.op {
+                    OpCode::Misc(MiscOp::Id) => {
+                        let source = instr.uses()[0];
+                        graph.moves.push((dst, source));
+                        let source = value(&values, source);
+                        if source == dst {
+                            values.remove(&dst);
+                        } else {
+                            values.insert(dst, source);
+                        }
+                    }
+                    _ => {
+                        values.remove(&dst);
+                    }
+                }
+                let own = value(&values, dst);
+                for &other in live_out.iter() {
+                    if value(&values, other) != own {
+                        graph.add_edge(dst, other);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    fn add_node(&mut self, var: VarId) {
+        if !self.edges.contains_key(&var) {
+            self.nodes.push(var);
+            self.edges.insert(var, BTreeSet::new());
+        }
+    }
+
+    fn add_edge(&mut self, a: VarId, b: VarId) {
+        if a != b {
+            self.edges.get_mut(&a).unwrap().insert(b);
+            self.edges.get_mut(&b).unwrap().insert(a);
+        }
+    }
+
+    fn degree(&self, var: VarId) -> usize {
+        self.edges[&var].len()
+    }
+
+    /// Folds `b` into `a`.
+    pub fn merge(&mut self, a: VarId, b: VarId) {
+        let neighbors = self.edges.remove(&b).unwrap();
+        self.nodes.retain(|&node| node != b);
+        for n in neighbors.iter() {
+            let set = self.edges.get_mut(n).unwrap();
+            set.remove(&b);
+            if *n != a {
+                set.insert(a);
+            }
+        }
+        let set = self.edges.get_mut(&a).unwrap();
+        set.extend(neighbors.into_iter().filter(|&n| n != a));
+    }
+}
+
+impl Function {
+    /// Chaitin-Briggs allocation: copies are coalesced when Briggs' conservative test holds, then
+    /// the interference graph is simplified with optimistic coloring. When every remaining node
+    /// has at least `registers` neighbours, the one with the lowest spill cost per neighbour is
+    /// pushed next; nodes that then find no free color are spilled to stack slots.
+    pub fn allocate_graph_coloring(&self, registers: usize) -> Allocation {
+        let mut graph = InterferenceGraph::new(self);
+        let forest = LoopForest::new(self);
+
+        let mut costs: HashMap<VarId, f64> = HashMap::new();
+        for block in self.blocks.iter() {
+            let weight = LOOP_WEIGHT.powi(forest.depth(block.name) as i32);
+            for instr in block.instructions.iter() {
+                for var in instr.uses().iter().chain(instr.dst.iter()) {
+                    *costs.entry(*var).or_default() += weight;
+                }
+            }
+        }
+
+        // Coalesce until no copy passes the conservative test
+        let mut alias: HashMap<VarId, VarId> = HashMap::new();
+        let find = |alias: &HashMap<VarId, VarId>, mut var: VarId| -> VarId {
+            while let Some(&next) = alias.get(&var) {
+                var = next;
+            }
+            var
+        };
+        loop {
+            let candidate = graph.moves.iter().find_map(|(dst, src)| {
+                let (a, b) = (find(&alias, *dst), find(&alias, *src));
+                if a == b || graph.edges[&a].contains(&b) {
+                    return None;
+                }
+                let combined: BTreeSet<&VarId> = graph.edges[&a].union(&graph.edges[&b]).collect();
+                let significant = combined
+                    .iter()
+                    .filter(|&&n| graph.degree(*n) >= registers)
+                    .count();
+                (significant < registers).then_some((a, b))
+            });
+            match candidate {
+                Some((a, b)) => {
+                    graph.merge(a, b);
+                    let cost = costs.remove(&b).unwrap_or_default();
+                    *costs.entry(a).or_default() += cost;
+                    alias.insert(b, a);
+                }
+                None => break,
+            }
+        }
+
+        // Simplify, pushing the cheapest node per neighbour when nothing is trivially colorable
+        let mut remaining: BTreeSet<VarId> = graph.nodes.iter().cloned().collect();
+        let mut stack: Vec<VarId> = vec![];
+        while !remaining.is_empty() {
+            let degree = |var: &VarId| -> usize {
+                graph.edges[var]
+                    .iter()
+                    .filter(|n| remaining.contains(*n))
+                    .count()
+            };
+            let next = graph
+                .nodes
+                .iter()
+                .find(|var| remaining.contains(*var) && degree(var) < registers)
+                .cloned()
+                .unwrap_or_else(|| {
+                    graph
+                        .nodes
+                        .iter()
+                        .filter(|var| remaining.contains(*var))
+                        .min_by(|a, b| {
+                            let ratio =
+                                |v: &VarId| costs.get(v).copied().unwrap_or(0.0) / degree(v) as f64;
+                            ratio(a).total_cmp(&ratio(b))
+                        })
+                        .cloned()
+                        .unwrap()
+                });
+            remaining.remove(&next);
+            stack.push(next);
+        }
+
+        let mut colors: HashMap<VarId, Location> = HashMap::new();
+        while let Some(var) = stack.pop() {
+            let taken: BTreeSet<usize> = graph.edges[&var]
+                .iter()
+                .filter_map(|n| match colors.get(n) {
+                    Some(Location::Register(r)) => Some(*r),
+                    _ => None,
+                })
+                .collect();
+            if let Some(r) = (0..registers).find(|r| !taken.contains(r)) {
+                colors.insert(var, Location::Register(r));
+            }
+        }
+
+        // Spilled nodes share a stack slot unless they interfere
+        for var in graph.nodes.iter() {
+            if colors.contains_key(var) {
+                continue;
+            }
+            let taken: BTreeSet<usize> = graph.edges[var]
+                .iter()
+                .filter_map(|n| match colors.get(n) {
+                    Some(Location::Stack(slot)) => Some(*slot),
+                    _ => None,
+                })
+                .collect();
+            let slot = (0..).find(|slot| !taken.contains(slot)).unwrap();
+            colors.insert(*var, Location::Stack(slot));
+        }
+
+        let mut locations: HashMap<VarId, Location> = HashMap::new();
+        for var in alias.keys().chain(graph.nodes.iter()) {
+            locations.insert(*var, colors[&find(&alias, *var)].clone());
+        }
+
+        Allocation::new(self, registers, locations)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::analysis::dataflow::{solve, LiveVariables};
+use crate::core::{Function, VarId};
+use crate::regalloc::{Allocation, Location};
+
+/// The range of linear positions over which a variable may hold a value that is still needed.
+#[derive(Clone, Debug)]
+pub struct Interval {
+    pub var: VarId,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Builds one conservative interval per variable over the blocks laid out in program order.
+/// Function arguments are defined at position 0; each block then takes a start position, a use
+/// and a def position per instruction (uses come first, so an operand's register can be reused
+/// for the destination), and an end position. A variable live into or out of a block covers the
+/// block's start or end.
+pub fn live_intervals(func: &Function) -> Vec<Interval> {
+    let live = solve(func, &LiveVariables);
+    let mut ranges: HashMap<VarId, (usize, usize)> = HashMap::new();
+    let mut order: Vec<VarId> = vec![];
+    let mut touch = |var: VarId, pos: usize| {
+        let range = ranges.entry(var).or_insert_with(|| {
+            order.push(var);
+            (pos, pos)
+        });
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+
+    for (arg, _) in func.args.iter() {
+        touch(*arg, 0);
+    }
+    let mut pos = 1;
+    for block in func.blocks.iter() {
+        for &var in live.block_in[&block.name].iter() {
+            touch(var, pos);
+        }
+        pos += 1;
+        for instr in block.instructions.iter() {
+            for &arg in instr.uses() {
+                touch(arg, pos);
+            }
+            if let Some(dst) = instr.dst {
+                touch(dst, pos + 1);
+            }
+            pos += 2;
+        }
+        for &var in live.block_out[&block.name].iter() {
+            touch(var, pos);
+        }
+        pos += 1;
+    }
+
+    order
+        .into_iter()
+        .map(|var| {
+            let (start, end) = ranges[&var];
+            Interval { var, start, end }
+        })
+        .collect()
+}
+
+impl Function {
+    /// Poletto and Sarkar's linear scan: intervals are visited by start position and, when all
+    /// registers are taken, the interval ending furthest away is spilled to a stack slot. A stack
+    /// slot is shared by spilled intervals that don't overlap.
+    pub fn allocate_linear_scan(&self, registers: usize) -> Allocation {
+        let mut intervals = live_intervals(self);
+        intervals.sort_by_key(|interval| interval.start);
+
+        let mut locations: HashMap<VarId, Location> = HashMap::new();
+        let mut active: Vec<(usize, VarId, usize)> = vec![];
+        let mut free: Vec<usize> = (0..registers).rev().collect();
+        // Last position occupied in each stack slot so far
+        let mut slot_ends: Vec<usize> = vec![];
+        let mut spill = |start: usize, end: usize| -> Location {
+            let slot = match slot_ends.iter().position(|&last| last < start) {
+                Some(slot) => slot,
+                None => {
+                    slot_ends.push(0);
+                    slot_ends.len() - 1
+                }
+            };
+            slot_ends[slot] = end;
+            Location::Stack(slot)
+        };
+        let starts: HashMap<VarId, usize> = intervals.iter().map(|i| (i.var, i.start)).collect();
+
+        for interval in intervals.iter() {
+            // Expire everything that ended before this interval starts
+            active.retain(|(end, _, reg)| {
+                if *end < interval.start {
+                    free.push(*reg);
+                    return false;
+                }
+                true
+            });
+            free.sort_by(|a, b| b.cmp(a));
+
+            if let Some(reg) = free.pop() {
+                active.push((interval.end, interval.var, reg));
+                locations.insert(interval.var, Location::Register(reg));
+                continue;
+            }
+
+            let furthest = active
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (end, _, _))| *end)
+                .map(|(i, _)| i);
+            match furthest {
+                Some(i) if active[i].0 > interval.end => {
+                    let (end, var, reg) = active.remove(i);
+                    locations.insert(var, spill(starts[&var], end));
+                    active.push((interval.end, interval.var, reg));
+                    locations.insert(interval.var, Location::Register(reg));
+                }
+                _ => {
+                    locations.insert(interval.var, spill(interval.start, interval.end));
+                }
+            }
+        }
+
+        Allocation::new(self, registers, locations)
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::dataflow::{solve, LiveVariables};
+use crate::analysis::{predecessors, Dominators};
+use crate::core::{
+    fresh_name, BasicBlock, Function, Instruction, LabelId, MiscOp, OpCode, Operands, Type, VarId,
+};
+use crate::parser::control_flow_graph;
+use crate::regalloc::InterferenceGraph;
+
+impl Function {
+    /// Converts the function to pruned SSA form: phis are only placed where the variable is live,
+    /// and every definition gets a fresh name. A phi argument for an edge along which the
+    /// variable has no definition is a fresh name that's never defined either. Unreachable blocks
+    /// are dropped, and a new entry block is added if the old one is a branch target, since phis
+    /// need predecessors.
+    pub fn to_ssa(&self) -> Function {
+        let mut func = self.clone();
+        let doms = Dominators::new(&func);
+        func.blocks.retain(|block| doms.is_reachable(block.name));
+
+        let cfg = control_flow_graph(&func);
+        let preds = predecessors(&func, &cfg);
+        if let Some(entry) = func.blocks.first() {
+            if !preds[&entry.name].is_empty() {
+                let name = func.fresh_block_name("entry");
+                func.blocks.insert(
+                    0,
+                    BasicBlock {
+                        name,
+                        instructions: vec![],
+                        pos: None,
+                        synthetic: true,
+                    },
+                );
+            }
+        }
+
+        let cfg = control_flow_graph(&func);
+        let preds = predecessors(&func, &cfg);
+        let doms = Dominators::new(&func);
+        let frontier = doms.frontier(&func);
+        let live = solve(&func, &LiveVariables);
+        let types = variable_types(&func);
+
+        // Place phis at the iterated dominance frontier of each variable's definitions
+        let mut defs: HashMap<VarId, Vec<LabelId>> = HashMap::new();
+        for block in func.blocks.iter() {
+            for instr in block.instructions.iter() {
+                if let Some(dst) = instr.dst {
+                    defs.entry(dst).or_default().push(block.name);
+                }
+            }
+        }
+        // Phis start their blocks in order of variable name
+        let mut phis: HashMap<LabelId, Vec<VarId>> = HashMap::new();
+        let mut vars: Vec<VarId> = defs.keys().copied().collect();
+        vars.sort_by(VarId::cmp_by_name);
+        for var in vars {
+            let mut worklist: Vec<LabelId> = defs[&var].clone();
+            let mut placed: HashSet<LabelId> = HashSet::new();
+            while let Some(block) = worklist.pop() {
+                for &target in frontier[&block].iter() {
+                    if live.block_in[&target].contains(&var) && placed.insert(target) {
+                        phis.entry(target).or_default().push(var);
+                        worklist.push(target);
+                    }
+                }
+            }
+        }
+
+        let undefined = func.fresh_var_name("__undefined");
+        let mut renamer = Renamer {
+            stacks: HashMap::new(),
+            counters: HashMap::new(),
+            taken: types.keys().copied().chain([undefined]).collect(),
+            phi_vars: phis.clone(),
+            undefined,
+        };
+        for &(arg, _) in func.args.iter() {
+            renamer.stacks.insert(arg, vec![arg]);
+        }
+
+        let mut blocks: HashMap<LabelId, BasicBlock> = HashMap::new();
+        for block in func.blocks.iter() {
+            let mut instructions: Vec<Instruction> = vec![];
+            for var in phis.get(&block.name).into_iter().flatten() {
+                instructions.push(Instruction {
+                    op: OpCode::Phi,
+                    dst: Some(*var),
+                    dst_type: Some(types[var].clone()),
+                    args: Some(Operands::new()),
+                    funcs: None,
+                    labels: Some(Operands::new()),
+                    value: None,
+                    pos: None,
+                    extensions: None,
+                });
+            }
+            instructions.extend(block.instructions.iter().cloned());
+            blocks.insert(
+                block.name,
+                BasicBlock {
+                    name: block.name,
+                    instructions,
+                    pos: block.pos,
+                    synthetic: block.synthetic,
+                },
+            );
+        }
+
+        if let Some(entry) = func.blocks.first() {
+            renamer.rename(entry.name, &mut blocks, &cfg, &doms);
+        }
+
+        // Order phi arguments like the predecessor list for readability
+        for block in blocks.values_mut() {
+            for phi in block
+                .instructions
+                .iter_mut()
+                .filter(|i| i.op == OpCode::Phi)
+            {
+                let labels = phi.labels.take().unwrap();
+                let args = phi.args.take().unwrap();
+                let mut pairs: Vec<(LabelId, VarId)> =
+                    labels.iter().copied().zip(args.iter().copied()).collect();
+                let order = &preds[&block.name];
+                pairs.sort_by_key(|(label, _)| order.iter().position(|p| p == label));
+                phi.labels = Some(pairs.iter().map(|&(label, _)| label).collect());
+                phi.args = Some(pairs.iter().map(|&(_, arg)| arg).collect());
+            }
+        }
+
+        Function {
+            name: func.name,
+            args: func.args.clone(),
+            ret_type: func.ret_type.clone(),
+            blocks: func
+                .blocks
+                .iter()
+                .map(|block| blocks.remove(&block.name).unwrap())
+                .collect(),
+            extensions: func.extensions.clone(),
+        }
+    }
+
+    /// Replaces every phi with copies at the end of its predecessors. A phi whose destination is
+    /// still needed along another edge out of a predecessor goes through a temporary copied at
+    /// the top of its block instead, and the copies on each edge are ordered to behave as if they
+    /// happened in parallel. Copies whose two sides don't interfere are then coalesced away.
+    pub fn from_ssa(&self) -> Function {
+        let cfg = control_flow_graph(self);
+        let preds = predecessors(self, &cfg);
+        let live = solve(self, &LiveVariables);
+        let mut func = self.clone();
+        let defined: HashSet<VarId> = variable_types(self).into_keys().collect();
+        let mut taken = defined.clone();
+        let blocks: HashMap<LabelId, &BasicBlock> = self
+            .blocks
+            .iter()
+            .map(|block| (block.name, block))
+            .collect();
+
+        // Copies to run at the end of each predecessor as (dst, type, src)
+        let mut copies: HashMap<LabelId, Vec<(VarId, Type, VarId)>> = HashMap::new();
+        for block in func.blocks.iter_mut() {
+            let mut header_copies: Vec<Instruction> = vec![];
+            for phi in block.instructions.iter().filter(|i| i.op == OpCode::Phi) {
+                let dst = phi.dst.unwrap();
+                let ty = phi.dst_type.clone().unwrap();
+                let clobbers = preds[&block.name].iter().any(|pred| {
+                    let other_succ_reads = cfg[pred]
+                        .iter()
+                        .any(|succ| *succ != block.name && live.block_in[succ].contains(&dst));
+                    let terminator_reads = blocks[pred]
+                        .instructions
+                        .last()
+                        .is_some_and(|last| last.is_terminator() && last.uses().contains(&dst));
+                    other_succ_reads || terminator_reads
+                });
+                let target = if clobbers {
+                    let tmp = fresh_name(&format!("{}.phi", dst), &mut taken);
+                    header_copies.push(Instruction::compute(
+                        OpCode::Misc(MiscOp::Id),
+                        dst,
+                        ty.clone(),
+                        vec![tmp],
+                    ));
+                    tmp
+                } else {
+                    dst
+                };
+
+                let labels = phi.labels.as_ref().unwrap();
+                // Arguments that are never defined stand for no value
+                for (arg, label) in phi.uses().iter().zip(labels.iter()) {
+                    if defined.contains(arg) {
+                        copies
+                            .entry(*label)
+                            .or_default()
+                            .push((target, ty.clone(), *arg));
+                    }
+                }
+            }
+            block.instructions.retain(|i| i.op != OpCode::Phi);
+            block.instructions.splice(0..0, header_copies);
+        }
+
+        for block in func.blocks.iter_mut() {
+            if let Some(pending) = copies.remove(&block.name) {
+                let sequential = sequentialize(pending, &mut taken);
+                let at = match block.instructions.last() {
+                    Some(last) if last.is_terminator() => block.instructions.len() - 1,
+                    _ => block.instructions.len(),
+                };
+                block.instructions.splice(at..at, sequential);
+            }
+        }
+        coalesce(func, &variable_types(self))
+    }
+}
+
+struct Renamer {
+    stacks: HashMap<VarId, Vec<VarId>>,
+    counters: HashMap<VarId, usize>,
+    taken: HashSet<VarId>,
+    /// Original variable of each phi, in the order the phis start their block.
+    phi_vars: HashMap<LabelId, Vec<VarId>>,
+    /// Phi argument for a variable that has no definition along the incoming edge.
+    undefined: VarId,
+}
+
+impl Renamer {
+    fn push(&mut self, var: VarId) -> VarId {
+        let counter = self.counters.entry(var).or_default();
+        let name = loop {
+            let name = VarId::new(&format!("{}.{}", var, counter));
+            *counter += 1;
+            if !self.taken.contains(&name) {
+                break name;
+            }
+        };
+        self.stacks.entry(var).or_default().push(name);
+        name
+    }
+
+    fn current(&self, var: VarId) -> Option<VarId> {
+        self.stacks
+            .get(&var)
+            .and_then(|stack| stack.last())
+            .copied()
+    }
+
+    fn rename(
+        &mut self,
+        name: LabelId,
+        blocks: &mut HashMap<LabelId, BasicBlock>,
+        cfg: &HashMap<LabelId, Vec<LabelId>>,
+        doms: &Dominators,
+    ) {
+        let mut pushed: Vec<VarId> = vec![];
+        let block = blocks.get_mut(&name).unwrap();
+        for instr in block.instructions.iter_mut() {
+            if instr.op != OpCode::Phi {
+                if let Some(args) = instr.args.as_mut() {
+                    for arg in args.iter_mut() {
+                        if let Some(current) = self.current(*arg) {
+                            *arg = current;
+                        }
+                    }
+                }
+            }
+            if let Some(dst) = instr.dst.as_mut() {
+                pushed.push(*dst);
+                *dst = self.push(*dst);
+            }
+        }
+
+        for succ in cfg[&name].iter() {
+            let vars = match self.phi_vars.get(succ) {
+                Some(vars) => vars,
+                None => continue,
+            };
+            let succ_block = blocks.get_mut(succ).unwrap();
+            for (phi, var) in succ_block.instructions.iter_mut().zip(vars.iter()) {
+                let value = match self.current(*var) {
+                    Some(current) => current,
+                    None => self.undefined,
+                };
+                phi.args.as_mut().unwrap().push(value);
+                phi.labels.as_mut().unwrap().push(name);
+            }
+        }
+
+        for &child in doms.children(name) {
+            self.rename(child, blocks, cfg, doms);
+        }
+
+        for var in pushed {
+            self.stacks.get_mut(&var).unwrap().pop();
+        }
+    }
+}
+
+/// Types of every argument and destination in the function.
+pub fn variable_types(func: &Function) -> HashMap<VarId, Type> {
+    let mut types: HashMap<VarId, Type> = func.args.iter().cloned().collect();
+    for block in func.blocks.iter() {
+        for instr in block.instructions.iter() {
+            if let (Some(dst), Some(ty)) = (instr.dst, &instr.dst_type) {
+                types.insert(dst, ty.clone());
+            }
+        }
+    }
+    types
+}
+
+/// Gives both sides of a copy the same name wherever they don't interfere and have the same
+/// type, and drops the copies that become `x = id x`. Arguments keep their names, and names from
+/// `ssa` are kept over the temporaries added leaving it.
+fn coalesce(mut func: Function, ssa: &HashMap<VarId, Type>) -> Function {
+    let mut graph = InterferenceGraph::new(&func);
+    let types = variable_types(&func);
+    let args: HashSet<VarId> = func.args.iter().map(|(arg, _)| *arg).collect();
+    let mut alias: HashMap<VarId, VarId> = HashMap::new();
+    let find = |alias: &HashMap<VarId, VarId>, mut var: VarId| -> VarId {
+        while let Some(&next) = alias.get(&var) {
+            var = next;
+        }
+        var
+    };
+    for (dst, src) in graph.moves.clone() {
+        let (a, b) = (find(&alias, dst), find(&alias, src));
+        if a == b || graph.edges[&a].contains(&b) || types.get(&a) != types.get(&b) {
+            continue;
+        }
+        let rank = |var: &VarId| (args.contains(var), ssa.contains_key(var));
+        let (a, b) = if rank(&b) > rank(&a) { (b, a) } else { (a, b) };
+        graph.merge(a, b);
+        alias.insert(b, a);
+    }
+
+    for block in func.blocks.iter_mut() {
+        for instr in block.instructions.iter_mut() {
+            if let Some(dst) = instr.dst.as_mut() {
+                *dst = find(&alias, *dst);
+            }
+            if let Some(args) = instr.args.as_mut() {
+                for arg in args.iter_mut() {
+                    *arg = find(&alias, *arg);
+                }
+            }
+        }
+        block.instructions.retain(|instr| {
+            instr.op != OpCode::Misc(MiscOp::Id) || instr.dst != Some(instr.uses()[0])
+        });
+    }
+    func
+}
+
+/// Orders a set of parallel copies so no copy overwrites a source another copy still needs,
+/// breaking cycles with a temporary.
+fn sequentialize(
+    mut pending: Vec<(VarId, Type, VarId)>,
+    taken: &mut HashSet<VarId>,
+) -> Vec<Instruction> {
+    let mut result: Vec<Instruction> = vec![];
+    pending.retain(|(dst, _, src)| dst != src);
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(dst, _, _)| !pending.iter().any(|(_, _, src)| src == dst));
+        match ready {
+            Some(i) => {
+                let (dst, ty, src) = pending.remove(i);
+                result.push(Instruction::compute(
+                    OpCode::Misc(MiscOp::Id),
+                    dst,
+                    ty,
+                    vec![src],
+                ));
+            }
+            None => {
+                // Every destination is still read by another copy: save one of them
+                let (dst, ty, _) = pending[0].clone();
+                let tmp = fresh_name(&format!("{}.swap", dst), taken);
+                result.push(Instruction::compute(
+                    OpCode::Misc(MiscOp::Id),
+                    tmp,
+                    ty,
+                    vec![dst],
+                ));
+                for (_, _, src) in pending.iter_mut() {
+                    if *src == dst {
+                        *src = tmp;
+                    }
+                }
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::core::{
+    at, BasicBlock, ControlOp, FuncId, Function, Instruction, LabelId, LogicOp, MiscOp, OpCode,
+    Position, Program, SpeculationOp, Type, Value, VarId,
+};
+
+/// What a call needs to know about its callee.
+struct Signature<'a> {
+    args: Vec<&'a Type>,
+    ret_type: Option<&'a Type>,
+}
+
+impl Program {
+    /// Checks the invariants every pass has to preserve: names are unique, every label and
+    /// function exists, terminators only end blocks and phis only start them, every operation
+    /// has the operands it needs, and every variable has a single type and is defined somewhere.
+    /// Returns the first violation found.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let mut signatures: HashMap<FuncId, Signature> = HashMap::new();
+        for func in self.functions.iter() {
+            let signature = Signature {
+                args: func.args.iter().map(|(_, ty)| ty).collect(),
+                ret_type: func.ret_type.as_ref(),
+            };
+            if signatures.insert(func.name, signature).is_some() {
+                return Err(format!("@{} is defined more than once", func.name).into());
+            }
+        }
+        for func in self.functions.iter() {
+            func.verify_with(&signatures)?;
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    fn verify_with(&self, signatures: &HashMap<FuncId, Signature>) -> Result<(), Box<dyn Error>> {
+        // Located at the instruction at fault, or the block's label
+        let error =
+            |block: &BasicBlock, pos: Option<Position>, message: String| -> Box<dyn Error> {
+                let pos = pos.or(block.pos);
+                format!("@{} .{}{}: {}", self.name, block.name, at(pos), message).into()
+            };
+
+        let mut blocks: HashSet<LabelId> = HashSet::new();
+        for block in self.blocks.iter() {
+            if !blocks.insert(block.name) {
+                return Err(error(
+                    block,
+                    block.pos,
+                    "block is defined more than once".into(),
+                ));
+            }
+        }
+        let mut types: HashMap<VarId, &Type> = HashMap::new();
+        for (arg, ty) in self.args.iter() {
+            if types.insert(*arg, ty).is_some() {
+                return Err(format!("@{}: argument {} is repeated", self.name, arg).into());
+            }
+        }
+        for block in self.blocks.iter() {
+            for instr in block.instructions.iter() {
+                if let (Some(dst), Some(ty)) = (&instr.dst, &instr.dst_type) {
+                    match types.insert(*dst, ty) {
+                        Some(other) if other != ty => {
+                            return Err(error(
+                                block,
+                                instr.pos,
+                                format!("{} is both {:?} and {:?}", dst, other, ty),
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        for block in self.blocks.iter() {
+            let n = block.instructions.len();
+            let mut phis_done = false;
+            for (i, instr) in block.instructions.iter().enumerate() {
+                if instr.is_terminator() && i + 1 != n {
+                    return Err(error(
+                        block,
+                        instr.pos,
+                        format!("{} is not at the end", instr),
+                    ));
+                }
+                if instr.op == OpCode::Phi && phis_done {
+                    return Err(error(
+                        block,
+                        instr.pos,
+                        format!("{} follows other instructions", instr),
+                    ));
+                }
+                phis_done |= instr.op != OpCode::Phi;
+                self.verify_instruction(instr, &types, &blocks, signatures)
+                    .map_err(|message| {
+                        error(block, instr.pos, format!("{}: {}", instr, message))
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the shape and operand types of one instruction.
+    fn verify_instruction(
+        &self,
+        instr: &Instruction,
+        types: &HashMap<VarId, &Type>,
+        blocks: &HashSet<LabelId>,
+        signatures: &HashMap<FuncId, Signature>,
+    ) -> Result<(), String> {
+        let args = instr.uses();
+        let labels = instr.labels.as_deref().unwrap_or(&[]);
+        let funcs = instr.funcs.as_deref().unwrap_or(&[]);
+        for label in labels.iter() {
+            if !blocks.contains(label) {
+                return Err(format!("unknown label .{}", label));
+            }
+        }
+        let type_of = |arg: &VarId| -> Result<&Type, String> {
+            types
+                .get(arg)
+                .copied()
+                .ok_or_else(|| format!("{} is never defined", arg))
+        };
+        let expect = |count: usize, ty: Option<&Type>| -> Result<(), String> {
+            if args.len() != count {
+                return Err(format!("expected {} argument(s)", count));
+            }
+            if let Some(ty) = ty {
+                for arg in args.iter() {
+                    if type_of(arg)? != ty {
+                        return Err(format!("{} is not {:?}", arg, ty));
+                    }
+                }
+            }
+            Ok(())
+        };
+        let result = |ty: Option<&Type>| -> Result<(), String> {
+            match (&instr.dst, &instr.dst_type, ty) {
+                (Some(_), Some(dst_type), Some(ty)) if dst_type == ty => Ok(()),
+                (Some(_), Some(dst_type), Some(ty)) => {
+                    Err(format!("result is {:?} instead of {:?}", dst_type, ty))
+                }
+                (None, None, None) => Ok(()),
+                (Some(_), Some(_), None) | (None, None, Some(_)) => {
+                    Err("unexpected or missing destination".into())
+                }
+                _ => Err("destination and type must be given together".into()),
+            }
+        };
+        let expect_labels = |count: usize| -> Result<(), String> {
+            if labels.len() != count {
+                return Err(format!("expected {} label(s)", count));
+            }
+            Ok(())
+        };
+
+        match &instr.op {
+            OpCode::Const => {
+                expect(0, None)?;
+                let ty = match &instr.value {
+                    Some(Value::Int(_)) => Type::Int,
+                    Some(Value::Bool(_)) => Type::Bool,
+                    None => return Err("missing value".into()),
+                };
+                result(Some(&ty))
+            }
+            OpCode::Phi => {
+                expect_labels(args.len())?;
+                let ty = instr.dst_type.as_ref().ok_or("missing destination")?;
+                // Phis of SSA form take variables that are never defined for no value
+                for arg in args.iter().filter(|arg| types.contains_key(arg)) {
+                    if type_of(arg)? != ty {
+                        return Err(format!("{} is not {:?}", arg, ty));
+                    }
+                }
+                result(Some(ty))
+            }
+            OpCode::Arithmetic(_) => {
+                expect(2, Some(&Type::Int))?;
+                result(Some(&Type::Int))
+            }
+            OpCode::Comparison(_) => {
+                expect(2, Some(&Type::Int))?;
+                result(Some(&Type::Bool))
+            }
+            OpCode::Logic(LogicOp::Not) => {
+                expect(1, Some(&Type::Bool))?;
+                result(Some(&Type::Bool))
+            }
+            OpCode::Logic(_) => {
+                expect(2, Some(&Type::Bool))?;
+                result(Some(&Type::Bool))
+            }
+            OpCode::Misc(MiscOp::Id) => {
+                expect(1, None)?;
+                result(Some(type_of(&args[0])?))
+            }
+            OpCode::Misc(MiscOp::Print) => {
+                for arg in args.iter() {
+                    type_of(arg)?;
+                }
+                result(None)
+            }
+            OpCode::Misc(MiscOp::Nop)
+            | OpCode::Speculation(SpeculationOp::Speculate)
+            | OpCode::Speculation(SpeculationOp::Commit) => {
+                expect(0, None)?;
+                result(None)
+            }
+            OpCode::Control(ControlOp::Jmp) => {
+                expect(0, None)?;
+                expect_labels(1)?;
+                result(None)
+            }
+            OpCode::Control(ControlOp::Br) => {
+                expect(1, Some(&Type::Bool))?;
+                expect_labels(2)?;
+                result(None)
+            }
+            OpCode::Speculation(SpeculationOp::Guard) => {
+                expect(1, Some(&Type::Bool))?;
+                expect_labels(1)?;
+                result(None)
+            }
+            OpCode::Control(ControlOp::Ret) => {
+                match (self.ret_type.as_ref(), args) {
+                    (None, []) => {}
+                    (Some(ty), [arg]) if type_of(arg)? == ty => {}
+                    _ => return Err("doesn't match the return type".into()),
+                }
+                result(None)
+            }
+            OpCode::Control(ControlOp::Call) => {
+                let callee = match funcs {
+                    [callee] => callee,
+                    _ => return Err("expected 1 function".into()),
+                };
+                let signature = signatures
+                    .get(callee)
+                    .ok_or_else(|| format!("unknown function @{}", callee))?;
+                if args.len() != signature.args.len() {
+                    return Err(format!(
+                        "@{} takes {} argument(s)",
+                        callee,
+                        signature.args.len()
+                    ));
+                }
+                for (arg, ty) in args.iter().zip(signature.args.iter()) {
+                    if type_of(arg)? != *ty {
+                        return Err(format!("{} is not {:?}", arg, ty));
+                    }
+                }
+                // The result of a call may be ignored
+                match instr.dst {
+                    Some(_) => result(signature.ret_type),
+                    None => result(None),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,631 @@
+mod compile;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+
+use crate::core::{FuncId, LabelId, Type, Value, VarId};
+use crate::profile::{FunctionProfile, Profile};
+
+/// Marks an absent slot in `Call::dst` and `Ret::value`.
+const NONE: u32 = u32::MAX;
+
+/// One bytecode instruction. Operands are slots in the current frame and jump targets are
+/// offsets into the function's code, all resolved at compile time.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Const {
+        dst: u32,
+        value: i64,
+    },
+    Add {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Sub {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Mul {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Div {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Eq {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Lt {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Gt {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Le {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Ge {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Not {
+        dst: u32,
+        a: u32,
+    },
+    And {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Or {
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Id {
+        dst: u32,
+        a: u32,
+    },
+    Nop,
+    Jmp {
+        target: u32,
+    },
+    Br {
+        cond: u32,
+        then: u32,
+        otherwise: u32,
+    },
+    /// `len` `(dst, src)` pairs in the pool starting at `copies`, assigned in parallel. These
+    /// are the values along one edge of the phis of the block it leads to, and are always
+    /// followed by a `Goto` to that block. A `src` of `NONE` leaves `dst` unassigned.
+    Phis {
+        copies: u32,
+        len: u32,
+    },
+    /// Fails when `slot` hasn't been assigned, ahead of an instruction reading it.
+    Check {
+        slot: u32,
+    },
+    /// Control passing to the next block without a Bril instruction.
+    Goto {
+        target: u32,
+    },
+    /// Argument slots are `len` entries of the pool starting at `args`.
+    Call {
+        func: u32,
+        args: u32,
+        len: u32,
+        dst: u32,
+    },
+    Ret {
+        value: u32,
+    },
+    /// Falling off the end of the function.
+    End,
+    /// Each of the `len` pool entries starting at `args` is a slot followed by 1 for booleans.
+    Print {
+        args: u32,
+        len: u32,
+    },
+    Speculate,
+    Commit,
+    Guard {
+        cond: u32,
+        target: u32,
+    },
+}
+
+struct CompiledFunction {
+    name: FuncId,
+    args: Vec<Type>,
+    returns_value: bool,
+    slots: usize,
+    /// Name of the variable in each slot.
+    variables: Vec<VarId>,
+    code: Vec<Op>,
+    /// Name and code offset of every block, in program order.
+    blocks: Vec<(LabelId, u32)>,
+}
+
+/// A program compiled to bytecode, ready to run any number of times.
+pub struct Module {
+    functions: Vec<CompiledFunction>,
+    /// Operand lists of calls, prints and phis.
+    pool: Vec<u32>,
+    main: Option<usize>,
+}
+
+struct Frame {
+    func: usize,
+    pc: usize,
+    base: usize,
+    /// Slot in the caller's frame receiving the return value.
+    dst: u32,
+}
+
+/// Hooks into control flow while the VM runs. Everything is inlined away for `()`.
+trait Observer {
+    fn call(&mut self, func: usize);
+    /// Control moves to `target` in the current function through a branch or fallthrough.
+    fn jump(&mut self, target: u32);
+    fn ret(&mut self);
+}
+
+impl Observer for () {
+    #[inline(always)]
+    fn call(&mut self, _func: usize) {}
+
+    #[inline(always)]
+    fn jump(&mut self, _target: u32) {}
+
+    #[inline(always)]
+    fn ret(&mut self) {}
+}
+
+/// The index of the block starting at each code offset, per function.
+fn block_starts(module: &Module) -> Vec<Vec<Option<usize>>> {
+    module
+        .functions
+        .iter()
+        .map(|func| {
+            let mut starts = vec![None; func.code.len()];
+            for (i, (_, offset)) in func.blocks.iter().enumerate() {
+                starts[*offset as usize] = Some(i);
+            }
+            starts
+        })
+        .collect()
+}
+
+/// Counts calls, block executions and edges by index while running.
+struct Profiler {
+    /// Block index starting at each code offset, per function.
+    block_at: Vec<Vec<Option<usize>>>,
+    calls: Vec<u64>,
+    blocks: Vec<Vec<u64>>,
+    edges: Vec<HashMap<(usize, usize), u64>>,
+    /// Function and current block of every active frame.
+    stack: Vec<(usize, usize)>,
+}
+
+impl Profiler {
+    fn new(module: &Module) -> Profiler {
+        Profiler {
+            block_at: block_starts(module),
+            calls: vec![0; module.functions.len()],
+            blocks: module
+                .functions
+                .iter()
+                .map(|func| vec![0; func.blocks.len()])
+                .collect(),
+            edges: vec![HashMap::new(); module.functions.len()],
+            stack: vec![],
+        }
+    }
+
+    fn into_profile(self, module: &Module) -> Profile {
+        let mut profile = Profile::default();
+        for (i, func) in module.functions.iter().enumerate() {
+            let name = |block: usize| func.blocks[block].0.to_string();
+            profile.functions.insert(
+                func.name.to_string(),
+                FunctionProfile {
+                    calls: self.calls[i],
+                    blocks: (0..func.blocks.len())
+                        .filter(|b| self.blocks[i][*b] > 0)
+                        .map(|b| (name(b), self.blocks[i][b]))
+                        .collect(),
+                    edges: self.edges[i]
+                        .iter()
+                        .map(|((from, to), count)| ((name(*from), name(*to)), *count))
+                        .collect(),
+                },
+            );
+        }
+        profile
+    }
+}
+
+impl Observer for Profiler {
+    fn call(&mut self, func: usize) {
+        self.calls[func] += 1;
+        if let Some(entry) = self.block_at[func].first().copied().flatten() {
+            self.blocks[func][entry] += 1;
+            self.stack.push((func, entry));
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        // Phi copies on an edge are skipped; the jump that follows them lands on the block
+        let (func, from) = *self.stack.last().unwrap();
+        if let Some(to) = self.block_at[func][target as usize] {
+            self.blocks[func][to] += 1;
+            *self.edges[func].entry((from, to)).or_default() += 1;
+            self.stack.last_mut().unwrap().1 = to;
+        }
+    }
+
+    fn ret(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// Records the blocks the first call of one function runs through, up to a limit.
+struct Tracer {
+    block_at: Vec<Vec<Option<usize>>>,
+    func: usize,
+    limit: usize,
+    depth: usize,
+    /// Frame depth of the call being traced.
+    traced: Option<usize>,
+    done: bool,
+    path: Vec<usize>,
+}
+
+impl Observer for Tracer {
+    fn call(&mut self, func: usize) {
+        self.depth += 1;
+        if func == self.func && !self.done && self.traced.is_none() {
+            self.traced = Some(self.depth);
+            self.path
+                .extend(self.block_at[func].first().copied().flatten());
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        if self.done || self.traced != Some(self.depth) {
+            return;
+        }
+        if let Some(block) = self.block_at[self.func][target as usize] {
+            self.path.push(block);
+            self.done = self.path.len() >= self.limit;
+        }
+    }
+
+    fn ret(&mut self) {
+        if self.traced == Some(self.depth) {
+            self.done = true;
+        }
+        self.depth -= 1;
+    }
+}
+
+fn print_value(out: &mut dyn Write, value: i64, is_bool: bool) -> std::io::Result<()> {
+    if is_bool {
+        write!(out, "{}", value != 0)
+    } else {
+        write!(out, "{}", value)
+    }
+}
+
+impl Module {
+    /// Converts command line arguments to values for `main`'s parameters.
+    pub fn parse_args(&self, args: &[String]) -> Result<Vec<Value>, Box<dyn Error>> {
+        let main = match self.main {
+            Some(main) => &self.functions[main],
+            None => return Err("no main function".into()),
+        };
+        if args.len() != main.args.len() {
+            return Err(format!(
+                "expected {} argument(s) to main, got {}",
+                main.args.len(),
+                args.len()
+            )
+            .into());
+        }
+        main.args
+            .iter()
+            .zip(args.iter())
+            .map(|(ty, arg)| match ty {
+                Type::Int => arg
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| format!("invalid integer argument {}", arg).into()),
+                Type::Bool => match arg.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => Err(format!("invalid boolean argument {}", arg).into()),
+                },
+            })
+            .collect()
+    }
+
+    /// Runs `main` with `args`, writing printed values to `out`. Returns the number of Bril
+    /// instructions executed.
+    pub fn run(&self, args: &[Value], out: &mut dyn Write) -> Result<u64, Box<dyn Error>> {
+        self.execute(args, out, &mut (), u64::MAX)
+    }
+
+    /// Like `run`, but fails once more than `limit` instructions have executed, for programs
+    /// that might not terminate.
+    pub fn run_limited(
+        &self,
+        args: &[Value],
+        out: &mut dyn Write,
+        limit: u64,
+    ) -> Result<u64, Box<dyn Error>> {
+        self.execute(args, out, &mut (), limit)
+    }
+
+    /// Like `run`, also counting how often each function, block and edge was executed.
+    pub fn run_profiled(
+        &self,
+        args: &[Value],
+        out: &mut dyn Write,
+    ) -> Result<(u64, Profile), Box<dyn Error>> {
+        let mut profiler = Profiler::new(self);
+        let count = self.execute(args, out, &mut profiler, u64::MAX)?;
+        Ok((count, profiler.into_profile(self)))
+    }
+
+    /// Runs `main` with `args` and returns the names of the first `limit` blocks the first call
+    /// of `func` runs through, in order.
+    pub fn record_trace(
+        &self,
+        args: &[Value],
+        out: &mut dyn Write,
+        func: &str,
+        limit: usize,
+    ) -> Result<Vec<LabelId>, Box<dyn Error>> {
+        let index = self
+            .functions
+            .iter()
+            .position(|f| f.name == func)
+            .ok_or_else(|| format!("no function @{}", func))?;
+        let mut tracer = Tracer {
+            block_at: block_starts(self),
+            func: index,
+            limit,
+            depth: 0,
+            traced: None,
+            done: false,
+            path: vec![],
+        };
+        self.execute(args, out, &mut tracer, u64::MAX)?;
+        let blocks = &self.functions[index].blocks;
+        Ok(tracer.path.iter().map(|block| blocks[*block].0).collect())
+    }
+
+    fn execute<O: Observer>(
+        &self,
+        args: &[Value],
+        out: &mut dyn Write,
+        observer: &mut O,
+        limit: u64,
+    ) -> Result<u64, Box<dyn Error>> {
+        let main = self.main.ok_or("no main function")?;
+        observer.call(main);
+        let mut regs: Vec<i64> = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Int(n) => *n as i64,
+                Value::Bool(b) => *b as i64,
+            })
+            .collect();
+        regs.resize(self.functions[main].slots, 0);
+        // Whether each slot has been assigned, since reading one that hasn't is an error
+        let mut defined: Vec<bool> = vec![true; args.len()];
+        defined.resize(regs.len(), false);
+
+        let mut frames: Vec<Frame> = vec![];
+        let mut func = main;
+        let mut code = &self.functions[main].code;
+        let mut base = 0;
+        let mut pc = 0;
+        let mut count: u64 = 0;
+        let mut scratch: Vec<(i64, bool)> = vec![];
+        // Frame depth and saved slots of each speculation in progress, innermost last
+        let mut speculations: Vec<(usize, Vec<i64>, Vec<bool>)> = vec![];
+        let pool = &self.pool;
+
+        macro_rules! set {
+            ($dst:expr, $value:expr) => {{
+                let value = $value;
+                regs[base + $dst as usize] = value;
+                defined[base + $dst as usize] = true;
+            }};
+        }
+        macro_rules! binary {
+            ($dst:expr, $a:expr, $b:expr, $f:expr) => {{
+                let (a, b) = (regs[base + $a as usize], regs[base + $b as usize]);
+                set!($dst, $f(a, b));
+            }};
+        }
+
+        loop {
+            let op = code[pc];
+            pc += 1;
+            count += 1;
+            if count > limit {
+                return Err(format!("executed more than {} instructions", limit).into());
+            }
+            match op {
+                Op::Const { dst, value } => set!(dst, value),
+                Op::Add { dst, a, b } => binary!(dst, a, b, i64::wrapping_add),
+                Op::Sub { dst, a, b } => binary!(dst, a, b, i64::wrapping_sub),
+                Op::Mul { dst, a, b } => binary!(dst, a, b, i64::wrapping_mul),
+                Op::Div { dst, a, b } => {
+                    if regs[base + b as usize] == 0 {
+                        return Err("division by zero".into());
+                    }
+                    binary!(dst, a, b, i64::wrapping_div)
+                }
+                Op::Eq { dst, a, b } => binary!(dst, a, b, |a, b| (a == b) as i64),
+                Op::Lt { dst, a, b } => binary!(dst, a, b, |a, b| (a < b) as i64),
+                Op::Gt { dst, a, b } => binary!(dst, a, b, |a, b| (a > b) as i64),
+                Op::Le { dst, a, b } => binary!(dst, a, b, |a, b| (a <= b) as i64),
+                Op::Ge { dst, a, b } => binary!(dst, a, b, |a, b| (a >= b) as i64),
+                Op::Not { dst, a } => set!(dst, regs[base + a as usize] ^ 1),
+                Op::And { dst, a, b } => binary!(dst, a, b, |a, b| a & b),
+                Op::Or { dst, a, b } => binary!(dst, a, b, |a, b| a | b),
+                Op::Id { dst, a } => set!(dst, regs[base + a as usize]),
+                Op::Nop => {}
+                Op::Jmp { target } => {
+                    observer.jump(target);
+                    pc = target as usize
+                }
+                Op::Br {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    let target = if regs[base + cond as usize] != 0 {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    observer.jump(target);
+                    pc = target as usize
+                }
+                Op::Phis { copies, len } => {
+                    let copies = &pool[copies as usize..(copies + 2 * len) as usize];
+                    scratch.clear();
+                    scratch.extend(copies.chunks(2).map(|pair| match pair[1] {
+                        NONE => (0, false),
+                        src => (regs[base + src as usize], defined[base + src as usize]),
+                    }));
+                    for (pair, &(value, is_defined)) in copies.chunks(2).zip(scratch.iter()) {
+                        regs[base + pair[0] as usize] = value;
+                        defined[base + pair[0] as usize] = is_defined;
+                    }
+                    count = count + len as u64 - 1;
+                }
+                Op::Check { slot } => {
+                    if !defined[base + slot as usize] {
+                        let name = self.functions[func].variables[slot as usize];
+                        return Err(format!("undefined variable {}", name).into());
+                    }
+                    count -= 1;
+                }
+                Op::Goto { target } => {
+                    observer.jump(target);
+                    pc = target as usize;
+                    count -= 1;
+                }
+                Op::Call {
+                    func: callee,
+                    args,
+                    len,
+                    dst,
+                } => {
+                    let new_base = regs.len();
+                    regs.resize(new_base + self.functions[callee as usize].slots, 0);
+                    defined.resize(regs.len(), false);
+                    for (i, arg) in pool[args as usize..(args + len) as usize]
+                        .iter()
+                        .enumerate()
+                    {
+                        regs[new_base + i] = regs[base + *arg as usize];
+                        defined[new_base + i] = true;
+                    }
+                    frames.push(Frame {
+                        func,
+                        pc,
+                        base,
+                        dst,
+                    });
+                    func = callee as usize;
+                    observer.call(func);
+                    code = &self.functions[func].code;
+                    base = new_base;
+                    pc = 0;
+                }
+                Op::Ret { .. } | Op::End => {
+                    let value = match op {
+                        Op::Ret { value } if value != NONE => Some(regs[base + value as usize]),
+                        Op::End => {
+                            count -= 1;
+                            if self.functions[func].returns_value {
+                                return Err(format!(
+                                    "@{} ended without returning a value",
+                                    self.functions[func].name
+                                )
+                                .into());
+                            }
+                            None
+                        }
+                        _ => None,
+                    };
+                    if speculations
+                        .last()
+                        .is_some_and(|(depth, _, _)| *depth == frames.len())
+                    {
+                        return Err(format!(
+                            "@{} returned while speculating",
+                            self.functions[func].name
+                        )
+                        .into());
+                    }
+                    regs.truncate(base);
+                    defined.truncate(base);
+                    observer.ret();
+                    let frame = match frames.pop() {
+                        Some(frame) => frame,
+                        None => break,
+                    };
+                    func = frame.func;
+                    code = &self.functions[func].code;
+                    base = frame.base;
+                    pc = frame.pc;
+                    if let (Some(value), true) = (value, frame.dst != NONE) {
+                        set!(frame.dst, value);
+                    }
+                }
+                Op::Speculate => {
+                    let slots = self.functions[func].slots;
+                    speculations.push((
+                        frames.len(),
+                        regs[base..base + slots].to_vec(),
+                        defined[base..base + slots].to_vec(),
+                    ));
+                }
+                Op::Commit => match speculations.last() {
+                    Some((depth, _, _)) if *depth == frames.len() => {
+                        speculations.pop();
+                    }
+                    _ => return Err("commit without speculate".into()),
+                },
+                Op::Guard { cond, target } => {
+                    if regs[base + cond as usize] == 0 {
+                        let (saved, saved_defined) = match speculations.pop() {
+                            Some((depth, saved, saved_defined)) if depth == frames.len() => {
+                                (saved, saved_defined)
+                            }
+                            _ => return Err("guard without speculate".into()),
+                        };
+                        regs[base..base + saved.len()].copy_from_slice(&saved);
+                        defined[base..base + saved.len()].copy_from_slice(&saved_defined);
+                        observer.jump(target);
+                        pc = target as usize;
+                    }
+                }
+                Op::Print { args, len } => {
+                    let args = &pool[args as usize..(args + 2 * len) as usize];
+                    for (i, arg) in args.chunks(2).enumerate() {
+                        if i > 0 {
+                            write!(out, " ")?;
+                        }
+                        print_value(out, regs[base + arg[0] as usize], arg[1] == 1)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::analysis::dataflow::{solve, DefinedVariables};
+use crate::core::{
+    at, ArithmeticOp, BasicBlock, ComparisonOp, ControlOp, FuncId, Function, LabelId, LogicOp,
+    MiscOp, OpCode, Position, Program, SpeculationOp, Type, Value, VarId,
+};
+use crate::parser::control_flow_graph;
+use crate::ssa::variable_types;
+use crate::vm::{CompiledFunction, Module, Op, NONE};
+
+/// Resolves variable names to frame slots and labels to code offsets for one function.
+struct Layout<'a> {
+    func: &'a Function,
+    slots: HashMap<VarId, u32>,
+    types: HashMap<VarId, Type>,
+    offsets: HashMap<LabelId, u32>,
+    /// Slots read by the instruction at each block and index that may not be assigned yet, in
+    /// the order they're checked before it runs.
+    checks: HashMap<(LabelId, usize), Vec<u32>>,
+    /// Code offset of the phi copies for each edge into a block with phis.
+    edges: HashMap<(LabelId, LabelId), u32>,
+}
+
+impl<'a> Layout<'a> {
+    /// Blocks are laid out in program order, each followed by a `Goto` or `End` when it doesn't
+    /// end in a terminator. Reads of variables that aren't assigned on every path to them are
+    /// preceded by a `Check` of their slot. The copies for each edge into a block with phis come after all the
+    /// blocks, two ops per edge.
+    fn new(func: &'a Function) -> Layout<'a> {
+        let mut slots: HashMap<VarId, u32> = HashMap::new();
+        let vars = func.args.iter().map(|(arg, _)| arg).chain(
+            func.blocks
+                .iter()
+                .flat_map(|block| block.instructions.iter())
+                .flat_map(|instr| instr.dst.iter().chain(instr.uses().iter())),
+        );
+        for &var in vars {
+            let next = slots.len() as u32;
+            slots.entry(var).or_insert(next);
+        }
+
+        let defined = solve(func, &DefinedVariables);
+        let mut checks: HashMap<(LabelId, usize), Vec<u32>> = HashMap::new();
+        for block in func.blocks.iter() {
+            let facts = defined.instruction_facts(&DefinedVariables, block);
+            for (i, (instr, (before, _))) in block.instructions.iter().zip(facts).enumerate() {
+                let mut unassigned: Vec<u32> = instr
+                    .uses()
+                    .iter()
+                    .filter(|arg| !before.contains(arg))
+                    .map(|arg| slots[arg])
+                    .collect();
+                unassigned.dedup();
+                if instr.op != OpCode::Phi && !unassigned.is_empty() {
+                    checks.insert((block.name, i), unassigned);
+                }
+            }
+        }
+
+        let mut offsets: HashMap<LabelId, u32> = HashMap::new();
+        let mut offset = 0;
+        for block in func.blocks.iter() {
+            offsets.insert(block.name, offset);
+            let ops = block
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, instr)| instr.op != OpCode::Phi)
+                .map(|(i, _)| 1 + checks.get(&(block.name, i)).map_or(0, Vec::len))
+                .sum::<usize>();
+            let falls_through = !block
+                .instructions
+                .last()
+                .is_some_and(|instr| instr.is_terminator());
+            offset += (ops + falls_through as usize) as u32;
+        }
+
+        let cfg = control_flow_graph(func);
+        let blocks: HashMap<LabelId, &BasicBlock> = func
+            .blocks
+            .iter()
+            .map(|block| (block.name, block))
+            .collect();
+        let mut edges: HashMap<(LabelId, LabelId), u32> = HashMap::new();
+        for block in func.blocks.iter() {
+            for succ in cfg[&block.name].iter() {
+                let Some(succ) = blocks.get(succ) else {
+                    continue;
+                };
+                let has_phis = succ
+                    .instructions
+                    .iter()
+                    .any(|instr| instr.op == OpCode::Phi);
+                if has_phis && !edges.contains_key(&(block.name, succ.name)) {
+                    edges.insert((block.name, succ.name), offset);
+                    offset += 2;
+                }
+            }
+        }
+
+        Layout {
+            func,
+            slots,
+            types: variable_types(func),
+            offsets,
+            checks,
+            edges,
+        }
+    }
+
+    fn slot(&self, var: VarId) -> u32 {
+        self.slots[&var]
+    }
+
+    /// Offset to go to from the end of block `from` to `to`, for an instruction at `pos`.
+    fn target(
+        &self,
+        from: LabelId,
+        to: LabelId,
+        pos: Option<Position>,
+    ) -> Result<u32, Box<dyn Error>> {
+        if let Some(offset) = self.edges.get(&(from, to)) {
+            return Ok(*offset);
+        }
+        match self.offsets.get(&to) {
+            Some(offset) => Ok(*offset),
+            None => Err(format!(
+                "@{}{} jumps to unknown label .{}",
+                self.func.name,
+                at(pos),
+                to
+            )
+            .into()),
+        }
+    }
+}
+
+impl Module {
+    /// Compiles every function of `prog` to bytecode.
+    pub fn new(prog: &Program) -> Result<Module, Box<dyn Error>> {
+        let indices: HashMap<FuncId, usize> = prog
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, func)| (func.name, i))
+            .collect();
+        let mut pool: Vec<u32> = vec![];
+        let mut functions: Vec<CompiledFunction> = vec![];
+        for func in prog.functions.iter() {
+            functions.push(compile_function(prog, &indices, func, &mut pool)?);
+        }
+        Ok(Module {
+            functions,
+            pool,
+            main: prog.functions.iter().position(|func| func.name == "main"),
+        })
+    }
+}
+
+fn compile_function(
+    prog: &Program,
+    indices: &HashMap<FuncId, usize>,
+    func: &Function,
+    pool: &mut Vec<u32>,
+) -> Result<CompiledFunction, Box<dyn Error>> {
+    let layout = Layout::new(func);
+    let mut code: Vec<Op> = vec![];
+    for (i, block) in func.blocks.iter().enumerate() {
+        for (index, instr) in block.instructions.iter().enumerate() {
+            if let Some(slots) = layout.checks.get(&(block.name, index)) {
+                code.extend(slots.iter().map(|&slot| Op::Check { slot }));
+            }
+            let dst = instr.dst.map_or(NONE, |dst| layout.slot(dst));
+            let args: Vec<u32> = instr.uses().iter().map(|&arg| layout.slot(arg)).collect();
+            let binary = |a: &[u32]| (a[0], a[1]);
+            let op = match &instr.op {
+                OpCode::Phi => continue,
+                OpCode::Const => Op::Const {
+                    dst,
+                    value: match instr.value.as_ref().unwrap() {
+                        Value::Int(n) => *n as i64,
+                        Value::Bool(b) => *b as i64,
+                    },
+                },
+                OpCode::Arithmetic(aop) => {
+                    let (a, b) = binary(&args);
+                    match aop {
+                        ArithmeticOp::Add => Op::Add { dst, a, b },
+                        ArithmeticOp::Sub => Op::Sub { dst, a, b },
+                        ArithmeticOp::Mul => Op::Mul { dst, a, b },
+                        ArithmeticOp::Div => Op::Div { dst, a, b },
+                    }
+                }
+                OpCode::Comparison(cop) => {
+                    let (a, b) = binary(&args);
+                    match cop {
+                        ComparisonOp::Eq => Op::Eq { dst, a, b },
+                        ComparisonOp::Lt => Op::Lt { dst, a, b },
+                        ComparisonOp::Gt => Op::Gt { dst, a, b },
+                        ComparisonOp::Le => Op::Le { dst, a, b },
+                        ComparisonOp::Ge => Op::Ge { dst, a, b },
+                    }
+                }
+                OpCode::Logic(LogicOp::Not) => Op::Not { dst, a: args[0] },
+                OpCode::Logic(LogicOp::And) => {
+                    let (a, b) = binary(&args);
+                    Op::And { dst, a, b }
+                }
+                OpCode::Logic(LogicOp::Or) => {
+                    let (a, b) = binary(&args);
+                    Op::Or { dst, a, b }
+                }
+                OpCode::Misc(MiscOp::Id) => Op::Id { dst, a: args[0] },
+                OpCode::Misc(MiscOp::Nop) => Op::Nop,
+                OpCode::Misc(MiscOp::Print) => {
+                    let start = pool.len() as u32;
+                    for (arg, slot) in instr.uses().iter().zip(args.iter()) {
+                        let is_bool = match layout.types.get(arg) {
+                            Some(ty) => *ty == Type::Bool,
+                            None => {
+                                return Err(format!(
+                                    "@{}{} prints undefined variable {}",
+                                    func.name,
+                                    at(instr.pos),
+                                    arg
+                                )
+                                .into())
+                            }
+                        };
+                        pool.extend([*slot, is_bool as u32]);
+                    }
+                    Op::Print {
+                        args: start,
+                        len: args.len() as u32,
+                    }
+                }
+                OpCode::Control(ControlOp::Jmp) => Op::Jmp {
+                    target: layout.target(
+                        block.name,
+                        instr.labels.as_ref().unwrap()[0],
+                        instr.pos,
+                    )?,
+                },
+                OpCode::Control(ControlOp::Br) => {
+                    let labels = instr.labels.as_ref().unwrap();
+                    Op::Br {
+                        cond: args[0],
+                        then: layout.target(block.name, labels[0], instr.pos)?,
+                        otherwise: layout.target(block.name, labels[1], instr.pos)?,
+                    }
+                }
+                OpCode::Control(ControlOp::Call) => {
+                    let callee = &instr.funcs.as_ref().unwrap()[0];
+                    let index = match indices.get(callee) {
+                        Some(index) => *index,
+                        None => {
+                            return Err(format!(
+                                "@{}{} calls unknown function @{}",
+                                func.name,
+                                at(instr.pos),
+                                callee
+                            )
+                            .into())
+                        }
+                    };
+                    if prog.functions[index].args.len() != args.len() {
+                        return Err(format!(
+                            "@{}{} calls @{} with {} argument(s)",
+                            func.name,
+                            at(instr.pos),
+                            callee,
+                            args.len()
+                        )
+                        .into());
+                    }
+                    let start = pool.len() as u32;
+                    pool.extend(args.iter());
+                    Op::Call {
+                        func: index as u32,
+                        args: start,
+                        len: args.len() as u32,
+                        dst,
+                    }
+                }
+                OpCode::Control(ControlOp::Ret) => Op::Ret {
+                    value: args.first().copied().unwrap_or(NONE),
+                },
+                OpCode::Speculation(SpeculationOp::Speculate) => Op::Speculate,
+                OpCode::Speculation(SpeculationOp::Commit) => Op::Commit,
+                OpCode::Speculation(SpeculationOp::Guard) => Op::Guard {
+                    cond: args[0],
+                    target: layout.target(
+                        block.name,
+                        instr.labels.as_ref().unwrap()[0],
+                        instr.pos,
+                    )?,
+                },
+            };
+            code.push(op);
+        }
+        if !block
+            .instructions
+            .last()
+            .is_some_and(|instr| instr.is_terminator())
+        {
+            code.push(match func.blocks.get(i + 1) {
+                Some(next) => Op::Goto {
+                    target: layout.target(block.name, next.name, None)?,
+                },
+                None => Op::End,
+            });
+        }
+    }
+    // An empty function has no blocks to fall off
+    if func.blocks.is_empty() {
+        code.push(Op::End);
+    }
+
+    let blocks: HashMap<LabelId, &BasicBlock> = func
+        .blocks
+        .iter()
+        .map(|block| (block.name, block))
+        .collect();
+    let mut edges: Vec<(&(LabelId, LabelId), &u32)> = layout.edges.iter().collect();
+    edges.sort_by_key(|(_, offset)| **offset);
+    for (&(from, to), _) in edges {
+        let block = blocks[&to];
+        let start = pool.len() as u32;
+        let mut len = 0;
+        for phi in block.instructions.iter().filter(|i| i.op == OpCode::Phi) {
+            let labels = phi.labels.as_ref().unwrap();
+            // A phi without a defined value for this edge leaves its destination unassigned
+            let src = labels
+                .iter()
+                .position(|&label| label == from)
+                .map(|i| phi.uses()[i])
+                .filter(|src| layout.types.contains_key(src))
+                .map_or(NONE, |src| layout.slot(src));
+            pool.extend([layout.slot(phi.dst.unwrap()), src]);
+            len += 1;
+        }
+        code.push(Op::Phis { copies: start, len });
+        code.push(Op::Goto {
+            target: layout.offsets[&to],
+        });
+    }
+    let mut variables: Vec<(u32, VarId)> = layout
+        .slots
+        .iter()
+        .map(|(&var, &slot)| (slot, var))
+        .collect();
+    variables.sort_by_key(|&(slot, _)| slot);
+    Ok(CompiledFunction {
+        name: func.name,
+        variables: variables.into_iter().map(|(_, var)| var).collect(),
+        args: func.args.iter().map(|(_, ty)| ty.clone()).collect(),
+        returns_value: func.ret_type.is_some(),
+        slots: layout.slots.len(),
+        code,
+        blocks: func
+            .blocks
+            .iter()
+            .map(|block| (block.name, layout.offsets[&block.name]))
+            .collect(),
+    })
+}
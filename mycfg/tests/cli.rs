@@ -0,0 +1,55 @@
+//! The command line stops quietly and successfully when whoever reads its output stops early.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use common::bril_to_json;
+
+const COUNTER: &str = "@main {
+  i: int = const 0;
+  one: int = const 1;
+  n: int = const 1000000;
+.loop:
+  print i;
+  i: int = add i one;
+  more: bool = lt i n;
+  br more .loop .done;
+.done:
+}
+";
+
+#[test]
+fn closed_output_is_not_an_error() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mycfg"))
+        .arg("vm")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let program = bril_to_json(COUNTER).unwrap().dump();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(program.as_bytes())
+        .unwrap();
+    let mut first = [0; 2];
+    let mut stdout = child.stdout.take().unwrap();
+    stdout.read_exact(&mut first).unwrap();
+    assert_eq!(&first, b"0\n");
+    drop(stdout);
+
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    let status = child.wait().unwrap();
+    assert!(status.success(), "{}: {}", status, stderr);
+    assert_eq!(stderr, "");
+}
@@ -0,0 +1,120 @@
+//! Backends keep distinct Bril names distinct, reject programs they can't compile instead of
+//! panicking, and compile division to fail like the VM does.
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::process::{Command, Output};
+
+use mycfg::codegen;
+use mycfg::core::Program;
+use mycfg::parser;
+
+use common::bril_to_json;
+
+fn parse(text: &str) -> Program {
+    parser::parse_program(&bril_to_json(text).unwrap()).unwrap()
+}
+
+#[test]
+fn mangled_names_stay_distinct() {
+    let prog = parse(
+        "@main {
+  a.b: int = const 1;
+  a_2e_b: int = const 2;
+  print a.b a_2e_b;
+}
+",
+    );
+    let c = codegen::emit_c(&prog).unwrap();
+    assert!(c.contains("v_a_2e_b "), "{}", c);
+    assert!(c.contains("v_a__2e__b "), "{}", c);
+}
+
+#[test]
+fn undefined_variables_are_reported() {
+    let prog = parse(
+        "@main {
+  x: int = const 1;
+  y: int = add x z;
+  print y;
+}
+",
+    );
+    let err = codegen::emit_x86(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 3:3 uses undefined variable z");
+    let err = codegen::emit_llvm(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 3:3 uses undefined variable z");
+    let err = codegen::emit_c(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 3:3 uses undefined variable z");
+    let err = codegen::emit_wat(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 3:3 uses undefined variable z");
+
+    // Read only by an effect operation
+    let prog = parse(
+        "@main {
+  print z;
+}
+",
+    );
+    let err = codegen::emit_llvm(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 2:3 uses undefined variable z");
+    let err = codegen::emit_c(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 2:3 uses undefined variable z");
+    let err = codegen::emit_wat(&prog).unwrap_err().to_string();
+    assert_eq!(err, "@main at 2:3 uses undefined variable z");
+}
+
+const DIVISION: &str = "@main(a: int, b: int) {
+  print a;
+  q: int = div a b;
+  print q;
+}
+";
+
+/// Assembles and links `asm` with the system C compiler and runs it with `args`, or returns
+/// `None` if there is no C compiler.
+fn run_assembly(asm: &str, name: &str, args: &[&str]) -> Option<Output> {
+    let dir = env::temp_dir().join(format!("mycfg-codegen-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("{}.s", name));
+    let binary = dir.join(name);
+    fs::write(&source, asm).unwrap();
+    let status = match Command::new("cc")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&source)
+        .status()
+    {
+        Ok(status) => status,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => panic!("couldn't run cc: {}", err),
+    };
+    assert!(status.success(), "cc failed on:\n{}", asm);
+    Some(Command::new(&binary).args(args).output().unwrap())
+}
+
+#[test]
+fn x86_division_fails_like_the_vm() {
+    let asm = codegen::emit_x86(&parse(DIVISION)).unwrap();
+    let Some(output) = run_assembly(&asm, "div_by_zero", &["7", "0"]) else {
+        eprintln!("skipped: no cc to assemble with");
+        return;
+    };
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "7\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: division by zero\n"
+    );
+
+    let min = i64::MIN.to_string();
+    let output = run_assembly(&asm, "div_overflow", &[&min, "-1"]).unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n{}\n", min, min)
+    );
+}
@@ -0,0 +1,300 @@
+//! Shared by the integration tests: the corpus under `tests/programs`, a reader for Bril's text
+//! format, so test programs can be written by hand without `bril2json`, and running programs
+//! before and after every pass.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use json::{array, object, JsonValue};
+
+use mycfg::core::{Function, Program};
+use mycfg::optimize::{Pass, PASSES};
+use mycfg::vm::Module;
+
+/// Instructions a test run may execute, so that a pass breaking a loop fails instead of hanging.
+pub const RUN_LIMIT: u64 = 10_000_000;
+/// Blocks of `main` recorded for trace speculation, as `mycfg trace` does.
+const TRACE_LENGTH: usize = 64;
+
+/// A program of the corpus and the arguments its `main` runs with.
+pub struct TestProgram {
+    pub name: String,
+    pub path: PathBuf,
+    pub json: JsonValue,
+    pub args: Vec<String>,
+}
+
+pub fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("programs")
+}
+
+/// Every `.json` and `.bril` program under `tests/programs`, sorted by name. Arguments come from
+/// an `ARGS:` comment like in turnt, or an `.args` file next to a JSON program.
+pub fn corpus() -> Vec<TestProgram> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(corpus_dir())
+        .expect("tests/programs is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| matches!(extension(path), "json" | "bril"))
+        .collect();
+    paths.sort();
+    paths.into_iter().map(|path| read_program(&path)).collect()
+}
+
+fn extension(path: &Path) -> &str {
+    path.extension().and_then(|ext| ext.to_str()).unwrap_or("")
+}
+
+pub fn read_program(path: &Path) -> TestProgram {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("couldn't read {}: {}", path.display(), err));
+    let (json, args) = if extension(path) == "bril" {
+        let args = contents
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix('#'))
+            .filter_map(|comment| comment.trim().strip_prefix("ARGS:"))
+            .flat_map(|args| args.split_whitespace().map(String::from))
+            .collect();
+        let json = bril_to_json(&contents)
+            .unwrap_or_else(|err| panic!("couldn't parse {}: {}", path.display(), err));
+        (json, args)
+    } else {
+        let json = json::parse(&contents)
+            .unwrap_or_else(|err| panic!("couldn't parse {}: {}", path.display(), err));
+        let args = fs::read_to_string(path.with_extension("args"))
+            .map(|args| args.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        (json, args)
+    };
+    TestProgram {
+        name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+        path: path.to_path_buf(),
+        json,
+        args,
+    }
+}
+
+/// Translates Bril's text format to its JSON format like `bril2json -p`, with the position of
+/// every instruction and label, for the core language and speculation.
+pub fn bril_to_json(text: &str) -> Result<JsonValue, String> {
+    let mut functions = array![];
+    let mut function: Option<JsonValue> = None;
+    for (row, line) in text.lines().enumerate() {
+        let col = line.len() - line.trim_start().len() + 1;
+        let pos = object! { "row" => row + 1, "col" => col };
+        let line = line.split('#').next().unwrap().trim();
+        let error = |message: &str| format!("line {}: {}", row + 1, message);
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('@') {
+            let header = header
+                .strip_suffix('{')
+                .ok_or_else(|| error("expected `{` after the function header"))?
+                .trim();
+            function = Some(parse_header(header).map_err(|err| error(&err))?);
+            continue;
+        }
+        let func = function
+            .as_mut()
+            .ok_or_else(|| error("instruction outside a function"))?;
+        if line == "}" {
+            functions.push(function.take().unwrap()).unwrap();
+        } else if let Some(label) = line.strip_prefix('.') {
+            let label = label
+                .strip_suffix(':')
+                .ok_or_else(|| error("expected `:` after the label"))?;
+            func["instrs"]
+                .push(object! { "label" => label, "pos" => pos })
+                .unwrap();
+        } else {
+            let instr = line
+                .strip_suffix(';')
+                .ok_or_else(|| error("expected `;` after the instruction"))?;
+            let mut instr = parse_instruction(instr).map_err(|err| error(&err))?;
+            instr["pos"] = pos;
+            func["instrs"].push(instr).unwrap();
+        }
+    }
+    if function.is_some() {
+        return Err("unterminated function".into());
+    }
+    Ok(object! { "functions" => functions })
+}
+
+/// `name(a: int, b: bool): int`, with the arguments and the return type optional.
+fn parse_header(header: &str) -> Result<JsonValue, String> {
+    let (signature, ty) = match header.rsplit_once(':') {
+        Some((signature, ty)) if !ty.contains(')') => (signature.trim(), Some(ty.trim())),
+        _ => (header, None),
+    };
+    let (name, args) = match signature.split_once('(') {
+        Some((name, args)) => (
+            name.trim(),
+            args.strip_suffix(')')
+                .ok_or("expected `)` after the arguments")?,
+        ),
+        None => (signature, ""),
+    };
+    let mut func = object! { "name" => name, "instrs" => array![] };
+    let mut json_args = array![];
+    for arg in args.split(',').map(str::trim).filter(|arg| !arg.is_empty()) {
+        let (name, ty) = arg
+            .split_once(':')
+            .ok_or_else(|| format!("argument {} has no type", arg))?;
+        json_args
+            .push(object! { "name" => name.trim(), "type" => ty.trim() })
+            .unwrap();
+    }
+    if !json_args.is_empty() {
+        func["args"] = json_args;
+    }
+    if let Some(ty) = ty {
+        func["type"] = ty.into();
+    }
+    Ok(func)
+}
+
+/// `dest: type = op args...` or `op args...`, where labels start with `.` and functions with `@`.
+fn parse_instruction(instr: &str) -> Result<JsonValue, String> {
+    let mut json = object! {};
+    let operation = match instr.split_once('=') {
+        Some((dest, operation)) => {
+            let (dest, ty) = dest
+                .split_once(':')
+                .ok_or("expected a type for the destination")?;
+            json["dest"] = dest.trim().into();
+            json["type"] = ty.trim().into();
+            operation
+        }
+        None => instr,
+    };
+    let mut tokens = operation.split_whitespace();
+    let op = tokens.next().ok_or("missing operation")?;
+    json["op"] = op.into();
+    if op == "const" {
+        json["value"] = match tokens.next() {
+            Some("true") => true.into(),
+            Some("false") => false.into(),
+            Some(n) => n
+                .parse::<i64>()
+                .map_err(|_| format!("invalid constant {}", n))?
+                .into(),
+            None => return Err("missing constant".into()),
+        };
+        return Ok(json);
+    }
+    let (mut args, mut funcs, mut labels) = (array![], array![], array![]);
+    for token in tokens {
+        if let Some(label) = token.strip_prefix('.') {
+            labels.push(label).unwrap();
+        } else if let Some(func) = token.strip_prefix('@') {
+            funcs.push(func).unwrap();
+        } else {
+            args.push(token).unwrap();
+        }
+    }
+    if !args.is_empty() || op == "print" {
+        json["args"] = args;
+    }
+    if !funcs.is_empty() {
+        json["funcs"] = funcs;
+    }
+    if !labels.is_empty() {
+        json["labels"] = labels;
+    }
+    Ok(json)
+}
+
+/// What running a program did: its printed output, or how it failed, and how many instructions
+/// it executed.
+#[derive(Debug, PartialEq)]
+pub struct Run {
+    pub output: Result<String, String>,
+    pub instructions: u64,
+}
+
+pub fn run(prog: &Program, args: &[String]) -> Run {
+    let mut out = vec![];
+    let result = Module::new(prog).and_then(|module| {
+        let args = module.parse_args(args)?;
+        module.run_limited(&args, &mut out, RUN_LIMIT)
+    });
+    let output = String::from_utf8(out).unwrap();
+    match result {
+        Ok(instructions) => Run {
+            output: Ok(output),
+            instructions,
+        },
+        Err(err) => Run {
+            output: Err(format!("{}{}", output, err)),
+            instructions: 0,
+        },
+    }
+}
+
+pub fn transform(prog: &Program, pass: impl Fn(&Function) -> Function) -> Program {
+    Program {
+        functions: prog.functions.iter().map(pass).collect(),
+    }
+}
+
+/// A pass under test: a registered one, or one that is trained on the input it's tested with.
+pub enum TestPass {
+    Registered(&'static Pass),
+    Layout,
+    Trace,
+}
+
+pub fn test_passes() -> Vec<TestPass> {
+    let mut passes: Vec<TestPass> = PASSES.iter().map(TestPass::Registered).collect();
+    passes.push(TestPass::Layout);
+    passes.push(TestPass::Trace);
+    passes
+}
+
+impl TestPass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TestPass::Registered(pass) => pass.name,
+            TestPass::Layout => "layout",
+            TestPass::Trace => "trace",
+        }
+    }
+
+    pub fn adds_instructions(&self) -> bool {
+        match self {
+            TestPass::Registered(pass) => pass.adds_instructions,
+            TestPass::Layout | TestPass::Trace => false,
+        }
+    }
+
+    /// Optimizes `prog`, training on a run with `args` if needed. A program that can't be
+    /// trained on comes back unchanged.
+    pub fn apply(&self, prog: &Program, args: &[String]) -> Program {
+        let pass = match self {
+            TestPass::Registered(pass) => return transform(prog, pass.run),
+            _ => self,
+        };
+        let trained = Module::new(prog).and_then(|module| {
+            let args = module.parse_args(args)?;
+            match pass {
+                TestPass::Layout => {
+                    let (_, profile) = module.run_profiled(&args, &mut io::sink())?;
+                    Ok(transform(prog, |func| func.profile_guided_layout(&profile)))
+                }
+                _ => {
+                    let path = module.record_trace(&args, &mut io::sink(), "main", TRACE_LENGTH)?;
+                    Ok(transform(prog, |func| match &*func.name.name() {
+                        "main" => func.speculate_trace(&path),
+                        _ => func.clone(),
+                    }))
+                }
+            }
+        });
+        trained.unwrap_or_else(|_| prog.clone())
+    }
+}
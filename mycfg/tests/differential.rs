@@ -0,0 +1,59 @@
+//! Differential tests: every program in `tests/programs` is run on the VM before and after each
+//! pass with the same arguments. A pass must print the same thing, fail the same way and, unless
+//! it's registered as adding instructions, execute no more instructions than the original
+//! program.
+
+mod common;
+
+use mycfg::core::Program;
+use mycfg::parser;
+
+use common::{run, test_passes, Run, TestPass};
+
+/// Runs `pass` on `prog` and describes how the result behaves differently from `original`, if
+/// it does.
+fn compare(original: &Run, pass: &TestPass, prog: &Program, args: &[String]) -> Option<String> {
+    let optimized = pass.apply(prog, args);
+    if let Err(err) = optimized.verify() {
+        return Some(format!("result is invalid: {}", err));
+    }
+    let run = run(&optimized, args);
+    if run.output != original.output {
+        Some(format!(
+            "output changed from {:?} to {:?}",
+            original.output, run.output
+        ))
+    } else if run.instructions > original.instructions && !pass.adds_instructions() {
+        Some(format!(
+            "executed {} instructions instead of {}",
+            run.instructions, original.instructions
+        ))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn passes_preserve_behaviour() {
+    let mut failures = vec![];
+    for program in common::corpus() {
+        let prog = parser::parse_program(&program.json).unwrap();
+        let original = run(&prog, &program.args);
+        for pass in test_passes() {
+            if let Some(difference) = compare(&original, &pass, &prog, &program.args) {
+                failures.push(format!(
+                    "{} on {}: {}",
+                    pass.name(),
+                    program.name,
+                    difference
+                ));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} pass(es) changed the behaviour of a program:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
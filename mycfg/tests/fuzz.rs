@@ -0,0 +1,88 @@
+//! Fuzzes every pass with random programs from `mycfg::generate`. The result of a pass must
+//! verify and print the same as the original program. Failing programs are minimized before
+//! they're reported, together with the seed that generated them.
+//!
+//! `MYCFG_FUZZ_CASES` sets how many programs to try and `MYCFG_FUZZ_SEED` the first seed, to
+//! fuzz for longer or reproduce a failure.
+
+mod common;
+
+use std::env;
+
+use mycfg::core::Program;
+use mycfg::generate::random_program;
+use mycfg::reduce::minimize;
+
+use common::{run, test_passes, TestPass};
+
+const CASES: u64 = 100;
+
+fn setting(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Describes how `pass` breaks `prog`, if it does. Programs that fail on their own, which
+/// minimizing can produce, are never broken.
+fn problem(pass: &TestPass, prog: &Program, args: &[String]) -> Option<String> {
+    let original = run(prog, args);
+    if original.output.is_err() {
+        return None;
+    }
+    let optimized = pass.apply(prog, args);
+    if let Err(err) = optimized.verify() {
+        return Some(format!("result is invalid: {}", err));
+    }
+    let optimized = run(&optimized, args);
+    if optimized.output != original.output {
+        return Some(format!(
+            "output changed from {:?} to {:?}",
+            original.output, optimized.output
+        ));
+    }
+    None
+}
+
+#[test]
+fn passes_preserve_behaviour_of_random_programs() {
+    let first = setting("MYCFG_FUZZ_SEED", 0);
+    let mut failures = vec![];
+    for seed in first..first + setting("MYCFG_FUZZ_CASES", CASES) {
+        let generated = random_program(seed);
+        let (prog, args) = (&generated.program, &generated.args);
+        if let Err(err) = prog.verify() {
+            panic!(
+                "seed {} generated an invalid program: {}\n{}",
+                seed, err, prog
+            );
+        }
+        if let Err(err) = run(prog, args).output {
+            panic!(
+                "seed {} generated a failing program: {}\n{}",
+                seed, err, prog
+            );
+        }
+        for pass in test_passes() {
+            if problem(&pass, prog, args).is_none() {
+                continue;
+            }
+            let minimized = minimize(prog, |candidate| problem(&pass, candidate, args).is_some());
+            failures.push(format!(
+                "{} on seed {} with arguments {:?}: {}\n{}",
+                pass.name(),
+                seed,
+                args,
+                problem(&pass, &minimized, args).unwrap(),
+                minimized
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} pass(es) broke a random program:\n\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,98 @@
+//! Source positions: `pos` fields are read from Bril JSON, kept by passes on the instructions
+//! they don't replace, written out again and reported in errors.
+
+mod common;
+
+use mycfg::core::{Position, Program};
+use mycfg::parser;
+
+use common::bril_to_json;
+
+const PROGRAM: &str = "@main(n: int) {
+  zero: int = const 0;
+  one: int = const 1;
+.loop:
+  done: bool = eq n zero;
+  br done .exit .body;
+.body:
+  n: int = sub n one;
+  jmp .loop;
+.exit:
+  print n;
+}
+";
+
+fn parse(text: &str) -> Program {
+    parser::parse_program(&bril_to_json(text).unwrap()).unwrap()
+}
+
+fn at(row: usize, col: usize) -> Option<Position> {
+    Some(Position { row, col })
+}
+
+#[test]
+fn positions_are_parsed() {
+    let prog = parse(PROGRAM);
+    let main = &prog.functions[0];
+    assert_eq!(main.blocks[0].pos, None);
+    assert_eq!(main.blocks[0].instructions[1].pos, at(3, 3));
+    assert_eq!(main.blocks[1].pos, at(4, 1));
+    assert_eq!(main.blocks[2].instructions[0].pos, at(8, 3));
+}
+
+#[test]
+fn positions_round_trip_through_json() {
+    let prog = parse(PROGRAM);
+    let json = prog.to_json();
+    // After the two instructions of the first block, which has no label
+    assert_eq!(json["functions"][0]["instrs"][2]["pos"]["row"], 4);
+    assert_eq!(json["functions"][0]["instrs"][3]["pos"]["col"], 3);
+    let reparsed = parser::parse_program(&json).unwrap();
+    assert!(reparsed.functions == prog.functions);
+}
+
+#[test]
+fn passes_keep_positions() {
+    let prog = parse(PROGRAM);
+    let ssa = prog.functions[0].to_ssa();
+    let sub = ssa
+        .blocks
+        .iter()
+        .flat_map(|block| block.instructions.iter())
+        .find(|instr| instr.to_string().contains("Sub"))
+        .unwrap();
+    assert_eq!(sub.pos, at(8, 3));
+    let body = ssa
+        .blocks
+        .iter()
+        .find(|block| block.name == "body")
+        .unwrap();
+    assert_eq!(body.pos, at(7, 1));
+}
+
+#[test]
+fn errors_report_positions() {
+    let prog = parse(&PROGRAM.replace("print n", "print m"));
+    let err = prog.verify().unwrap_err().to_string();
+    assert!(err.starts_with("@main .exit at 11:3: "), "{}", err);
+
+    let prog = parse(&PROGRAM.replace(".loop;", ".nowhere;"));
+    let err = prog.verify().unwrap_err().to_string();
+    assert!(err.starts_with("@main .body at 9:3: "), "{}", err);
+}
+
+#[test]
+fn parser_errors_report_positions() {
+    let error = |text: &str| match parser::parse_program(&bril_to_json(text).unwrap()) {
+        Ok(_) => panic!("{} was parsed", text),
+        Err(err) => err.to_string(),
+    };
+    assert_eq!(
+        error(&PROGRAM.replace("eq n zero", "equals n zero")),
+        "unknown operation equals at 5:3"
+    );
+    assert_eq!(
+        error(&PROGRAM.replace("one: int", "one: float")),
+        "unknown type float at 3:3"
+    );
+}
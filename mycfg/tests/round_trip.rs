@@ -0,0 +1,102 @@
+//! Writing a program back to Bril JSON keeps what other tools attached to it: fields `mycfg`
+//! doesn't know on instructions and functions, and the user's labels, without adding labels of
+//! its own where they aren't needed.
+
+mod common;
+
+use json::JsonValue;
+
+use mycfg::core::Program;
+use mycfg::parser;
+
+const PROGRAM: &str = r#"{
+  "functions": [
+    {
+      "name": "main",
+      "attrs": { "inline": false },
+      "instrs": [
+        { "op": "const", "dest": "x", "type": "int", "value": 1, "note": "first" },
+        { "op": "const", "dest": "y", "type": "int", "value": 2 },
+        { "op": "add", "dest": "z", "type": "int", "args": ["x", "y"], "cost": 3 },
+        { "op": "jmp", "labels": ["b1"] },
+        { "op": "print", "args": ["z"] },
+        { "label": "b1" },
+        { "op": "print", "args": ["x"], "note": "last" }
+      ]
+    }
+  ]
+}"#;
+
+fn parse() -> Program {
+    parser::parse_program(&json::parse(PROGRAM).unwrap()).unwrap()
+}
+
+fn instrs(prog: &Program) -> Vec<JsonValue> {
+    prog.to_json()["functions"][0]["instrs"]
+        .members()
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn unknown_fields_are_kept() {
+    let json = parse().to_json();
+    let main = &json["functions"][0];
+    assert_eq!(main["attrs"]["inline"], false);
+    assert_eq!(main["instrs"][0]["note"], "first");
+    assert_eq!(main["instrs"][2]["cost"], 3);
+}
+
+#[test]
+fn passes_keep_unknown_fields() {
+    let prog = parse();
+    let optimized = common::transform(&prog, |func| func.to_ssa().from_ssa());
+    let json = optimized.to_json();
+    assert_eq!(json["functions"][0]["attrs"]["inline"], false);
+    let add = instrs(&optimized)
+        .into_iter()
+        .find(|instr| instr["op"] == "add")
+        .unwrap();
+    assert_eq!(add["cost"], 3);
+}
+
+#[test]
+fn made_up_labels_are_left_out() {
+    let prog = parse();
+    let main = &prog.functions[0];
+    // The unreachable print after the jump gets a block of its own
+    assert!(main.blocks[0].synthetic && main.blocks[1].synthetic);
+    assert!(!main.blocks[2].synthetic);
+
+    let labels: Vec<String> = instrs(&prog)
+        .iter()
+        .filter_map(|instr| instr["label"].as_str().map(String::from))
+        .collect();
+    assert_eq!(labels, ["b1"]);
+}
+
+#[test]
+fn made_up_names_avoid_user_labels() {
+    let prog = parse();
+    let names: Vec<String> = prog.functions[0]
+        .blocks
+        .iter()
+        .map(|block| block.name.to_string())
+        .collect();
+    assert_eq!(names, ["b0", "b1.1", "b1"]);
+}
+
+#[test]
+fn referenced_made_up_labels_are_written() {
+    let prog = parse();
+    let split = common::transform(&prog, |func| {
+        let mut func = func.clone();
+        func.blocks[0].instructions.last_mut().unwrap().labels = Some(["b1.1".into()].into());
+        func
+    });
+    let labels: Vec<String> = instrs(&split)
+        .iter()
+        .filter_map(|instr| instr["label"].as_str().map(String::from))
+        .collect();
+    assert_eq!(labels, ["b1.1", "b1"]);
+}
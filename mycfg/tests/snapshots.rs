@@ -0,0 +1,236 @@
+//! Snapshot tests in the style of turnt: every program in `tests/programs` goes through every
+//! command of `mycfg` and the output is compared against `tests/snapshots/<command>/<program>.out`.
+//! Whatever a command reports to stderr, and its exit code if it fails, is kept in a `.err` file
+//! next to it.
+//!
+//! Run `cargo test --test snapshots -- --bless` to write the snapshots after an intended change
+//! and review them with `git diff`. Other arguments select the tests whose `<command>/<program>`
+//! name contains them.
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use common::TestProgram;
+
+/// Each command with the options it's run with. `layout` also gets `--profile` with the profile
+/// of the program's run.
+const COMMANDS: [(&str, &[&str]); 19] = [
+    ("print", &[]),
+    ("cfg", &[]),
+    ("loops", &[]),
+    (
+        "annotate",
+        &[
+            "--analysis",
+            "live",
+            "--analysis",
+            "reaching",
+            "--analysis",
+            "values",
+        ],
+    ),
+    ("opt", &[]),
+    ("sr", &[]),
+    ("ssa", &[]),
+    ("gvn", &[]),
+    ("pre", &[]),
+    ("linear-scan", &["--registers", "3"]),
+    ("coloring", &["--registers", "3"]),
+    ("asm", &[]),
+    ("llvm", &[]),
+    ("c", &[]),
+    ("wat", &[]),
+    ("vm", &["-p"]),
+    ("profile", &[]),
+    ("layout", &[]),
+    ("trace", &[]),
+];
+
+/// Commands that run the program, so they get its arguments.
+const RUNNING_COMMANDS: [&str; 3] = ["vm", "profile", "trace"];
+
+struct Output {
+    stdout: String,
+    /// stderr, followed by the exit code if the command failed.
+    stderr: String,
+    success: bool,
+}
+
+fn mycfg(args: &[String], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mycfg"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("couldn't run mycfg");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let mut stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        stderr.push_str(&format!("[exit {}]\n", output.status.code().unwrap_or(-1)));
+    }
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr,
+        success: output.status.success(),
+    }
+}
+
+fn run(command: &str, options: &[&str], program: &TestProgram) -> Output {
+    let input = program.json.dump();
+    let mut args: Vec<String> = vec![command.into()];
+    args.extend(options.iter().map(|option| option.to_string()));
+    if command == "layout" {
+        let profile = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("{}.profile.json", program.name))
+            .display()
+            .to_string();
+        let mut profiling: Vec<String> = vec!["profile".into(), "-o".into(), profile.clone()];
+        profiling.push("--".into());
+        profiling.extend(program.args.iter().cloned());
+        let output = mycfg(&profiling, &input);
+        if !output.success {
+            return output;
+        }
+        args.push("--profile".into());
+        args.push(profile);
+    }
+    if RUNNING_COMMANDS.contains(&command) {
+        args.push("--".into());
+        args.extend(program.args.iter().cloned());
+    }
+    mycfg(&args, &input)
+}
+
+/// A line diff of the snapshot against the actual output, with a few lines of context.
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // Longest common subsequence lengths of every pair of suffixes
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines: Vec<(char, &str)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', a[i]));
+            i += 1;
+        } else {
+            lines.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    const CONTEXT: usize = 3;
+    let changed: Vec<usize> = (0..lines.len()).filter(|&k| lines[k].0 != ' ').collect();
+    let mut out = String::new();
+    let mut shown_to = 0;
+    for (k, (sign, line)) in lines.iter().enumerate() {
+        if changed.iter().any(|&c| c.abs_diff(k) <= CONTEXT) {
+            if k > shown_to && shown_to > 0 {
+                out.push_str("   ...\n");
+            }
+            out.push_str(&format!(" {} {}\n", sign, line));
+            shown_to = k + 1;
+        }
+    }
+    out
+}
+
+/// Compares `actual` against the snapshot at `path`, or writes it when blessing. A missing
+/// snapshot stands for empty output so that commands without stderr have no `.err` file.
+fn check(path: &Path, actual: &str, bless: bool) -> Result<(), String> {
+    let expected = fs::read_to_string(path).unwrap_or_default();
+    if expected == actual {
+        return Ok(());
+    }
+    if bless {
+        if actual.is_empty() {
+            fs::remove_file(path).unwrap();
+        } else {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, actual).unwrap();
+        }
+        return Ok(());
+    }
+    Err(format!("{}:\n{}", path.display(), diff(&expected, actual)))
+}
+
+fn main() {
+    let mut bless = false;
+    let mut filters: Vec<String> = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bless" => bless = true,
+            // Options cargo passes on to test harnesses, like `--quiet`
+            arg if arg.starts_with('-') => {}
+            arg => filters.push(arg.into()),
+        }
+    }
+
+    let snapshots: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("snapshots");
+    let programs = common::corpus();
+    let (mut passed, mut failures) = (0, vec![]);
+    for (command, options) in COMMANDS.iter() {
+        for program in programs.iter() {
+            let name = format!("{}/{}", command, program.name);
+            if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter)) {
+                continue;
+            }
+            let output = run(command, options, program);
+            let base = snapshots.join(command).join(&program.name);
+            let results = [
+                check(&base.with_extension("out"), &output.stdout, bless),
+                check(&base.with_extension("err"), &output.stderr, bless),
+            ];
+            let errors: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+            if errors.is_empty() {
+                passed += 1;
+            } else {
+                println!("FAILED {}", name);
+                for error in errors.iter() {
+                    println!("{}", error);
+                }
+                failures.push(name);
+            }
+        }
+    }
+
+    if bless {
+        println!("blessed {} snapshots", passed);
+        return;
+    }
+    println!("snapshots: {} passed, {} failed", passed, failures.len());
+    if !failures.is_empty() {
+        println!("\nfailed:");
+        for name in failures.iter() {
+            println!("    {}", name);
+        }
+        println!("\nrun `cargo test --test snapshots -- --bless` to accept the new output");
+        process::exit(1);
+    }
+}
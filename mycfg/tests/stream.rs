@@ -0,0 +1,72 @@
+//! The streaming reader gives the same program as parsing a `JsonValue`, whatever the layout of
+//! the text, and points at where the text goes wrong.
+
+mod common;
+
+use json::JsonValue;
+
+use mycfg::core::Program;
+use mycfg::generate::random_program;
+use mycfg::parser;
+
+fn read(text: &str) -> Result<Program, String> {
+    parser::read_program(text.as_bytes()).map_err(|err| err.to_string())
+}
+
+fn error(text: &str) -> String {
+    match read(text) {
+        Ok(_) => panic!("{} was read", text),
+        Err(err) => err,
+    }
+}
+
+fn assert_same(json: &JsonValue, name: &str) {
+    let expected = parser::parse_program(json).unwrap();
+    for text in [json.dump(), json.pretty(4)] {
+        let prog = read(&text).unwrap_or_else(|err| panic!("{}: {}", name, err));
+        assert!(prog.functions == expected.functions, "{} differs", name);
+    }
+}
+
+#[test]
+fn corpus_reads_the_same() {
+    for program in common::corpus() {
+        assert_same(&program.json, &program.name);
+    }
+}
+
+#[test]
+fn generated_programs_read_the_same() {
+    for seed in 0..50 {
+        assert_same(&random_program(seed).program.to_json(), &seed.to_string());
+    }
+}
+
+#[test]
+fn extensions_and_escapes_read_the_same() {
+    let text = r#"{
+        "functions": [{
+            "name": "mé😀",
+            "attrs": {"tags": ["a", "b\n"], "weight": -1.5, "none": null},
+            "instrs": [
+                {"op": "const", "dest": "x", "type": "bool", "value": true, "meta": {"n": 12}},
+                {"label": "l", "pos": {"row": 2, "col": 1}},
+                {"op": "print", "args": ["x"], "label": "not a label"}
+            ]
+        }],
+        "comment": "ignored"
+    }"#;
+    assert_same(&json::parse(text).unwrap(), "escapes");
+    let prog = read(text).unwrap();
+    assert_eq!(prog.functions[0].name, "mé😀");
+}
+
+#[test]
+fn errors_point_at_the_text() {
+    let err = error("{\"functions\": [{\"name\": \"main\",\n  \"instrs\": [}]}");
+    assert_eq!(err, "expected `{` at 2:14");
+    let err = error("{\"functions\": [{\"name\": \"main\", \"instrs\": [{\"op\": \"frob\"}]}]}");
+    assert!(err.starts_with("unknown operation frob at 1:"), "{}", err);
+    let err = error("{\"functions\": []} x");
+    assert_eq!(err, "unexpected text after the program at 1:19");
+}
@@ -0,0 +1,26 @@
+//! Interned names stand for their text: equal names give equal handles, handles print as the
+//! names do and can be sorted by them.
+
+use mycfg::core::{LabelId, VarId};
+
+#[test]
+fn equal_names_give_equal_handles() {
+    let a = VarId::new("sum.1");
+    let b: VarId = String::from("sum.1").into();
+    assert_eq!(a, b);
+    assert_ne!(a, VarId::new("sum.2"));
+    assert_eq!(a, "sum.1");
+    assert_eq!(&*a.name(), "sum.1");
+    assert_eq!(a.to_string(), "sum.1");
+    assert_eq!(format!("{:?}", a), "\"sum.1\"");
+}
+
+#[test]
+fn handles_sort_by_name() {
+    // Interned in the opposite order to their names
+    let names = ["zeta.b", "mid.b", "alpha.b"];
+    let mut labels: Vec<LabelId> = names.iter().map(|name| LabelId::new(name)).collect();
+    labels.sort_by(LabelId::cmp_by_name);
+    let sorted: Vec<String> = labels.iter().map(LabelId::to_string).collect();
+    assert_eq!(sorted, ["alpha.b", "mid.b", "zeta.b"]);
+}
@@ -0,0 +1,54 @@
+//! Loop passes handle speculated traces: a guard that rolls back to the entry block is an edge
+//! into it, which matters when the entry block is a loop header.
+
+mod common;
+
+use mycfg::core::Program;
+use mycfg::parser;
+
+use common::{bril_to_json, run, transform, TestPass};
+
+const ENTRY_LOOP: &str = "@main(n: int) {
+.loop:
+  one: int = const 1;
+  two: int = const 2;
+  three: int = add one two;
+  four: int = add three one;
+  step: int = sub four three;
+  n: int = sub n step;
+  zero: int = const 0;
+  c: bool = gt n zero;
+  br c .loop .done;
+.done:
+  print n;
+}
+";
+
+fn parse(text: &str) -> Program {
+    parser::parse_program(&bril_to_json(text).unwrap()).unwrap()
+}
+
+fn output(prog: &Program, arg: &str) -> Result<String, String> {
+    run(prog, &[arg.to_string()]).output
+}
+
+#[test]
+fn loop_passes_finish_on_a_trace_into_an_entry_loop() {
+    let prog = parse(ENTRY_LOOP);
+    let traced = TestPass::Trace.apply(&prog, &["3".to_string()]);
+    assert!(traced.functions[0].blocks[0]
+        .instructions
+        .iter()
+        .any(|instr| instr
+            .labels
+            .as_ref()
+            .is_some_and(|labels| labels[0] == "loop")));
+
+    let optimized = transform(&traced, |func| func.loop_invariant_code_motion());
+    let reduced = transform(&traced, |func| func.strength_reduction().0);
+    for arg in ["3", "7"] {
+        let expected = output(&prog, arg);
+        assert_eq!(output(&optimized, arg), expected);
+        assert_eq!(output(&reduced, arg), expected);
+    }
+}
@@ -0,0 +1,97 @@
+//! Reading a variable that was never assigned fails like it does in the reference interpreter,
+//! rather than reading whatever its slot holds.
+
+mod common;
+
+use mycfg::core::Program;
+use mycfg::parser;
+
+use common::{bril_to_json, run, transform};
+
+const PROGRAM: &str = "@main(c: bool) {
+  br c .assign .skip;
+.assign:
+  x: int = const 4;
+.skip:
+  y: int = add x x;
+  print y;
+}
+";
+
+fn parse(text: &str) -> Program {
+    parser::parse_program(&bril_to_json(text).unwrap()).unwrap()
+}
+
+fn output(prog: &Program, arg: &str) -> Result<String, String> {
+    run(prog, &[arg.to_string()]).output
+}
+
+#[test]
+fn unassigned_variables_are_errors() {
+    let prog = parse(PROGRAM);
+    assert_eq!(output(&prog, "true"), Ok("8\n".into()));
+    assert_eq!(output(&prog, "false"), Err("undefined variable x".into()));
+}
+
+#[test]
+fn phis_without_a_value_leave_their_destination_unassigned() {
+    let prog = transform(&parse(PROGRAM), |func| func.to_ssa());
+    assert_eq!(output(&prog, "true"), Ok("8\n".into()));
+    let err = output(&prog, "false").unwrap_err();
+    assert!(err.starts_with("undefined variable x"), "{}", err);
+}
+
+#[test]
+fn phis_without_a_value_avoid_user_variables() {
+    let prog = parse(
+        "@main(c: bool, __undefined: int) {
+  br c .assign .skip;
+.assign:
+  x: int = const 4;
+  __undefined: int = const 2;
+.skip:
+  print __undefined;
+  y: int = add x x;
+  print y;
+}
+",
+    );
+    let ssa = transform(&prog, |func| func.to_ssa());
+    let round_trip = transform(&prog, |func| func.to_ssa().from_ssa());
+    let gvn = transform(&prog, |func| func.to_ssa().global_value_numbering());
+    let args = |c: &str| [c.to_string(), "1".to_string()];
+    for optimized in [ssa, round_trip, gvn] {
+        assert_eq!(run(&optimized, &args("true")).output, Ok("2\n8\n".into()));
+        let err = run(&optimized, &args("false")).output.unwrap_err();
+        assert!(err.starts_with("1\nundefined variable x"), "{}", err);
+    }
+}
+
+#[test]
+fn licm_leaves_reads_of_unassigned_variables_in_the_loop() {
+    let prog = parse(
+        "@main(n: int, c: bool) {
+  br c .assign .loop;
+.assign:
+  x: int = const 2;
+.loop:
+  zero: int = const 0;
+  more: bool = gt n zero;
+  br more .body .done;
+.body:
+  y: int = add x x;
+  z: int = mul w w;
+  one: int = const 1;
+  n: int = sub n one;
+  jmp .loop;
+.done:
+  print n;
+}
+",
+    );
+    let optimized = transform(&prog, |func| func.loop_invariant_code_motion());
+    for c in ["true", "false"] {
+        let args = [String::from("0"), c.to_string()];
+        assert_eq!(run(&optimized, &args).output, Ok("0\n".into()));
+    }
+}